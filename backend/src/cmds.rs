@@ -1,7 +1,6 @@
 use crate::download::Downloads;
 use crate::transpose::{TransposeConfig, TransposeService};
 use enthalpy::audio::input::{AudioInput, HostDevice};
-use enthalpy::sense_voice_small::SenseVoiceSmall;
 use enthalpy::util::modelscope::FileInfo;
 use serde_json::Value;
 
@@ -23,25 +22,31 @@ pub async fn get_transcribe_config() -> CmdResult<TransposeConfig> {
 
 #[tauri::command]
 pub async fn get_required_files(model_dir: String) -> CmdResult<Vec<FileInfo>> {
-    SenseVoiceSmall::get_required_files(&model_dir)
+    TransposeService::get()
+        .await
+        .required_files(model_dir)
         .await
         .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub async fn download_required_file(model_dir: String, file_name: String) -> CmdResult<()> {
+pub async fn download_required_file(
+    model_dir: String,
+    model_id: String,
+    file_name: String,
+) -> CmdResult<()> {
     Downloads::get()
         .await
-        .download(model_dir, file_name)
+        .download(model_dir, model_id, file_name)
         .await
         .map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-pub async fn stop_download_required_file(file_name: String) -> CmdResult<()> {
+pub async fn stop_download_required_file(model_id: String, file_name: String) -> CmdResult<()> {
     Downloads::get()
         .await
-        .stop_download(file_name)
+        .stop_download(model_id, file_name)
         .await
         .map_err(|e| e.to_string())
 }
@@ -1,6 +1,6 @@
 use crate::notify::Notifier;
+use crate::transpose::TransposeService;
 use chrono::Utc;
-use enthalpy::sense_voice_small::SenseVoiceSmall;
 use enthalpy::util::modelscope::Progress;
 use enthalpy::Res;
 use serde::Serialize;
@@ -26,14 +26,21 @@ impl Downloads {
         }
     }
 
-    pub async fn download(&self, model_dir: String, file_name: String) -> Res<()> {
-        let handle = tokio::spawn(download(model_dir, file_name.clone()));
-        self.downloads.write().await.insert(file_name, handle);
+    pub async fn download(
+        &self,
+        model_dir: String,
+        model_id: String,
+        file_name: String,
+    ) -> Res<()> {
+        let key = download_key(&model_id, &file_name);
+        let handle = tokio::spawn(download(model_dir, model_id, file_name));
+        self.downloads.write().await.insert(key, handle);
         Ok(())
     }
 
-    pub async fn stop_download(&self, file_name: String) -> Res<()> {
-        if let Some(handle) = self.downloads.write().await.remove(&file_name) {
+    pub async fn stop_download(&self, model_id: String, file_name: String) -> Res<()> {
+        let key = download_key(&model_id, &file_name);
+        if let Some(handle) = self.downloads.write().await.remove(&key) {
             handle.abort();
         }
 
@@ -41,19 +48,29 @@ impl Downloads {
     }
 }
 
-async fn download(model_dir: String, file_name: String) -> Res<()> {
+/// Models share file names like model.pt, so downloads are told apart by their repository
+fn download_key(model_id: &str, file_name: &str) -> String {
+    format!("{model_id}/{file_name}")
+}
+
+async fn download(model_dir: String, model_id: String, file_name: String) -> Res<()> {
+    let repo = TransposeService::get()
+        .await
+        .model_repo(model_dir, model_id.clone())
+        .await?;
     let downloader = DownloadProgress {
+        model_id,
         file_name: file_name.clone(),
         notifier: Notifier::get().await.clone(),
         size: AtomicU64::new(0),
         position: AtomicU64::new(0),
         last_report: AtomicI64::new(0),
     };
-    let repo = SenseVoiceSmall::model_repo(&model_dir).await;
     repo.download_with_progress(&file_name, downloader).await
 }
 
 struct DownloadProgress {
+    model_id: String,
     file_name: String,
     notifier: Notifier,
     size: AtomicU64,
@@ -63,6 +80,7 @@ struct DownloadProgress {
 
 #[derive(Serialize, Clone)]
 struct ProgressInfo {
+    model_id: String,
     file_name: String,
     size: u64,
     position: u64,
@@ -110,6 +128,7 @@ impl DownloadProgress {
         self.last_report.swap(now, Ordering::Relaxed);
 
         let progress = ProgressInfo {
+            model_id: self.model_id.clone(),
             file_name: self.file_name.clone(),
             size: self.size.load(Ordering::Relaxed),
             position: self.position.load(Ordering::Relaxed),
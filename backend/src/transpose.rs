@@ -2,8 +2,11 @@ use crate::config::ConfigSync;
 use crate::notify::Notifier;
 use anyhow::bail;
 use enthalpy::audio::input::AudioInput;
-use enthalpy::audio::silero_vad::VadConfig;
-use enthalpy::sense_voice_small::{SenseVoiceSmall, SenseVoiceSmallConfig, Token};
+use enthalpy::punctuation::{CtTransformer, PunctuationConfig, PunctuationStream};
+use enthalpy::recognizer::{Engine, Recognizer};
+use enthalpy::sense_voice_small::{DecodeMethod, Token};
+use enthalpy::speaker::{Diarizer, SpeakerConfig};
+use enthalpy::util::modelscope::{FileInfo, ModelScopeRepo};
 use enthalpy::{ConfigRefresher, Res};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
@@ -25,7 +28,55 @@ pub struct TransposeConfig {
     input_device: String,
     realtime: bool,
     realtime_rate: u64,
-    model_config: SenseVoiceSmallConfig,
+    /// Restore punctuation of the recognized text and caption whole sentences
+    punctuation: bool,
+    /// Words that have to follow a sentence end before it is captioned
+    punctuation_lookahead: usize,
+    /// Label the captions with the speaker
+    diarization: bool,
+    /// Cosine similarity a voice needs to be taken for a known speaker
+    speaker_threshold: f32,
+    /// Speech recognition model
+    engine: Engine,
+    /// Config of the engine, fields it does not know are dropped
+    model_config: Value,
+}
+
+impl TransposeConfig {
+    /// Model config with the files looked up in `model_dir`
+    fn with_model_dir(&self, model_dir: String) -> Value {
+        let mut config = self.model_config.clone();
+        config["model_dir"] = Value::String(model_dir);
+        config
+    }
+
+    /// Fields every engine understands, the rest starts from the defaults
+    /// of a newly selected engine
+    fn shared_model_config(&self) -> Value {
+        let shared = ["model_dir", "vad", "resample", "use_gpu"]
+            .into_iter()
+            .filter_map(|key| Some((key.to_string(), self.model_config.get(key)?.clone())))
+            .collect();
+        Value::Object(shared)
+    }
+
+    /// Punctuation model in the model directory and on the device of the engine
+    fn punctuation_config(&self) -> Res<PunctuationConfig> {
+        let mut config = serde_json::from_value::<PunctuationConfig>(self.shared_model_config())?;
+        config.lookahead = self.punctuation_lookahead;
+        Ok(config)
+    }
+
+    /// Speaker model in the model directory and on the device of the engine
+    fn speaker_config(&self) -> Res<SpeakerConfig> {
+        let mut config = serde_json::from_value::<SpeakerConfig>(self.shared_model_config())?;
+        config.threshold = self.speaker_threshold;
+        Ok(config)
+    }
+
+    fn keyword_spotting(&self) -> bool {
+        self.model_config.pointer("/decode/method") == Some(&json!(DecodeMethod::KeywordSpotting))
+    }
 }
 
 pub struct TransposeService {
@@ -53,18 +104,23 @@ impl TransposeService {
         let home_dir = home_dir().unwrap_or(PathBuf::from("."));
         let model_dir = home_dir.join(".cache/modelscope/hub/models");
 
+        let engine = Engine::default();
         let config = TransposeConfig {
             enable: false,
             input_host: String::default(),
             input_device: String::default(),
             realtime: false,
             realtime_rate: 800,
-            model_config: SenseVoiceSmallConfig {
-                model_dir,
-                vad: VadConfig::default(),
-                resample: Some((48000, 16000)),
-                use_gpu: false,
-            },
+            punctuation: false,
+            punctuation_lookahead: PunctuationConfig::default().lookahead,
+            diarization: false,
+            speaker_threshold: SpeakerConfig::default().threshold,
+            engine,
+            model_config: engine.normalize_config(&json!({
+                "model_dir": model_dir,
+                "resample": [48000, 16000],
+                "batch_size": 4,
+            }))?,
         };
 
         let config = ConfigSync::new(config);
@@ -80,19 +136,58 @@ impl TransposeService {
         event!(tracing::Level::DEBUG, "Update config {}", patch);
         let mut config = self.config.write().await;
         let old = config.curr().clone();
-        let mut new = serde_json::to_value(old)?;
+        let mut new = serde_json::to_value(&old)?;
         json_patch::merge(&mut new, &patch);
-        let new = serde_json::from_value::<TransposeConfig>(new)?;
+        let mut new = serde_json::from_value::<TransposeConfig>(new)?;
+        if new.engine != old.engine {
+            new.model_config = new.shared_model_config();
+        }
+        new.model_config = new.engine.normalize_config(&new.model_config)?;
 
         config.update_sync(new).await?;
 
         Ok(())
     }
+
+    /// Files the configured engine and the enabled models need in `model_dir`
+    pub async fn required_files(&self, model_dir: String) -> Res<Vec<FileInfo>> {
+        let config = self.get_config().await;
+        let model_config = config.with_model_dir(model_dir.clone());
+        let mut files = config.engine.required_files(&model_config).await?;
+        if config.punctuation {
+            files.extend(CtTransformer::get_required_files(&model_dir).await?);
+        }
+        if config.diarization {
+            files.extend(Diarizer::get_required_files(&model_dir).await?);
+        }
+        Ok(files)
+    }
+
+    /// Repository of `model_id`, among the configured engine and the enabled models
+    pub async fn model_repo(&self, model_dir: String, model_id: String) -> Res<ModelScopeRepo> {
+        let config = self.get_config().await;
+        let model_config = config.with_model_dir(model_dir.clone());
+        let mut repos = vec![config.engine.model_repo(&model_config).await?];
+        if config.punctuation {
+            repos.push(CtTransformer::model_repo(&model_dir).await);
+        }
+        if config.diarization {
+            repos.push(Diarizer::model_repo(&model_dir).await);
+        }
+        match repos.into_iter().find(|repo| repo.model_id() == model_id) {
+            Some(repo) => Ok(repo),
+            None => bail!("Unknown model {}", model_id),
+        }
+    }
 }
 
 struct Transpose {
     config: ConfigSync<TransposeConfig>,
-    model: Option<SenseVoiceSmall>,
+    model: Option<Box<dyn Recognizer>>,
+    punctuation: Option<CtTransformer>,
+    /// Words not yet captioned as a sentence
+    sentences: PunctuationStream,
+    diarizer: Option<Diarizer>,
     input: Option<AudioInput>,
     pcm_tx: Sender<Vec<f32>>,
     app_handle: AppHandle,
@@ -108,6 +203,9 @@ impl Transpose {
             let mut transpose = Transpose {
                 config,
                 model: None,
+                punctuation: None,
+                sentences: PunctuationStream::new(0),
+                diarizer: None,
                 input: None,
                 pcm_tx,
                 app_handle,
@@ -145,9 +243,22 @@ impl Transpose {
         }
         let model = self.model.as_mut().unwrap();
         let mut segments = model.segment(pcm)?;
-        let tokens: Vec<Token> = model.transpose(&mut segments)?;
+        // Before transposing, the model may scale the samples in place
+        let speakers = match &mut self.diarizer {
+            Some(diarizer) => diarizer.assign(&segments)?,
+            None => Vec::new(),
+        };
+        let mut tokens: Vec<Token> = model.transpose(&mut segments)?;
+        for (token, speaker) in tokens.iter_mut().zip(speakers) {
+            token.set_speaker(speaker);
+        }
 
-        self.emit_tokens(tokens);
+        // Only final segments, the realtime cache would report a keyword again
+        self.emit_keywords(&tokens);
+        if !self.config.curr().keyword_spotting() {
+            let tokens = self.punctuate(tokens)?;
+            self.emit_tokens(tokens);
+        }
 
         Ok(())
     }
@@ -158,14 +269,45 @@ impl Transpose {
         }
         let model = self.model.as_mut().unwrap();
 
-        if self.config.curr().realtime {
+        if self.config.curr().realtime && !self.config.curr().keyword_spotting() {
             let tokens = model.transpose_vad_cache()?;
+            let tokens = self.punctuate_preview(tokens)?;
             self.emit_tokens(tokens);
         }
 
         Ok(())
     }
 
+    /// Complete sentences of closed segments, the words still waiting for a
+    /// sentence end are shown by the realtime tick, see `punctuate_preview`
+    fn punctuate(&mut self, segments: Vec<Token>) -> Res<Vec<Token>> {
+        let Some(punctuation) = &self.punctuation else {
+            return Ok(segments);
+        };
+        if segments.is_empty() {
+            return Ok(segments);
+        }
+
+        self.sentences.push(punctuation, &segments)
+    }
+
+    /// Words waiting for a sentence end followed by the speech the VAD is
+    /// still collecting, as one unfinished caption
+    fn punctuate_preview(&self, segments: Vec<Token>) -> Res<Vec<Token>> {
+        let Some(punctuation) = &self.punctuation else {
+            return Ok(segments);
+        };
+        if segments.is_empty() {
+            return Ok(segments);
+        }
+
+        Ok(self
+            .sentences
+            .preview(punctuation, &segments)?
+            .map(|sentence| vec![sentence])
+            .unwrap_or_default())
+    }
+
     fn emit_tokens(&mut self, tokens: Vec<Token>) {
         if !tokens.is_empty() {
             self.realtime_interval.reset();
@@ -180,6 +322,33 @@ impl Transpose {
                     "start": token.start,
                     "end": token.end,
                     "text": token.text,
+                    "language": token.tags.language,
+                    "emotion": token.tags.emotion,
+                    "events": token.tags.events,
+                    "confidence": token.confidence,
+                    "speaker": token.speaker,
+                    "tokens": token
+                        .tokens
+                        .iter()
+                        .map(|t| json!({
+                            "text": t.text,
+                            "start": t.start,
+                            "end": t.end,
+                            "confidence": t.confidence,
+                            "speaker": t.speaker,
+                        }))
+                        .collect::<Vec<_>>(),
+                    "words": token
+                        .words
+                        .iter()
+                        .map(|w| json!({
+                            "text": w.text,
+                            "start": w.start,
+                            "end": w.end,
+                            "confidence": w.confidence,
+                            "speaker": w.speaker,
+                        }))
+                        .collect::<Vec<_>>(),
                 }),
             );
 
@@ -189,6 +358,21 @@ impl Transpose {
         }
     }
 
+    fn emit_keywords(&self, tokens: &[Token]) {
+        for keyword in tokens.iter().flat_map(|t| &t.keywords) {
+            event!(
+                tracing::Level::INFO,
+                "Keyword: {} ({:.2})",
+                keyword.keyword,
+                keyword.score
+            );
+
+            if let Err(e) = self.app_handle.emit("keyword", keyword) {
+                event!(tracing::Level::ERROR, "Error emitting event {}", e);
+            }
+        }
+    }
+
     async fn update_config(&mut self) {
         match self.do_update_config().await {
             Ok(_) => {
@@ -205,6 +389,9 @@ impl Transpose {
         let new = self.config.fresh().clone();
         if !new.enable {
             self.model.take();
+            self.punctuation.take();
+            self.sentences.clear();
+            self.diarizer.take();
             self.input.take();
             return Ok(());
         }
@@ -249,21 +436,19 @@ impl Transpose {
             _ => {}
         }
 
-        let device_changed = old.model_config.use_gpu != new.model_config.use_gpu;
-        let model_dir_changed = old.model_config.model_dir != new.model_config.model_dir;
-        let should_reload = model_dir_changed || device_changed;
+        let engine = new.engine;
+        let should_reload =
+            old.engine != engine || engine.needs_reload(&old.model_config, &new.model_config)?;
 
         match (&mut self.model, should_reload) {
             (None, _) | (Some(_), true) => {
-                let ok = SenseVoiceSmall::check_required_files(&new.model_config.model_dir).await;
-
-                if !ok {
+                if !engine.check_required_files(&new.model_config).await? {
                     bail!("Missing required files");
                 }
 
                 event!(tracing::Level::DEBUG, "Loading model");
                 self.notifier.info("Loading");
-                match SenseVoiceSmall::with_config(new.model_config).await {
+                match engine.load(&new.model_config).await {
                     Ok(new_model) => {
                         self.model.replace(new_model);
                     }
@@ -274,7 +459,82 @@ impl Transpose {
             }
             (Some(model), false) => {
                 event!(tracing::Level::DEBUG, "Refreshing model");
-                model.refresh(&new.model_config, &old.model_config)?;
+                model.refresh(&old.model_config, &new.model_config)?;
+            }
+        }
+
+        self.update_punctuation(&old, &new).await?;
+        self.update_diarizer(&old, &new).await
+    }
+
+    async fn update_punctuation(
+        &mut self,
+        old: &TransposeConfig,
+        new: &TransposeConfig,
+    ) -> Res<()> {
+        if !new.punctuation {
+            self.punctuation.take();
+            self.sentences.clear();
+            return Ok(());
+        }
+
+        let config = new.punctuation_config()?;
+        self.sentences.set_lookahead(config.lookahead);
+
+        let old_config = old.punctuation_config()?;
+        let should_reload = config.model_dir != old_config.model_dir
+            || config.use_gpu != old_config.use_gpu
+            || self.punctuation.is_none();
+        if should_reload {
+            if !CtTransformer::check_required_files(&config.model_dir).await {
+                bail!("Missing required files");
+            }
+
+            event!(tracing::Level::DEBUG, "Loading punctuation model");
+            match CtTransformer::with_config(config).await {
+                Ok(punctuation) => {
+                    self.punctuation.replace(punctuation);
+                    self.sentences.clear();
+                }
+                Err(e) => {
+                    bail!("Error loading punctuation model {}", e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn update_diarizer(&mut self, old: &TransposeConfig, new: &TransposeConfig) -> Res<()> {
+        if !new.diarization {
+            self.diarizer.take();
+            return Ok(());
+        }
+
+        let config = new.speaker_config()?;
+        let old_config = old.speaker_config()?;
+        let should_reload = config.model_dir != old_config.model_dir
+            || config.use_gpu != old_config.use_gpu
+            || self.diarizer.is_none();
+
+        match (&mut self.diarizer, should_reload) {
+            (None, _) | (Some(_), true) => {
+                if !Diarizer::check_required_files(&config.model_dir).await {
+                    bail!("Missing required files");
+                }
+
+                event!(tracing::Level::DEBUG, "Loading speaker model");
+                match Diarizer::with_config(config).await {
+                    Ok(diarizer) => {
+                        self.diarizer.replace(diarizer);
+                    }
+                    Err(e) => {
+                        bail!("Error loading speaker model {}", e);
+                    }
+                }
+            }
+            (Some(diarizer), false) => {
+                diarizer.refresh(&old_config, &config)?;
             }
         }
 
@@ -0,0 +1,42 @@
+use anyhow::Error;
+use enthalpy::Res;
+use enthalpy::audio::load_audio;
+use enthalpy::sense_voice_small::{SenseVoiceSmall, SenseVoiceSmallConfig};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Align a transcript to its audio and print LRC and SRT timings
+///
+/// ```text
+/// cargo run --example align -- song.mp3 lyrics.txt
+/// ```
+#[tokio::main]
+async fn main() -> Res<()> {
+    let mut args = env::args().skip(1);
+    let usage = || Error::msg("Usage: align <audio> <transcript.txt>");
+    let audio = args.next().ok_or_else(usage)?;
+    let transcript = args.next().ok_or_else(usage)?;
+
+    let (mut data, sample_rate) = load_audio(audio)?;
+    let text = fs::read_to_string(transcript)?;
+
+    let cfg = SenseVoiceSmallConfig {
+        model_dir: PathBuf::from("/Users/entropy/.cache/modelscope/hub/models/"),
+        resample: Some((sample_rate, 16000)),
+        use_gpu: true,
+        ..SenseVoiceSmallConfig::default()
+    };
+
+    let mut model = SenseVoiceSmall::with_config(cfg).await?;
+    let alignment = model.align(&mut data, &text, 0)?;
+
+    for line in alignment.failed() {
+        eprintln!("not aligned: {}", line.text);
+    }
+
+    println!("{}", alignment.to_lrc());
+    println!("{}", alignment.to_srt());
+
+    Ok(())
+}
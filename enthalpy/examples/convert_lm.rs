@@ -0,0 +1,38 @@
+use anyhow::Error;
+use enthalpy::Res;
+use enthalpy::lm::NgramLm;
+use std::env;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Convert an ARPA language model to the binary format
+///
+/// ```text
+/// cargo run --example convert_lm -- model.arpa [model.bin]
+/// ```
+fn main() -> Res<()> {
+    let mut args = env::args().skip(1);
+    let input = PathBuf::from(
+        args.next()
+            .ok_or_else(|| Error::msg("Usage: convert_lm <model.arpa> [model.bin]"))?,
+    );
+    let output = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| input.with_extension("bin"));
+
+    let start = Instant::now();
+    let lm = NgramLm::from_file(&input)?;
+    println!("loaded {}-gram model in {:?}", lm.order(), start.elapsed());
+
+    lm.save_binary(&output)?;
+    let start = Instant::now();
+    NgramLm::from_file(&output)?;
+    println!(
+        "wrote {} (loads in {:?})",
+        output.display(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
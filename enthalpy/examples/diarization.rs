@@ -0,0 +1,61 @@
+use enthalpy::Res;
+use enthalpy::audio::load_audio;
+use enthalpy::audio::silero_vad::VadConfig;
+use enthalpy::paraformer::{Paraformer, ParaformerConfig};
+use enthalpy::sense_voice_small::FsmnDevice;
+use enthalpy::speaker::{Diarizer, SpeakerConfig};
+use std::path::PathBuf;
+use tokio::time::Instant;
+use tracing::Level;
+
+#[tokio::main]
+async fn main() -> Res<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_env_filter("enthalpy=TRACE")
+        .compact()
+        .init();
+
+    let model_dir = PathBuf::from("/Users/entropy/.cache/modelscope/hub/models/");
+    let (mut data, sample_rate) = load_audio("/Users/entropy/Documents/meeting.mp3")?;
+
+    let mut model = Paraformer::with_config(ParaformerConfig {
+        model_dir: model_dir.clone(),
+        vad: VadConfig::default(),
+        resample: Some((sample_rate, 16000)),
+        use_gpu: true,
+        fsmn_device: FsmnDevice::Encoder,
+    })
+    .await?;
+    let mut diarizer = Diarizer::with_config(SpeakerConfig {
+        model_dir,
+        use_gpu: true,
+        ..SpeakerConfig::default()
+    })
+    .await?;
+
+    let start = Instant::now();
+    let mut segments = model.segment(&mut data)?;
+    // The whole file is known, so cluster offline
+    let speakers = diarizer.diarize(&segments)?;
+    let mut tokens = model.transpose(&mut segments)?;
+    println!("{:.2}", start.elapsed().as_secs_f32());
+
+    for (token, speaker) in tokens.iter_mut().zip(speakers) {
+        token.set_speaker(speaker);
+    }
+    for token in tokens {
+        let speaker = token
+            .speaker
+            .map_or("?".to_string(), |speaker| speaker.to_string());
+        println!(
+            "[{:.1}s,{:.1}s] S{}: {}",
+            token.start as f32 / 1000.0,
+            token.end as f32 / 1000.0,
+            speaker,
+            token.text
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use enthalpy::Res;
+use enthalpy::audio::load_audio;
+use enthalpy::audio::silero_vad::VadConfig;
+use enthalpy::paraformer::{Paraformer, ParaformerConfig};
+use enthalpy::sense_voice_small::FsmnDevice;
+use std::path::PathBuf;
+use tokio::time::Instant;
+use tracing::Level;
+
+#[tokio::main]
+async fn main() -> Res<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_env_filter("enthalpy=TRACE")
+        .compact()
+        .init();
+
+    let (mut data, sample_rate) = load_audio("/Users/entropy/Documents/meeting.mp3")?;
+
+    let cfg = ParaformerConfig {
+        model_dir: PathBuf::from("/Users/entropy/.cache/modelscope/hub/models/"),
+        vad: VadConfig::default(),
+        resample: Some((sample_rate, 16000)),
+        use_gpu: true,
+        fsmn_device: FsmnDevice::Encoder,
+    };
+
+    let mut model = Paraformer::with_config(cfg).await?;
+
+    let start = Instant::now();
+    let mut segments = model.segment(&mut data)?;
+    let tokens = model.transpose(&mut segments)?;
+    println!("{:.2}", start.elapsed().as_secs_f32());
+    for token in tokens {
+        println!(
+            "[{:.1}s,{:.1}s]:{}",
+            token.start as f32 / 1000.0,
+            token.end as f32 / 1000.0,
+            token.text
+        );
+        for word in token.words {
+            println!(
+                "  [{:.2}s,{:.2}s]:{}",
+                word.start as f32 / 1000.0,
+                word.end as f32 / 1000.0,
+                word.text
+            );
+        }
+    }
+
+    Ok(())
+}
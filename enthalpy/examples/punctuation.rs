@@ -0,0 +1,62 @@
+use enthalpy::Res;
+use enthalpy::audio::load_audio;
+use enthalpy::audio::silero_vad::VadConfig;
+use enthalpy::paraformer::{Paraformer, ParaformerConfig};
+use enthalpy::punctuation::{CtTransformer, PunctuationConfig, PunctuationStream};
+use enthalpy::sense_voice_small::FsmnDevice;
+use std::path::PathBuf;
+use tracing::Level;
+
+#[tokio::main]
+async fn main() -> Res<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_env_filter("enthalpy=TRACE")
+        .compact()
+        .init();
+
+    let model_dir = PathBuf::from("/Users/entropy/.cache/modelscope/hub/models/");
+    let cfg = PunctuationConfig {
+        model_dir: model_dir.clone(),
+        use_gpu: true,
+        lookahead: 3,
+    };
+    let lookahead = cfg.lookahead;
+    let punc = CtTransformer::with_config(cfg).await?;
+
+    println!(
+        "{}",
+        punc.punctuate_text("跨境河流是养育沿岸人民的生命之源")?
+    );
+
+    let (mut data, sample_rate) = load_audio("/Users/entropy/Documents/meeting.mp3")?;
+    let mut model = Paraformer::with_config(ParaformerConfig {
+        model_dir,
+        vad: VadConfig::default(),
+        resample: Some((sample_rate, 16000)),
+        use_gpu: true,
+        fsmn_device: FsmnDevice::Encoder,
+    })
+    .await?;
+
+    // Feed the segments one by one, like live audio
+    let mut stream = PunctuationStream::new(lookahead);
+    let mut segments = model.segment(&mut data)?;
+    let mut sentences = Vec::new();
+    for segment in segments.chunks_mut(1) {
+        let tokens = model.transpose(segment)?;
+        sentences.extend(stream.push(&punc, &tokens)?);
+    }
+    sentences.extend(stream.flush(&punc)?);
+
+    for sentence in sentences {
+        println!(
+            "[{:.1}s,{:.1}s]:{}",
+            sentence.start as f32 / 1000.0,
+            sentence.end as f32 / 1000.0,
+            sentence.text
+        );
+    }
+
+    Ok(())
+}
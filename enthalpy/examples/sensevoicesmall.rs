@@ -2,7 +2,10 @@ use enthalpy::Res;
 use enthalpy::audio::input::AudioInput;
 use enthalpy::audio::load_audio;
 use enthalpy::audio::silero_vad::VadConfig;
-use enthalpy::sense_voice_small::{SenseVoiceSmall, SenseVoiceSmallConfig};
+use enthalpy::sense_voice_small::{
+    ChunkConfig, DecodeConfig, DecodeMethod, FsmnDevice, Language, ModelDtype, SenseVoiceSmall,
+    SenseVoiceSmallConfig, TextNorm,
+};
 use std::path::PathBuf;
 use tokio::time::Instant;
 use tracing::Level;
@@ -30,6 +33,17 @@ async fn transpose_file() -> Res<()> {
         vad: VadConfig::default(),
         resample: Some((sample_rate, 16000)),
         use_gpu: true,
+        language: Language::Auto,
+        text_norm: TextNorm::WithItn,
+        batch_size: 8,
+        decode: DecodeConfig {
+            method: DecodeMethod::BeamSearch,
+            ..DecodeConfig::default()
+        },
+        chunk: ChunkConfig::default(),
+        dtype: ModelDtype::F16,
+        weight_file: None,
+        fsmn_device: FsmnDevice::Encoder,
     };
 
     let mut model = SenseVoiceSmall::with_config(cfg).await?;
@@ -59,6 +73,14 @@ async fn transpose_stream() -> Res<()> {
         vad: VadConfig::default(),
         resample: Some((sample_rate, 16000)),
         use_gpu: false,
+        language: Language::Auto,
+        text_norm: TextNorm::WithItn,
+        batch_size: 1,
+        decode: DecodeConfig::default(),
+        chunk: ChunkConfig::default(),
+        dtype: ModelDtype::F32,
+        weight_file: None,
+        fsmn_device: FsmnDevice::Encoder,
     };
 
     let mut model = SenseVoiceSmall::with_config(cfg).await?;
@@ -0,0 +1,44 @@
+use enthalpy::Res;
+use enthalpy::audio::load_audio;
+use enthalpy::audio::silero_vad::VadConfig;
+use enthalpy::whisper::{Whisper, WhisperConfig, WhisperSize};
+use std::path::PathBuf;
+use tokio::time::Instant;
+use tracing::Level;
+
+#[tokio::main]
+async fn main() -> Res<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::TRACE)
+        .with_env_filter("enthalpy=TRACE")
+        .compact()
+        .init();
+
+    let (mut data, sample_rate) = load_audio("/Users/entropy/Documents/german.mp3")?;
+
+    let cfg = WhisperConfig {
+        model_dir: PathBuf::from("/Users/entropy/.cache/modelscope/hub/models/"),
+        size: WhisperSize::Base,
+        vad: VadConfig::default(),
+        resample: Some((sample_rate, 16000)),
+        use_gpu: true,
+        language: "de".to_string(),
+    };
+
+    let mut model = Whisper::with_config(cfg).await?;
+
+    let start = Instant::now();
+    let mut segments = model.segment(&mut data)?;
+    let tokens = model.transpose(&mut segments)?;
+    println!("{:.2}", start.elapsed().as_secs_f32());
+    for token in tokens {
+        println!(
+            "[{:.1}s,{:.1}s]:{}",
+            token.start as f32 / 1000.0,
+            token.end as f32 / 1000.0,
+            token.text
+        );
+    }
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 pub mod input;
 pub mod resample;
+pub mod segmenter;
 pub mod silero_vad;
 pub mod wav_frontend;
 
@@ -0,0 +1,73 @@
+use crate::Res;
+use crate::audio::resample::Resampler;
+use crate::audio::silero_vad::{Segment, VadConfig, VadProcessor};
+use tracing::{Level, event};
+
+/// Resampler and VAD in front of the speech recognizers, cutting live audio
+/// into speech segments
+pub struct Segmenter {
+    resample: Option<(u32, u32)>,
+    resampler: Option<Resampler>,
+    vad_config: VadConfig,
+    vad: VadProcessor,
+}
+
+impl Segmenter {
+    /// # Arguments
+    /// * `resample` - Input and model sample rates, None if they are the same
+    /// * `vad` - Voice activity detection settings
+    pub fn new(resample: Option<(u32, u32)>, vad: &VadConfig) -> Res<Self> {
+        Ok(Self {
+            resample,
+            resampler: init_resampler(resample)?,
+            vad_config: vad.clone(),
+            vad: VadProcessor::new(vad.clone())?,
+        })
+    }
+
+    /// Resampler to the model sample rate, None if the input is already at it
+    pub fn resampler(&self) -> Option<&Resampler> {
+        self.resampler.as_ref()
+    }
+
+    /// Push audio and take the speech segments the VAD has closed
+    pub fn segment(&mut self, waveform: &[f32]) -> Res<Vec<Segment>> {
+        let waveform = match &self.resampler {
+            None => waveform,
+            Some(sampler) => &sampler.apply_resample(waveform)?,
+        };
+
+        self.vad.push(waveform);
+
+        Ok(self.vad.segment())
+    }
+
+    /// Speech the VAD is still collecting
+    pub fn samples(&self) -> Option<Segment> {
+        self.vad.samples()
+    }
+
+    /// Rebuild the resampler or the VAD if their settings changed
+    pub fn refresh(&mut self, resample: Option<(u32, u32)>, vad: &VadConfig) -> Res<()> {
+        if self.vad_config != *vad {
+            event!(Level::DEBUG, "Refreshing VAD");
+            self.vad = VadProcessor::new(vad.clone())?;
+            self.vad_config = vad.clone();
+        }
+
+        if self.resample != resample {
+            event!(Level::DEBUG, "Refreshing resampler");
+            self.resampler = init_resampler(resample)?;
+            self.resample = resample;
+        }
+
+        Ok(())
+    }
+}
+
+fn init_resampler(sample_config: Option<(u32, u32)>) -> Res<Option<Resampler>> {
+    Ok(match sample_config {
+        Some((from, to)) => Some(Resampler::new(from, to)?),
+        None => None,
+    })
+}
@@ -24,6 +24,10 @@ pub struct WavFrontendConfig {
     pub lfr_n: usize,
     /// Optional path to the CMVN (cepstral mean and variance normalization) file.
     pub cmvn_file: Option<PathBuf>,
+    /// Window function of each frame, such as `hamming` or `povey`.
+    pub window_type: &'static CStr,
+    /// Amount of random noise added to the waveform, 0 for none.
+    pub dither: f32,
 }
 
 /// Implementation of the `Default` trait for `WavFrontendConfig`.
@@ -42,6 +46,8 @@ impl Default for WavFrontendConfig {
             lfr_m: 7,
             lfr_n: 6,
             cmvn_file: None,
+            window_type: c"hamming",
+            dither: 1.0,
         }
     }
 }
@@ -98,6 +104,25 @@ impl WavFrontend {
         })
     }
 
+    /// Creates a new `WavFrontend` with CMVN statistics that are already loaded.
+    ///
+    /// `cmvn_file` of the config is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Configuration settings for the audio.
+    /// * `means` - Shift added to each feature, as in the `<AddShift>` block of a CMVN file.
+    /// * `vars` - Scale of each feature, as in the `<Rescale>` block of a CMVN file.
+    pub fn with_cmvn(config: WavFrontendConfig, means: Vec<f32>, vars: Vec<f32>) -> Res<Self> {
+        let means_len = means.len();
+        let vars_len = vars.len();
+        Ok(WavFrontend {
+            config,
+            cmvn_means: Some(Tensor::from_vec(means, means_len, &Device::Cpu)?),
+            cmvn_vars: Some(Tensor::from_vec(vars, vars_len, &Device::Cpu)?),
+        })
+    }
+
     pub fn extract_features_f32(&self, waveform: &mut [f32]) -> Res<Tensor> {
         let fbank = self.compute_fbank_features(waveform)?;
         let lfr_feats = self.apply_lfr(&fbank, self.config.lfr_m, self.config.lfr_n)?;
@@ -124,8 +149,8 @@ impl WavFrontend {
         let opt = FbankOptions {
             frame_opts: FrameExtractionOptions {
                 samp_freq: self.config.sample_rate as f32,
-                window_type: CStr::from_bytes_with_nul(b"hamming\0").unwrap().as_ptr(),
-                dither: 1.0,
+                window_type: self.config.window_type.as_ptr(),
+                dither: self.config.dither,
                 frame_shift_ms: self.config.frame_shift_ms,
                 frame_length_ms: self.config.frame_length_ms,
                 snip_edges: true,
@@ -175,7 +200,7 @@ impl WavFrontend {
     /// # Errors
     ///
     /// Returns an error if the file cannot be opened or if the CMVN data is malformed.
-    fn load_cmvn(path: &PathBuf) -> Res<(Tensor, Tensor)> {
+    pub(crate) fn load_cmvn(path: &PathBuf) -> Res<(Tensor, Tensor)> {
         let file = File::open(path.deref())?;
         let reader = std::io::BufReader::new(file);
         let mut lines = std::io::BufRead::lines(reader);
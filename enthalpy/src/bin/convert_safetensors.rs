@@ -0,0 +1,47 @@
+use anyhow::{Error, bail};
+use candle_core::Device;
+use enthalpy::Res;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Convert a pickled checkpoint such as ModelScope's `model.pt` to safetensors
+///
+/// Tensor names and dtypes are kept as they are.
+///
+/// ```text
+/// cargo run --bin convert_safetensors -- model.pt [model.safetensors]
+/// ```
+fn main() -> Res<()> {
+    let mut args = env::args().skip(1);
+    let input =
+        PathBuf::from(args.next().ok_or_else(|| {
+            Error::msg("Usage: convert_safetensors <model.pt> [model.safetensors]")
+        })?);
+    let output = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| input.with_extension("safetensors"));
+
+    let start = Instant::now();
+    let tensors = candle_core::pickle::read_all(&input)?
+        .into_iter()
+        .collect::<HashMap<_, _>>();
+    println!("loaded {} tensors in {:?}", tensors.len(), start.elapsed());
+
+    candle_core::safetensors::save(&tensors, &output)?;
+
+    let start = Instant::now();
+    let written = candle_core::safetensors::load(&output, &Device::Cpu)?;
+    if let Some(name) = tensors.keys().find(|n| !written.contains_key(*n)) {
+        bail!("Tensor {} was not written", name);
+    }
+    println!(
+        "wrote {} (loads in {:?})",
+        output.display(),
+        start.elapsed()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use anyhow::Error;
+use enthalpy::Res;
+use enthalpy::sense_voice_small::{GgufQuant, export_gguf};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Export a SenseVoiceSmall model directory to a single GGUF file
+///
+/// Quantization is one of f16, q8_0, q5_k or q4_k, q8_0 by default.
+///
+/// ```text
+/// cargo run --release --bin export_gguf -- ~/.cache/modelscope/hub/models/iic/SenseVoiceSmall q4_k
+/// ```
+fn main() -> Res<()> {
+    let mut args = env::args().skip(1);
+    let usage = || Error::msg("Usage: export_gguf <model_dir> [quant] [output.gguf]");
+    let model_dir = PathBuf::from(args.next().ok_or_else(usage)?);
+    let name = args.next().unwrap_or_else(|| "q8_0".to_string());
+    let quant = GgufQuant::from_name(&name).ok_or_else(usage)?;
+    let output = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| model_dir.join(format!("model-{name}.gguf")));
+
+    let start = Instant::now();
+    export_gguf(&model_dir, quant, &output)?;
+    println!(
+        "wrote {} ({} MB) in {:?}",
+        output.display(),
+        fs::metadata(&output)?.len() / 1_000_000,
+        start.elapsed()
+    );
+
+    Ok(())
+}
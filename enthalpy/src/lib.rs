@@ -1,11 +1,17 @@
 pub mod audio;
 mod config;
+pub mod lm;
+pub mod paraformer;
+pub mod punctuation;
 #[allow(dead_code)]
 mod quantized_nn;
 mod quantized_var_builder;
+pub mod recognizer;
 pub mod sense_voice_small;
+pub mod speaker;
 pub mod util;
 pub mod var_builder;
+pub mod whisper;
 
 pub use config::ConfigRefresher;
 
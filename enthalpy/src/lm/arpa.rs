@@ -0,0 +1,92 @@
+use crate::Res;
+use crate::lm::{Gram, LN_10, NgramLm};
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+impl NgramLm {
+    /// Parse an ARPA text file
+    pub fn from_arpa<P: AsRef<Path>>(path: P) -> Res<Self> {
+        let reader = BufReader::new(File::open(path)?);
+
+        let mut order = 0;
+        let mut section = 0;
+        let mut words = Vec::new();
+        let mut vocab = HashMap::<String, u32>::new();
+        let mut grams = HashMap::new();
+
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line == "\\data\\" {
+                continue;
+            }
+
+            if line == "\\end\\" {
+                break;
+            }
+
+            if let Some(n) = line.strip_prefix("ngram ") {
+                let (n, _) = n
+                    .split_once('=')
+                    .with_context(|| format!("Bad ngram count at line {}", line_no + 1))?;
+                order = order.max(n.trim().parse::<usize>()?);
+                continue;
+            }
+
+            if let Some(n) = line
+                .strip_prefix('\\')
+                .and_then(|l| l.strip_suffix("-grams:"))
+            {
+                section = n.parse::<usize>()?;
+                continue;
+            }
+
+            if section == 0 {
+                bail!("Unexpected line {} before any n-gram section", line_no + 1);
+            }
+
+            // log10(prob) w1 .. wn [log10(backoff)]
+            let mut parts = line.split_whitespace();
+            let prob = parts
+                .next()
+                .with_context(|| format!("Missing probability at line {}", line_no + 1))?
+                .parse::<f32>()?;
+            let parts = parts.collect::<Vec<&str>>();
+            if parts.len() < section {
+                bail!("Expected {section} words at line {}", line_no + 1);
+            }
+
+            let backoff = match parts.get(section) {
+                Some(b) => b.parse::<f32>()?,
+                None => 0.0,
+            };
+
+            let mut ids = Vec::with_capacity(section);
+            for word in &parts[..section] {
+                let id = match vocab.get(*word) {
+                    Some(id) => *id,
+                    None => {
+                        let id = words.len() as u32;
+                        words.push(word.to_string());
+                        vocab.insert(word.to_string(), id);
+                        id
+                    }
+                };
+                ids.push(id);
+            }
+
+            grams.insert(
+                ids,
+                Gram {
+                    prob: prob * LN_10,
+                    backoff: backoff * LN_10,
+                },
+            );
+        }
+
+        Self::new(order, words, grams)
+    }
+}
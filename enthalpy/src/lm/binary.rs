@@ -0,0 +1,184 @@
+use crate::Res;
+use crate::lm::{Gram, NgramLm};
+use anyhow::bail;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"ENTLM\0\0\x01";
+
+/// Most entries preallocated from the counts in the file, which may be corrupt
+const MAX_PREALLOC: usize = 1 << 20;
+
+impl NgramLm {
+    /// Write the model in the compact binary format
+    ///
+    /// Layout, all integers little endian:
+    /// magic, order: u32, word count: u32, words as (len: u32, utf8),
+    /// gram count: u64, grams as (n: u8, ids: [u32; n], prob: f32, backoff: f32)
+    pub fn save_binary<P: AsRef<Path>>(&self, path: P) -> Res<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        w.write_all(&(self.order as u32).to_le_bytes())?;
+
+        w.write_all(&(self.words.len() as u32).to_le_bytes())?;
+        for word in &self.words {
+            w.write_all(&(word.len() as u32).to_le_bytes())?;
+            w.write_all(word.as_bytes())?;
+        }
+
+        w.write_all(&(self.grams.len() as u64).to_le_bytes())?;
+        for (ids, gram) in &self.grams {
+            w.write_all(&[ids.len() as u8])?;
+            for id in ids {
+                w.write_all(&id.to_le_bytes())?;
+            }
+            w.write_all(&gram.prob.to_le_bytes())?;
+            w.write_all(&gram.backoff.to_le_bytes())?;
+        }
+
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read a model written by [`NgramLm::save_binary`]
+    pub fn from_binary<P: AsRef<Path>>(path: P) -> Res<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("Not a binary language model");
+        }
+
+        let order = read_u32(&mut r)? as usize;
+
+        let word_count = read_u32(&mut r)? as usize;
+        let mut words = Vec::with_capacity(word_count.min(MAX_PREALLOC));
+        for _ in 0..word_count {
+            let len = read_u32(&mut r)? as usize;
+            let mut buf = Vec::new();
+            r.by_ref().take(len as u64).read_to_end(&mut buf)?;
+            if buf.len() != len {
+                bail!("Language model ends inside a word");
+            }
+            words.push(String::from_utf8(buf)?);
+        }
+
+        let mut buf = [0u8; 8];
+        r.read_exact(&mut buf)?;
+        let gram_count = u64::from_le_bytes(buf) as usize;
+        let mut grams = HashMap::with_capacity(gram_count.min(MAX_PREALLOC));
+        for _ in 0..gram_count {
+            let mut n = [0u8; 1];
+            r.read_exact(&mut n)?;
+            let ids = (0..n[0])
+                .map(|_| read_u32(&mut r))
+                .collect::<Res<Vec<u32>>>()?;
+            if ids.iter().any(|&id| id as usize >= words.len()) {
+                bail!("N-gram refers to a word outside the vocabulary");
+            }
+            let prob = f32::from_bits(read_u32(&mut r)?);
+            let backoff = f32::from_bits(read_u32(&mut r)?);
+            grams.insert(ids, Gram { prob, backoff });
+        }
+
+        Self::new(order, words, grams)
+    }
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Res<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    const ARPA: &str = "\\data\\
+ngram 1=5
+ngram 2=3
+
+\\1-grams:
+-1.0 <s> -0.3
+-1.2 </s>
+-2.0 <unk>
+-0.8 a -0.2
+-0.9 b -0.4
+
+\\2-grams:
+-0.2 <s> a
+-0.5 a b
+-0.3 b </s>
+
+\\end\\
+";
+
+    #[test]
+    fn binary_round_trip_keeps_scores() -> Res<()> {
+        let dir = env::temp_dir().join(format!("enthalpy-lm-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let arpa = dir.join("lm.arpa");
+        let bin = dir.join("lm.bin");
+        fs::write(&arpa, ARPA)?;
+
+        let lm = NgramLm::from_arpa(&arpa)?;
+        lm.save_binary(&bin)?;
+        let loaded = NgramLm::from_binary(&bin)?;
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(loaded.order(), lm.order());
+        let (bos, eos) = (lm.bos(), lm.eos());
+        let (a, b) = (lm.word_id("a"), lm.word_id("b"));
+        for (context, word) in [
+            (vec![bos], a),
+            (vec![bos], b),
+            (vec![bos, a.unwrap()], b),
+            (vec![b.unwrap()], Some(eos)),
+            (vec![a.unwrap()], a),
+            (vec![], None),
+        ] {
+            assert_eq!(loaded.score(&context, word), lm.score(&context, word));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_binary_is_rejected() -> Res<()> {
+        let dir = env::temp_dir().join(format!("enthalpy-lm-corrupt-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let file = dir.join("lm.bin");
+
+        let mut zero_order = MAGIC.to_vec();
+        zero_order.extend(0u32.to_le_bytes());
+        zero_order.extend(0u32.to_le_bytes());
+        zero_order.extend(0u64.to_le_bytes());
+
+        let mut huge_counts = MAGIC.to_vec();
+        huge_counts.extend(2u32.to_le_bytes());
+        huge_counts.extend(u32::MAX.to_le_bytes());
+
+        let mut unknown_word = MAGIC.to_vec();
+        unknown_word.extend(2u32.to_le_bytes());
+        unknown_word.extend(1u32.to_le_bytes());
+        unknown_word.extend(3u32.to_le_bytes());
+        unknown_word.extend(b"<s>");
+        unknown_word.extend(1u64.to_le_bytes());
+        unknown_word.push(1);
+        unknown_word.extend(7u32.to_le_bytes());
+
+        for bytes in [zero_order, huge_counts, unknown_word] {
+            fs::write(&file, bytes)?;
+            assert!(NgramLm::from_binary(&file).is_err());
+        }
+        fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Back-off n-gram language models
+//!
+//! Models are read from ARPA text files or from a compact binary format
+//! written by [`NgramLm::save_binary`], which loads much faster.
+
+use crate::Res;
+use anyhow::{Error, bail};
+use std::collections::HashMap;
+use std::path::Path;
+
+mod arpa;
+mod binary;
+
+/// ln(10), ARPA files store log10 probabilities
+const LN_10: f32 = std::f32::consts::LN_10;
+
+pub const BOS: &str = "<s>";
+pub const EOS: &str = "</s>";
+pub const UNK: &str = "<unk>";
+
+/// Probability and back-off weight of an n-gram, both natural log
+#[derive(Clone, Copy, Debug)]
+struct Gram {
+    prob: f32,
+    backoff: f32,
+}
+
+pub struct NgramLm {
+    order: usize,
+    words: Vec<String>,
+    vocab: HashMap<String, u32>,
+    grams: HashMap<Vec<u32>, Gram>,
+    /// Log probability of words missing from the vocabulary
+    unk_prob: f32,
+}
+
+impl NgramLm {
+    /// Load an ARPA (`.arpa`) or binary (`.bin`) model
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Res<Self> {
+        let path = path.as_ref();
+        let ext = path
+            .extension()
+            .ok_or_else(|| Error::msg("No extension found"))?;
+
+        if ext == "arpa" {
+            return Self::from_arpa(path);
+        }
+
+        if ext == "bin" {
+            return Self::from_binary(path);
+        }
+
+        Err(Error::msg("Unsupported language model extension"))
+    }
+
+    fn new(order: usize, words: Vec<String>, grams: HashMap<Vec<u32>, Gram>) -> Res<Self> {
+        if order == 0 {
+            bail!("Language model has no n-gram order");
+        }
+
+        let vocab = words
+            .iter()
+            .enumerate()
+            .map(|(id, w)| (w.clone(), id as u32))
+            .collect::<HashMap<String, u32>>();
+
+        for special in [BOS, EOS] {
+            if !vocab.contains_key(special) {
+                bail!("Language model has no {special} unigram");
+            }
+        }
+
+        let unk_prob = vocab
+            .get(UNK)
+            .and_then(|id| grams.get(&vec![*id]))
+            .map(|g| g.prob)
+            .unwrap_or(-100.0);
+
+        Ok(Self {
+            order,
+            words,
+            vocab,
+            grams,
+            unk_prob,
+        })
+    }
+
+    /// Highest n-gram order
+    pub fn order(&self) -> usize {
+        self.order
+    }
+
+    /// Id of a word, None if it is out of vocabulary
+    pub fn word_id(&self, word: &str) -> Option<u32> {
+        self.vocab.get(word).copied()
+    }
+
+    pub fn bos(&self) -> u32 {
+        self.vocab[BOS]
+    }
+
+    pub fn eos(&self) -> u32 {
+        self.vocab[EOS]
+    }
+
+    /// Natural log probability of `word` following `context`
+    ///
+    /// `context` is oldest first, only its last `order - 1` words are used.
+    /// Out of vocabulary words (`None`) get the `<unk>` probability.
+    pub fn score(&self, context: &[u32], word: Option<u32>) -> f32 {
+        let Some(word) = word else {
+            return self.unk_prob;
+        };
+
+        let context = &context[context.len().saturating_sub(self.order - 1)..];
+        let mut backoff = 0.0;
+        for start in 0..=context.len() {
+            let history = &context[start..];
+            let mut gram = history.to_vec();
+            gram.push(word);
+
+            if let Some(g) = self.grams.get(&gram) {
+                return backoff + g.prob;
+            }
+
+            if let Some(h) = self.grams.get(history) {
+                backoff += h.backoff;
+            }
+        }
+
+        backoff + self.unk_prob
+    }
+
+    /// Shorten a context to what the model can still use
+    pub fn trim_context(&self, context: &mut Vec<u32>) {
+        let keep = self.order.saturating_sub(1);
+        if context.len() > keep {
+            context.drain(..context.len() - keep);
+        }
+    }
+}
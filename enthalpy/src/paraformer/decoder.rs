@@ -0,0 +1,224 @@
+use crate::Res;
+use crate::sense_voice_small::encoder::{FsmnBlock, FsmnDevice};
+use crate::var_builder::{LayerNorm, Linear, VarBuilder};
+use candle_core::{DType, Module, Tensor};
+
+/// Configuration for ParaformerSANMDecoder
+pub struct DecoderConfig {
+    /// Output vocabulary size
+    pub vocab_size: usize,
+    /// Attention and encoder output size
+    pub attention_dim: usize,
+    /// Number of cross attention heads
+    pub attention_heads: usize,
+    /// Number of linear units
+    pub linear_units: usize,
+    /// Number of decoder blocks with cross attention
+    pub att_layer_num: usize,
+    /// FSMN kernel size
+    pub kernel_size: usize,
+    /// SANM shift
+    pub sanm_shfit: usize,
+    /// Where the FSMN blocks run
+    pub fsmn_device: FsmnDevice,
+}
+
+/// Feed forward with a layer norm before the output projection
+struct PositionwiseFeedForwardDecoderSANM {
+    w_1: Linear,
+    w_2: Linear,
+    norm: LayerNorm,
+}
+
+impl PositionwiseFeedForwardDecoderSANM {
+    fn new(in_dim: usize, hidden_units: usize, vb: VarBuilder) -> Res<Self> {
+        Ok(Self {
+            w_1: vb.pp("w_1").linear(in_dim, hidden_units)?,
+            w_2: vb.pp("w_2").linear_no_bias(hidden_units, in_dim)?,
+            norm: vb.pp("norm").layer_norm(hidden_units, 1e-5)?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        let x = self.w_1.forward(x)?.relu()?;
+        let x = self.norm.forward(&x)?;
+        Ok(self.w_2.forward(&x)?)
+    }
+}
+
+/// Attention of the acoustic embeddings to the encoder output
+struct MultiHeadedAttentionCrossAtt {
+    d_k: usize,
+    h: usize,
+    linear_q: Linear,
+    /// Combined linear layer for K, V transformations of the encoder output
+    linear_k_v: Linear,
+    linear_out: Linear,
+}
+
+impl MultiHeadedAttentionCrossAtt {
+    fn new(n_head: usize, n_feat: usize, vb: VarBuilder) -> Res<Self> {
+        assert_eq!(n_feat % n_head, 0, "n_feat must be divisible by n_head");
+
+        Ok(Self {
+            d_k: n_feat / n_head,
+            h: n_head,
+            linear_q: vb.pp("linear_q").linear(n_feat, n_feat)?,
+            linear_k_v: vb.pp("linear_k_v").linear(n_feat, n_feat * 2)?,
+            linear_out: vb.pp("linear_out").linear(n_feat, n_feat)?,
+        })
+    }
+
+    /// Forward pass
+    ///
+    /// # Arguments
+    /// * `x` - Acoustic embeddings (batch, tokens, size)
+    /// * `memory` - Encoder output (batch, time, size)
+    fn forward(&self, x: &Tensor, memory: &Tensor) -> Res<Tensor> {
+        let heads = |x: &Tensor| -> Res<Tensor> {
+            let (b, t, _) = x.dims3()?;
+            Ok(x.reshape((b, t, self.h, self.d_k))?
+                .transpose(1, 2)?
+                .contiguous()?)
+        };
+
+        let q = heads(&self.linear_q.forward(x)?)?;
+        let k_v = self.linear_k_v.forward(memory)?.chunk(2, 2)?;
+        let (k, v) = (heads(&k_v[0])?, heads(&k_v[1])?);
+
+        // Softmax runs in f32 so half precision scores do not overflow
+        let scale = (self.d_k as f64).powf(-0.5);
+        let scores = (q * scale)?.matmul(&k.t()?)?.to_dtype(DType::F32)?;
+        let attn = candle_nn::ops::softmax_last_dim(&scores)?.to_dtype(v.dtype())?;
+
+        let x = attn.matmul(&v)?.transpose(1, 2)?.flatten_from(2)?;
+        Ok(self.linear_out.forward(&x)?)
+    }
+}
+
+struct DecoderLayerSANM {
+    /// FSMN self attention, None for the last layer
+    self_attn: Option<FsmnBlock>,
+    src_attn: Option<MultiHeadedAttentionCrossAtt>,
+    feed_forward: PositionwiseFeedForwardDecoderSANM,
+    norm1: LayerNorm,
+    norm2: Option<LayerNorm>,
+    norm3: Option<LayerNorm>,
+}
+
+impl DecoderLayerSANM {
+    /// Layer with FSMN self attention and cross attention, or with only the
+    /// feed forward when `attention` is false
+    fn new(cfg: &DecoderConfig, attention: bool, vb: VarBuilder) -> Res<Self> {
+        let size = cfg.attention_dim;
+        let feed_forward =
+            PositionwiseFeedForwardDecoderSANM::new(size, cfg.linear_units, vb.pp("feed_forward"))?;
+        let norm1 = vb.pp("norm1").layer_norm(size, 1e-5)?;
+        if !attention {
+            return Ok(Self {
+                self_attn: None,
+                src_attn: None,
+                feed_forward,
+                norm1,
+                norm2: None,
+                norm3: None,
+            });
+        }
+
+        let self_attn = FsmnBlock::new(
+            size,
+            cfg.kernel_size,
+            cfg.sanm_shfit,
+            cfg.fsmn_device,
+            0.0,
+            vb.pp("self_attn").pp("fsmn_block"),
+        )?;
+        let src_attn =
+            MultiHeadedAttentionCrossAtt::new(cfg.attention_heads, size, vb.pp("src_attn"))?;
+
+        Ok(Self {
+            self_attn: Some(self_attn),
+            src_attn: Some(src_attn),
+            feed_forward,
+            norm1,
+            norm2: Some(vb.pp("norm2").layer_norm(size, 1e-5)?),
+            norm3: Some(vb.pp("norm3").layer_norm(size, 1e-5)?),
+        })
+    }
+
+    /// Forward pass
+    ///
+    /// Without self attention the feed forward output is returned as is,
+    /// there is no residual connection around it.
+    fn forward(&self, tgt: &Tensor, memory: &Tensor) -> Res<Tensor> {
+        let residual = tgt;
+        let tgt = self.feed_forward.forward(&self.norm1.forward(tgt)?)?;
+
+        let mut x = tgt.clone();
+        if let (Some(self_attn), Some(norm2)) = (&self.self_attn, &self.norm2) {
+            let attn = self_attn.forward(&norm2.forward(&tgt)?, None)?;
+            x = (residual + attn)?;
+        }
+
+        if let (Some(src_attn), Some(norm3)) = (&self.src_attn, &self.norm3) {
+            let attn = src_attn.forward(&norm3.forward(&x)?, memory)?;
+            x = (x + attn)?;
+        }
+
+        Ok(x)
+    }
+}
+
+/// Non-autoregressive decoder of Paraformer
+///
+/// Every acoustic embedding of the CIF predictor becomes one token in a single
+/// pass, so there is no search.
+pub struct Decoder {
+    decoders: Vec<DecoderLayerSANM>,
+    decoders3: Vec<DecoderLayerSANM>,
+    after_norm: LayerNorm,
+    output_layer: Linear,
+}
+
+impl Decoder {
+    pub fn new(cfg: &DecoderConfig, vb: VarBuilder) -> Res<Self> {
+        let vb = vb.pp("decoder");
+
+        let decoders = (0..cfg.att_layer_num)
+            .map(|i| DecoderLayerSANM::new(cfg, true, vb.pp("decoders").pp(i)))
+            .collect::<Res<Vec<_>>>()?;
+        let decoders3 = vec![DecoderLayerSANM::new(cfg, false, vb.pp("decoders3").pp(0))?];
+
+        let after_norm = vb.pp("after_norm").layer_norm(cfg.attention_dim, 1e-5)?;
+        let output_layer = vb
+            .pp("output_layer")
+            .linear(cfg.attention_dim, cfg.vocab_size)?;
+
+        Ok(Self {
+            decoders,
+            decoders3,
+            after_norm,
+            output_layer,
+        })
+    }
+
+    /// Forward pass
+    ///
+    /// # Arguments
+    /// * `acoustic_embeds` - Acoustic embeddings of the predictor (batch, tokens, size)
+    /// * `memory` - Encoder output (batch, time, size)
+    ///
+    /// # Returns
+    /// * Log probabilities (batch, tokens, vocab) in f32
+    pub fn forward(&self, acoustic_embeds: &Tensor, memory: &Tensor) -> Res<Tensor> {
+        let mut x = acoustic_embeds.clone();
+        for layer in self.decoders.iter().chain(&self.decoders3) {
+            x = layer.forward(&x, memory)?;
+        }
+
+        let x = self.after_norm.forward(&x)?;
+        let logits = self.output_layer.forward(&x)?.to_dtype(DType::F32)?;
+
+        Ok(candle_nn::ops::log_softmax(&logits, 2)?)
+    }
+}
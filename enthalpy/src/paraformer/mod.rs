@@ -0,0 +1,316 @@
+//! Paraformer-large speech recognition
+//!
+//! FunASR's non-autoregressive Mandarin model: the SANM encoder of
+//! SenseVoiceSmall, a CIF predictor that turns encoder frames into one
+//! acoustic embedding per token, and a decoder that reads all tokens in a
+//! single pass. Token timing comes from where the predictor fires.
+
+use crate::Res;
+use crate::audio::segmenter::Segmenter;
+use crate::audio::silero_vad::{Segment, VadConfig};
+use crate::audio::{WavFrontend, WavFrontendConfig};
+use crate::config::ConfigRefresher;
+use crate::recognizer::{SpeechRecognizer, transpose_each};
+use crate::sense_voice_small::encoder::{Encoder, EncoderConfig, FsmnDevice};
+use crate::sense_voice_small::{Hypothesis, Tags, Token};
+use crate::util::modelscope::{FileInfo, ModelScopeRepo};
+use crate::util::select_device;
+use crate::var_builder::VarBuilder;
+use anyhow::Error;
+use candle_core::{D, DType, Device};
+use decoder::{Decoder, DecoderConfig};
+use predictor::CifPredictor;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{Level, event};
+
+mod decoder;
+mod predictor;
+
+const MODEL_ID: &str = "iic/speech_paraformer-large_asr_nat-zh-cn-16k-common-vocab8404-pytorch";
+
+/// CMVN stats, weights and token list of the checkpoint
+const MODEL_FILES: [&str; 3] = ["am.mvn", "model.pt", "tokens.json"];
+
+/// Duration of one encoder frame in milliseconds
+const FRAME_MS: u32 = 60;
+
+/// Longest a token lasts, earlier frames since the previous token are silence
+const MAX_TOKEN_MS: u32 = 240;
+
+/// Word piece marker of English tokens continued by the next token
+const CONTINUATION: &str = "@@";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ParaformerConfig {
+    pub model_dir: PathBuf,
+    pub vad: VadConfig,
+    pub resample: Option<(u32, u32)>,
+    pub use_gpu: bool,
+    /// Where the FSMN memory blocks of the encoder and decoder run
+    pub fsmn_device: FsmnDevice,
+}
+
+pub struct Paraformer {
+    device: Device,
+    segmenter: Segmenter,
+    frontend: WavFrontend,
+    encoder: Encoder,
+    predictor: CifPredictor,
+    decoder: Decoder,
+    tokens: Vec<String>,
+}
+
+impl Paraformer {
+    pub async fn with_config(cfg: ParaformerConfig) -> Res<Self> {
+        let device = select_device(cfg.use_gpu)?;
+        Self::new(cfg, &device).await
+    }
+
+    pub async fn new(cfg: ParaformerConfig, device: &Device) -> Res<Self> {
+        let device = device.clone();
+        let repo = Self::model_repo(&cfg.model_dir).await;
+
+        let start = Instant::now();
+        let tokens: Vec<String> =
+            serde_json::from_reader(File::open(repo.get("tokens.json").await?)?)?;
+        let frontend = Self::init_frontend(&repo.get("am.mvn").await?)?;
+        let weight_file = repo.get("model.pt").await?;
+        let vb = VarBuilder::from_file(&weight_file, DType::F32, &device)?;
+
+        let encoder = Encoder::new_with_config(encoder_config(cfg.fsmn_device), vb.clone())?;
+        let predictor = CifPredictor::new(512, 1, 1, 1.0, 0.45, vb.clone())?;
+        let decoder = Decoder::new(&decoder_config(tokens.len(), cfg.fsmn_device), vb)?;
+        event!(
+            Level::INFO,
+            "Loaded {} in {:.2?}",
+            weight_file.display(),
+            start.elapsed()
+        );
+
+        Ok(Self {
+            device,
+            segmenter: Segmenter::new(cfg.resample, &cfg.vad)?,
+            frontend,
+            encoder,
+            predictor,
+            decoder,
+            tokens,
+        })
+    }
+
+    pub async fn model_repo<P: Into<PathBuf>>(model_dir: P) -> ModelScopeRepo {
+        ModelScopeRepo::new(MODEL_ID, model_dir.into())
+    }
+
+    pub async fn get_required_files<P: Into<PathBuf>>(model_dir: P) -> Res<Vec<FileInfo>> {
+        let repo = Self::model_repo(model_dir).await;
+        repo.get_files_info(&MODEL_FILES).await
+    }
+
+    pub async fn check_required_files<P: Into<PathBuf>>(model_dir: P) -> bool {
+        Self::model_repo(model_dir).await.has_files(&MODEL_FILES)
+    }
+
+    fn init_frontend(cmvn_file: &Path) -> Res<WavFrontend> {
+        WavFrontend::new(WavFrontendConfig {
+            cmvn_file: Some(cmvn_file.to_path_buf()),
+            ..WavFrontendConfig::default()
+        })
+    }
+
+    pub fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        self.segmenter.segment(waveform)
+    }
+
+    pub fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        transpose_each(segments, |data| self.process(data))
+    }
+
+    pub fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        let mut segment = self.segmenter.samples();
+        transpose_each(segment.as_mut_slice(), |data| self.process(data))
+    }
+
+    /// Recognize one segment, token timing is relative to it
+    fn process(&mut self, waveform: &mut [f32]) -> Res<Hypothesis> {
+        let features = self
+            .frontend
+            .extract_features_f32(waveform)
+            .map_err(|e| Error::msg(e.to_string()))?
+            .to_device(&self.device)?
+            .unsqueeze(0)?;
+        let frames = features.dim(1)?;
+
+        let encoder_out = self.encoder.forward(&features, None)?;
+        let cif = self.predictor.forward(&encoder_out)?;
+
+        let mut hyp = Hypothesis {
+            tokens: Vec::new(),
+            tags: Tags::default(),
+            score: 0.0,
+            keywords: Vec::new(),
+            words: Vec::new(),
+        };
+        if cif.fires.is_empty() {
+            return Ok(hyp);
+        }
+
+        let log_probs = self
+            .decoder
+            .forward(&cif.acoustic_embeds, &encoder_out)?
+            .squeeze(0)?;
+        let ids = log_probs.argmax(D::Minus1)?.to_vec1::<u32>()?;
+        let scores = log_probs.max(D::Minus1)?.to_vec1::<f32>()?;
+
+        let length = frames as u32 * FRAME_MS;
+        let mut prev_end = 0;
+        let mut word_end = false;
+        for ((id, score), fire) in ids.into_iter().zip(scores).zip(cif.fires) {
+            let end = ((fire as u32 + 1) * FRAME_MS).min(length);
+            let start = prev_end.max(end.saturating_sub(MAX_TOKEN_MS));
+            prev_end = end;
+            hyp.score += score;
+
+            let piece = self.tokens.get(id as usize).map_or("", String::as_str);
+            let Some(text) = token_text(piece, &mut word_end) else {
+                continue;
+            };
+            hyp.tokens.push(Token {
+                text,
+                start,
+                end,
+                tags: Tags::default(),
+                confidence: score.exp(),
+                tokens: Vec::new(),
+                words: Vec::new(),
+                keywords: Vec::new(),
+                speaker: None,
+            });
+        }
+
+        Ok(hyp)
+    }
+}
+
+impl ConfigRefresher<ParaformerConfig> for Paraformer {
+    fn refresh(&mut self, _old: &ParaformerConfig, new: &ParaformerConfig) -> Res<()> {
+        self.segmenter.refresh(new.resample, &new.vad)?;
+
+        Ok(())
+    }
+}
+
+impl SpeechRecognizer for Paraformer {
+    type Config = ParaformerConfig;
+
+    async fn load(config: ParaformerConfig) -> Res<Self> {
+        Self::with_config(config).await
+    }
+
+    async fn model_repo(config: &ParaformerConfig) -> ModelScopeRepo {
+        Self::model_repo(&config.model_dir).await
+    }
+
+    async fn required_files(config: &ParaformerConfig) -> Res<Vec<FileInfo>> {
+        Self::get_required_files(&config.model_dir).await
+    }
+
+    async fn check_required_files(config: &ParaformerConfig) -> bool {
+        Self::check_required_files(&config.model_dir).await
+    }
+
+    fn needs_reload(old: &ParaformerConfig, new: &ParaformerConfig) -> bool {
+        old.model_dir != new.model_dir
+            || old.use_gpu != new.use_gpu
+            || old.fsmn_device != new.fsmn_device
+    }
+
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        Self::segment(self, waveform)
+    }
+
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        Self::transpose(self, segments)
+    }
+
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        Self::transpose_vad_cache(self)
+    }
+}
+
+/// Text of a token piece, None for special tokens
+///
+/// English word pieces lose their `@@` marker and words get a space between
+/// them, Chinese characters are joined as they are. `word_end` tracks whether
+/// the previous token ended an English word.
+fn token_text(piece: &str, word_end: &mut bool) -> Option<String> {
+    if matches!(piece, "" | "<blank>" | "<s>" | "</s>" | "<unk>") {
+        return None;
+    }
+
+    let cjk = !piece.is_ascii();
+    let text = match piece.strip_suffix(CONTINUATION) {
+        Some(stem) if *word_end => format!(" {stem}"),
+        Some(stem) => stem.to_string(),
+        None if *word_end && !cjk => format!(" {piece}"),
+        None => piece.to_string(),
+    };
+    *word_end = !cjk && !piece.ends_with(CONTINUATION);
+
+    Some(text)
+}
+
+/// Hyperparameters of the Paraformer-large encoder
+fn encoder_config(fsmn_device: FsmnDevice) -> EncoderConfig {
+    EncoderConfig {
+        input_size: 560,
+        output_size: 512,
+        attention_heads: 4,
+        linear_units: 2048,
+        num_blocks: 50,
+        tp_blocks: 0,
+        dropout_rate: 0.1,
+        attention_dropout_rate: 0.1,
+        kernel_size: 11,
+        sanm_shfit: 0,
+        fsmn_device,
+        normalize_before: true,
+        concat_after: false,
+    }
+}
+
+/// Hyperparameters of the Paraformer-large decoder
+fn decoder_config(vocab_size: usize, fsmn_device: FsmnDevice) -> DecoderConfig {
+    DecoderConfig {
+        vocab_size,
+        attention_dim: 512,
+        attention_heads: 4,
+        linear_units: 2048,
+        att_layer_num: 16,
+        kernel_size: 11,
+        sanm_shfit: 0,
+        fsmn_device,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_text_joins_word_pieces() {
+        let pieces = [
+            "hel@@", "lo", "wor@@", "ld", "你", "好", "ok", "<blank>", "go", "</s>",
+        ];
+        let mut word_end = false;
+        let text = pieces
+            .iter()
+            .filter_map(|piece| token_text(piece, &mut word_end))
+            .collect::<String>();
+        assert_eq!(text, "hello world你好ok go");
+    }
+}
@@ -0,0 +1,175 @@
+use crate::Res;
+use crate::var_builder::{Linear, VarBuilder};
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{Conv1d, Conv1dConfig};
+
+/// Continuous integrate-and-fire predictor (CifPredictorV2)
+///
+/// Every encoder frame gets a weight, the weights are summed until they reach
+/// the threshold and the weighted frames so far fire as one acoustic embedding.
+/// Where a token fires gives its timing.
+pub struct CifPredictor {
+    cif_conv1d: Conv1d,
+    cif_output: Linear,
+    /// Integrated weight of one token
+    threshold: f32,
+    /// Weight of an extra silent frame at the end, so the last token fires
+    tail_threshold: f32,
+}
+
+/// Acoustic embeddings of one sequence
+pub struct CifOutput {
+    /// One embedding per token (1, tokens, size)
+    pub acoustic_embeds: Tensor,
+    /// Encoder frame at which each token fired
+    pub fires: Vec<usize>,
+}
+
+impl CifPredictor {
+    /// Creates a new CifPredictor instance
+    ///
+    /// # Arguments
+    /// * `idim` - Encoder output size
+    /// * `l_order` - Context frames on the left of the convolution
+    /// * `r_order` - Context frames on the right of the convolution
+    /// * `threshold` - Integrated weight of one token
+    /// * `tail_threshold` - Weight of the silent frame appended at the end
+    /// * `vb` - VarBuilder for creating layers
+    pub fn new(
+        idim: usize,
+        l_order: usize,
+        r_order: usize,
+        threshold: f32,
+        tail_threshold: f32,
+        vb: VarBuilder,
+    ) -> Res<Self> {
+        let vb = vb.pp("predictor");
+        let cif_conv1d = vb.pp("cif_conv1d").conv1d(
+            idim,
+            idim,
+            l_order + r_order + 1,
+            Conv1dConfig {
+                padding: l_order,
+                ..Conv1dConfig::default()
+            },
+        )?;
+        let cif_output = vb.pp("cif_output").linear(idim, 1)?;
+
+        Ok(Self {
+            cif_conv1d,
+            cif_output,
+            threshold,
+            tail_threshold,
+        })
+    }
+
+    /// Predict the acoustic embeddings of the encoder output (1, time, size)
+    pub fn forward(&self, hidden: &Tensor) -> Res<CifOutput> {
+        let context = hidden.transpose(1, 2)?;
+        let output = self.cif_conv1d.forward(&context)?.relu()?.transpose(1, 2)?;
+        let alphas = candle_nn::ops::sigmoid(&self.cif_output.forward(&output)?)?;
+        let mut alphas = alphas
+            .flatten_all()?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?;
+
+        // A silent frame with the tail weight lets a token cut off at the end fire
+        alphas.push(self.tail_threshold);
+        let token_num = alphas.iter().sum::<f32>().floor() as usize;
+
+        let hidden_cpu = hidden
+            .squeeze(0)?
+            .to_dtype(DType::F32)?
+            .to_device(&Device::Cpu)?
+            .to_vec2::<f32>()?;
+        let size = hidden.dim(2)?;
+        let silence = vec![0f32; size];
+
+        let (embeds, mut fires) = cif(
+            hidden_cpu.iter().chain([&silence]).map(Vec::as_slice),
+            &alphas,
+            size,
+            self.threshold,
+        );
+        fires.truncate(token_num);
+
+        let tokens = fires.len();
+        let embeds = embeds
+            .into_iter()
+            .take(tokens)
+            .flatten()
+            .collect::<Vec<_>>();
+        let acoustic_embeds = Tensor::from_vec(embeds, (1, tokens, size), hidden.device())?
+            .to_dtype(hidden.dtype())?;
+
+        Ok(CifOutput {
+            acoustic_embeds,
+            fires,
+        })
+    }
+}
+
+/// Integrate `frames` weighted by `alphas` and fire an embedding every
+/// time the weight reaches the threshold
+///
+/// The weight of a firing frame is split between the token it completes
+/// and the next one.
+fn cif<'a>(
+    frames: impl Iterator<Item = &'a [f32]>,
+    alphas: &[f32],
+    size: usize,
+    threshold: f32,
+) -> (Vec<Vec<f32>>, Vec<usize>) {
+    let mut embeds = Vec::new();
+    let mut fires = Vec::new();
+    let mut integrate = 0f32;
+    let mut frame = vec![0f32; size];
+    for (t, (hidden, &alpha)) in frames.zip(alphas).enumerate() {
+        let completion = 1.0 - integrate;
+        integrate += alpha;
+        if integrate < threshold {
+            frame
+                .iter_mut()
+                .zip(hidden)
+                .for_each(|(f, h)| *f += alpha * h);
+            continue;
+        }
+
+        integrate -= 1.0;
+        let remainder = alpha - completion;
+        frame
+            .iter_mut()
+            .zip(hidden)
+            .for_each(|(f, h)| *f += completion * h);
+        embeds.push(frame);
+        fires.push(t);
+        frame = hidden.iter().map(|h| remainder * h).collect();
+    }
+
+    (embeds, fires)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cif_fires_where_the_weight_reaches_the_threshold() {
+        let frames = [1f32, 2., 3., 4., 5.].map(|x| [x, 10.0 * x]);
+        let alphas = [0.5, 0.75, 0.5, 0.25, 1.0];
+        let (embeds, fires) = cif(frames.iter().map(|f| f.as_slice()), &alphas, 2, 1.0);
+
+        assert_eq!(fires, [1, 3, 4]);
+        // Frame 1 gives half its weight to the first token and a quarter to
+        // the second, frame 3 completes the second token exactly
+        let expected = [
+            0.5 * 1.0 + 0.5 * 2.0,
+            0.25 * 2.0 + 0.5 * 3.0 + 0.25 * 4.0,
+            5.0,
+        ];
+        for (embed, expected) in embeds.iter().zip(expected) {
+            assert!((embed[0] - expected).abs() < 1e-6, "{embed:?}");
+            assert!((embed[1] - 10.0 * expected).abs() < 1e-5, "{embed:?}");
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Punctuation restoration
+//!
+//! FunASR's CT-Transformer: word embeddings through the SANM encoder of
+//! SenseVoiceSmall and a linear layer that tags every word with the
+//! punctuation following it. Used on the text of engines that do not
+//! punctuate, see [`PunctuationStream`] for live captions.
+
+use crate::Res;
+use crate::sense_voice_small::encoder::{Encoder, EncoderConfig};
+use crate::util::modelscope::{FileInfo, ModelScopeRepo};
+use crate::util::{is_cjk, select_device};
+use crate::var_builder::{Linear, VarBuilder};
+use anyhow::Error;
+use candle_core::{D, DType, Device, Module, Tensor};
+use candle_nn::Embedding;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::{Level, event};
+
+mod stream;
+
+pub use stream::PunctuationStream;
+
+const MODEL_ID: &str = "iic/punc_ct-transformer_zh-cn-common-vocab272727-pytorch";
+
+/// Weights and vocabulary of the punctuation model
+const MODEL_FILES: [&str; 2] = ["model.pt", "tokens.json"];
+
+/// Size of the word embeddings and the encoder
+const EMBED_DIM: usize = 256;
+
+/// Word of the vocabulary used for words it does not know
+const UNK: &str = "<unk>";
+
+/// Punctuation following a word
+///
+/// The classes of the checkpoint, in the order of `punc_list` in its
+/// config.yaml: `<unk>`, `_`, `，`, `。`, `？`, `、`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Punctuation {
+    None,
+    Comma,
+    Period,
+    Question,
+    /// Enumeration comma between items of a list
+    Pause,
+}
+
+impl Punctuation {
+    /// Number of classes the model predicts
+    const CLASSES: usize = 6;
+
+    fn from_class(class: u32) -> Self {
+        match class {
+            2 => Punctuation::Comma,
+            3 => Punctuation::Period,
+            4 => Punctuation::Question,
+            5 => Punctuation::Pause,
+            _ => Punctuation::None,
+        }
+    }
+
+    pub fn is_sentence_end(self) -> bool {
+        matches!(self, Punctuation::Period | Punctuation::Question)
+    }
+
+    /// Mark written after a word, full width after CJK characters
+    pub fn mark(self, cjk: bool) -> &'static str {
+        match (self, cjk) {
+            (Punctuation::None, _) => "",
+            (Punctuation::Comma, true) => "，",
+            (Punctuation::Period, true) => "。",
+            (Punctuation::Question, true) => "？",
+            (Punctuation::Pause, true) => "、",
+            (Punctuation::Comma | Punctuation::Pause, false) => ",",
+            (Punctuation::Period, false) => ".",
+            (Punctuation::Question, false) => "?",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PunctuationConfig {
+    pub model_dir: PathBuf,
+    pub use_gpu: bool,
+    /// Words that have to follow a sentence end before [`PunctuationStream`]
+    /// trusts it, the model often moves the end once the next words arrive
+    pub lookahead: usize,
+}
+
+impl Default for PunctuationConfig {
+    fn default() -> Self {
+        Self {
+            model_dir: PathBuf::default(),
+            use_gpu: false,
+            lookahead: 3,
+        }
+    }
+}
+
+/// CT-Transformer punctuation model
+pub struct CtTransformer {
+    device: Device,
+    embed: Embedding,
+    encoder: Encoder,
+    decoder: Linear,
+    ids: HashMap<String, u32>,
+    unk: u32,
+}
+
+impl CtTransformer {
+    pub async fn with_config(cfg: PunctuationConfig) -> Res<Self> {
+        let device = select_device(cfg.use_gpu)?;
+        Self::new(cfg, &device).await
+    }
+
+    pub async fn new(cfg: PunctuationConfig, device: &Device) -> Res<Self> {
+        let device = device.clone();
+        let repo = Self::model_repo(&cfg.model_dir).await;
+
+        let start = Instant::now();
+        let tokens: Vec<String> =
+            serde_json::from_reader(File::open(repo.get("tokens.json").await?)?)?;
+        let ids = tokens
+            .iter()
+            .enumerate()
+            .map(|(id, word)| (word.clone(), id as u32))
+            .collect::<HashMap<_, _>>();
+        let unk = *ids
+            .get(UNK)
+            .ok_or_else(|| Error::msg(format!("{UNK} is missing in tokens.json")))?;
+
+        let weight_file = repo.get("model.pt").await?;
+        let vb = VarBuilder::from_file(&weight_file, DType::F32, &device)?;
+        let embed = vb.pp("embed").embedding(tokens.len(), EMBED_DIM)?;
+        let encoder = Encoder::new_with_config(encoder_config(), vb.clone())?;
+        let decoder = vb.pp("decoder").linear(EMBED_DIM, Punctuation::CLASSES)?;
+        event!(
+            Level::INFO,
+            "Loaded {} in {:.2?}",
+            weight_file.display(),
+            start.elapsed()
+        );
+
+        Ok(Self {
+            device,
+            embed,
+            encoder,
+            decoder,
+            ids,
+            unk,
+        })
+    }
+
+    pub async fn model_repo<P: Into<PathBuf>>(model_dir: P) -> ModelScopeRepo {
+        ModelScopeRepo::new(MODEL_ID, model_dir.into())
+    }
+
+    pub async fn get_required_files<P: Into<PathBuf>>(model_dir: P) -> Res<Vec<FileInfo>> {
+        let repo = Self::model_repo(model_dir).await;
+        repo.get_files_info(&MODEL_FILES).await
+    }
+
+    pub async fn check_required_files<P: Into<PathBuf>>(model_dir: P) -> bool {
+        Self::model_repo(model_dir).await.has_files(&MODEL_FILES)
+    }
+
+    /// Punctuation following each of `words`
+    ///
+    /// The model reads a single CJK character or a whole word of other
+    /// characters at a time, a word of several CJK characters gets the
+    /// punctuation predicted after its last one.
+    pub fn punctuate(&self, words: &[&str]) -> Res<Vec<Punctuation>> {
+        if words.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut ids = Vec::with_capacity(words.len());
+        let mut last = Vec::with_capacity(words.len());
+        for word in words {
+            let units = split_units(word);
+            if units.is_empty() {
+                ids.push(self.unk);
+            }
+            ids.extend(units.into_iter().map(|unit| self.word_id(unit)));
+            last.push(ids.len() - 1);
+        }
+
+        let len = ids.len();
+        let ids = Tensor::from_vec(ids, (1, len), &self.device)?;
+        let x = self.embed.forward(&ids)?;
+        let x = self.encoder.forward(&x, None)?;
+        let classes = self
+            .decoder
+            .forward(&x)?
+            .squeeze(0)?
+            .argmax(D::Minus1)?
+            .to_vec1::<u32>()?;
+
+        Ok(last
+            .into_iter()
+            .map(|i| Punctuation::from_class(classes[i]))
+            .collect())
+    }
+
+    /// Punctuate a complete text, whose last sentence is closed with a period
+    pub fn punctuate_text(&self, text: &str) -> Res<String> {
+        let words = split_units(text);
+        let mut punctuation = self.punctuate(&words)?;
+        close_sentence(&mut punctuation);
+
+        let mut out = String::with_capacity(text.len() * 2);
+        let mut prev: Option<&str> = None;
+        for (word, punc) in words.into_iter().zip(punctuation) {
+            out.push_str(separator(prev, word));
+            out.push_str(word);
+            out.push_str(punc.mark(ends_cjk(word)));
+            prev = Some(word);
+        }
+
+        Ok(out)
+    }
+
+    /// Vocabulary id of a unit, the vocabulary has English words in lower case
+    fn word_id(&self, unit: &str) -> u32 {
+        self.ids
+            .get(unit)
+            .or_else(|| self.ids.get(&unit.to_lowercase()))
+            .copied()
+            .unwrap_or(self.unk)
+    }
+}
+
+/// Hyperparameters of the CT-Transformer encoder
+fn encoder_config() -> EncoderConfig {
+    EncoderConfig {
+        input_size: EMBED_DIM,
+        output_size: EMBED_DIM,
+        attention_heads: 8,
+        linear_units: 1024,
+        num_blocks: 4,
+        tp_blocks: 0,
+        dropout_rate: 0.1,
+        attention_dropout_rate: 0.0,
+        kernel_size: 11,
+        sanm_shfit: 0,
+        ..EncoderConfig::default()
+    }
+}
+
+/// Split text into the units of the model
+///
+/// A CJK character is a unit of its own, runs of other characters are one
+/// unit and whitespace separates units.
+fn split_units(text: &str) -> Vec<&str> {
+    let mut units = Vec::new();
+    for part in text.split_whitespace() {
+        let mut word_start = None;
+        for (i, c) in part.char_indices() {
+            if !is_cjk(c) {
+                word_start.get_or_insert(i);
+                continue;
+            }
+            if let Some(start) = word_start.take() {
+                units.push(&part[start..i]);
+            }
+            units.push(&part[i..i + c.len_utf8()]);
+        }
+        if let Some(start) = word_start {
+            units.push(&part[start..]);
+        }
+    }
+    units
+}
+
+/// Close the last sentence with a period unless it already ends one
+fn close_sentence(punctuation: &mut [Punctuation]) {
+    if let Some(last) = punctuation.last_mut().filter(|p| !p.is_sentence_end()) {
+        *last = Punctuation::Period;
+    }
+}
+
+fn ends_cjk(word: &str) -> bool {
+    word.chars().last().is_some_and(is_cjk)
+}
+
+/// Space between two words unless one of them is CJK
+fn separator(prev: Option<&str>, word: &str) -> &'static str {
+    match prev {
+        Some(prev) if !ends_cjk(prev) && !word.chars().next().is_some_and(is_cjk) => " ",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cjk_characters_are_units_of_their_own() {
+        assert_eq!(split_units("hello world"), ["hello", "world"]);
+        assert_eq!(split_units("今天天气"), ["今", "天", "天", "气"]);
+        assert_eq!(
+            split_units("用GPU跑 model2 吧"),
+            ["用", "GPU", "跑", "model2", "吧"]
+        );
+        assert!(split_units("  ").is_empty());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::Res;
+use crate::punctuation::{CtTransformer, Punctuation, close_sentence, ends_cjk, separator};
+use crate::sense_voice_small::Token;
+
+/// Pending words that force a sentence end, at the last comma if there is one
+const MAX_PENDING_WORDS: usize = 200;
+
+/// Sentences of live captions
+///
+/// Recognized segments are pushed as they close, their words are punctuated
+/// together with the words still pending and only sentences followed by
+/// `lookahead` more words are taken out. The rest waits for the next segment,
+/// whose words may move the sentence end.
+pub struct PunctuationStream {
+    lookahead: usize,
+    /// Words without punctuation, with the tags of their segment
+    words: Vec<Token>,
+}
+
+impl PunctuationStream {
+    pub fn new(lookahead: usize) -> Self {
+        Self {
+            lookahead,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn set_lookahead(&mut self, lookahead: usize) {
+        self.lookahead = lookahead;
+    }
+
+    /// Whether there are words not yet taken as a sentence
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// Push recognized segments and take the sentences that are complete
+    ///
+    /// Every sentence is a segment level [`Token`] with punctuated words as
+    /// its tokens and the tags and speaker of the segment its first word came
+    /// from.
+    pub fn push(&mut self, model: &CtTransformer, segments: &[Token]) -> Res<Vec<Token>> {
+        self.words.extend(segments.iter().flat_map(segment_words));
+        let punctuation = self.punctuate(model, &self.words)?;
+
+        Ok(self.take_sentences(punctuation))
+    }
+
+    /// Take the sentences followed by `lookahead` more words, given the
+    /// punctuation of all pending words
+    fn take_sentences(&mut self, mut punctuation: Vec<Punctuation>) -> Vec<Token> {
+        let ready = self.words.len().saturating_sub(self.lookahead);
+        let mut cut = punctuation[..ready]
+            .iter()
+            .rposition(|p| p.is_sentence_end())
+            .map(|i| i + 1);
+
+        if cut.is_none() && self.words.len() > MAX_PENDING_WORDS {
+            // Too long without a sentence end, end it at the last comma
+            let end = punctuation[..ready]
+                .iter()
+                .rposition(|p| *p != Punctuation::None)
+                .map_or(ready, |i| i + 1);
+            close_sentence(&mut punctuation[..end]);
+            cut = Some(end);
+        }
+
+        let Some(cut) = cut.filter(|&cut| cut > 0) else {
+            return Vec::new();
+        };
+        let words = self.words.drain(..cut).collect::<Vec<_>>();
+
+        sentences(words, &punctuation[..cut])
+    }
+
+    /// Pending words followed by the words of `segments`, punctuated as one
+    /// caption without taking them, for realtime captions of unclosed speech
+    pub fn preview(&self, model: &CtTransformer, segments: &[Token]) -> Res<Option<Token>> {
+        let mut words = self.words.clone();
+        words.extend(segments.iter().flat_map(segment_words));
+        if words.is_empty() {
+            return Ok(None);
+        }
+
+        let punctuation = self.punctuate(model, &words)?;
+        Ok(Some(sentence(words, &punctuation)))
+    }
+
+    /// Take all pending words, the last sentence is closed with a period
+    pub fn flush(&mut self, model: &CtTransformer) -> Res<Vec<Token>> {
+        let words = std::mem::take(&mut self.words);
+        let mut punctuation = self.punctuate(model, &words)?;
+        close_sentence(&mut punctuation);
+
+        Ok(sentences(words, &punctuation))
+    }
+
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    /// Punctuation of words, a sentence also ends where the speaker changes
+    fn punctuate(&self, model: &CtTransformer, words: &[Token]) -> Res<Vec<Punctuation>> {
+        let texts = words.iter().map(|w| w.text.as_str()).collect::<Vec<_>>();
+        let mut punctuation = model.punctuate(&texts)?;
+        split_speakers(words, &mut punctuation);
+        Ok(punctuation)
+    }
+}
+
+/// End a sentence between two words of different speakers, words without a
+/// speaker never split
+fn split_speakers(words: &[Token], punctuation: &mut [Punctuation]) {
+    for (i, pair) in words.windows(2).enumerate() {
+        if matches!((pair[0].speaker, pair[1].speaker), (Some(a), Some(b)) if a != b) {
+            close_sentence(&mut punctuation[..=i]);
+        }
+    }
+}
+
+/// Words of a segment without their punctuation, tagged like the segment
+fn segment_words(segment: &Token) -> impl Iterator<Item = Token> + '_ {
+    segment.words.iter().filter_map(|word| {
+        let text = word
+            .text
+            .chars()
+            .filter(|c| c.is_alphanumeric() || *c == '\'')
+            .collect::<String>();
+        if text.is_empty() {
+            return None;
+        }
+
+        Some(Token {
+            text,
+            tags: segment.tags.clone(),
+            ..word.clone()
+        })
+    })
+}
+
+/// Split punctuated words at the sentence ends
+fn sentences(words: Vec<Token>, punctuation: &[Punctuation]) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut words = words.into_iter();
+    let mut start = 0;
+    for (i, punc) in punctuation.iter().enumerate() {
+        if punc.is_sentence_end() || i + 1 == punctuation.len() {
+            let group = words.by_ref().take(i + 1 - start).collect();
+            out.push(sentence(group, &punctuation[start..=i]));
+            start = i + 1;
+        }
+    }
+    out
+}
+
+/// One caption of punctuated words
+fn sentence(words: Vec<Token>, punctuation: &[Punctuation]) -> Token {
+    let mut prev: Option<&str> = None;
+    let mut tokens = Vec::with_capacity(words.len());
+    for (word, punc) in words.iter().zip(punctuation) {
+        let mark = punc.mark(ends_cjk(&word.text));
+        // Tokens keep the space before them, like the tokens of a recognizer
+        let text = format!("{}{}{mark}", separator(prev, &word.text), word.text);
+        prev = Some(&word.text);
+        tokens.push(Token {
+            text,
+            ..word.clone()
+        });
+    }
+    let text = tokens.iter().map(|t| t.text.as_str()).collect::<String>();
+    let words = tokens
+        .iter()
+        .map(|t| Token {
+            text: t.text.trim_start().to_string(),
+            ..t.clone()
+        })
+        .collect::<Vec<_>>();
+
+    let sum = words.iter().map(|w| w.confidence.ln()).sum::<f32>();
+    let confidence = if words.is_empty() {
+        0.0
+    } else {
+        (sum / words.len() as f32).exp()
+    };
+
+    Token {
+        text,
+        start: words.first().map_or(0, |w| w.start),
+        end: words.last().map_or(0, |w| w.end),
+        tags: words.first().map(|w| w.tags.clone()).unwrap_or_default(),
+        confidence,
+        speaker: words.first().and_then(|w| w.speaker),
+        tokens,
+        words,
+        keywords: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sense_voice_small::Tags;
+
+    fn word(text: &str, speaker: Option<u32>) -> Token {
+        Token {
+            text: text.to_string(),
+            start: 0,
+            end: 0,
+            tags: Tags::default(),
+            confidence: 1.0,
+            tokens: Vec::new(),
+            words: Vec::new(),
+            keywords: Vec::new(),
+            speaker,
+        }
+    }
+
+    fn stream(lookahead: usize, texts: &[&str]) -> PunctuationStream {
+        let mut stream = PunctuationStream::new(lookahead);
+        stream.words = texts.iter().map(|text| word(text, None)).collect();
+        stream
+    }
+
+    fn texts(sentences: &[Token]) -> Vec<&str> {
+        sentences.iter().map(|s| s.text.as_str()).collect()
+    }
+
+    #[test]
+    fn sentences_wait_for_lookahead_words() {
+        use Punctuation::{Comma, None, Period, Question};
+        let words = ["so", "hi", "there", "how", "are", "you"];
+        let punctuation = vec![Comma, None, Period, None, None, Question];
+
+        // The question ends within the last two words, it may still move
+        let mut pending = stream(2, &words);
+        let sentences = pending.take_sentences(punctuation.clone());
+        assert_eq!(texts(&sentences), ["so, hi there."]);
+        assert_eq!(sentences[0].words.len(), 3);
+        assert_eq!(pending.words.len(), 3);
+
+        let mut pending = stream(4, &words);
+        assert!(pending.take_sentences(punctuation.clone()).is_empty());
+        assert_eq!(pending.words.len(), 6);
+
+        let mut pending = stream(0, &words);
+        let sentences = pending.take_sentences(punctuation);
+        assert_eq!(texts(&sentences), ["so, hi there.", "how are you?"]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn long_pending_words_end_at_the_last_comma() {
+        let count = MAX_PENDING_WORDS + 10;
+        let words = vec!["la"; count];
+        let mut punctuation = vec![Punctuation::None; count];
+        punctuation[50] = Punctuation::Comma;
+
+        let mut pending = stream(5, &words);
+        let sentences = pending.take_sentences(punctuation.clone());
+        assert_eq!(sentences.len(), 1);
+        assert_eq!(sentences[0].words.len(), 51);
+        assert!(sentences[0].text.ends_with("la."));
+        assert_eq!(pending.words.len(), count - 51);
+
+        // Without a comma, everything but the lookahead is taken
+        punctuation[50] = Punctuation::None;
+        let mut pending = stream(5, &words);
+        let sentences = pending.take_sentences(punctuation);
+        assert_eq!(sentences[0].words.len(), count - 5);
+
+        // Below the limit the words keep waiting
+        let mut pending = stream(5, &words[..MAX_PENDING_WORDS]);
+        let punctuation = vec![Punctuation::None; MAX_PENDING_WORDS];
+        assert!(pending.take_sentences(punctuation).is_empty());
+    }
+
+    #[test]
+    fn only_known_speakers_split_sentences() {
+        let words = [
+            word("a", None),
+            word("b", Some(0)),
+            word("c", Some(0)),
+            word("d", Some(1)),
+            word("e", None),
+        ];
+        let mut punctuation = vec![Punctuation::None; words.len()];
+        split_speakers(&words, &mut punctuation);
+        assert_eq!(
+            punctuation,
+            [
+                Punctuation::None,
+                Punctuation::None,
+                Punctuation::Period,
+                Punctuation::None,
+                Punctuation::None,
+            ]
+        );
+    }
+}
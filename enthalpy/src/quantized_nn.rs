@@ -71,14 +71,14 @@ pub fn linear_b(in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder) -> Re
     } else {
         None
     };
-    let ws = vb.get((in_dim, out_dim), "weight")?;
+    let ws = vb.get((out_dim, in_dim), "weight")?;
     let weight = QMatMul::from_arc(ws)?;
     Ok(Linear { weight, bias })
 }
 
 pub fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Res<Linear> {
     let bias = vb.get(out_dim, "bias")?.dequantize(vb.device())?;
-    let ws = vb.get((in_dim, out_dim), "weight")?;
+    let ws = vb.get((out_dim, in_dim), "weight")?;
     let weight = QMatMul::from_arc(ws)?;
     Ok(Linear {
         weight,
@@ -98,7 +98,7 @@ pub fn layer_norm_no_bias(size: usize, eps: f64, vb: VarBuilder) -> Res<candle_n
 }
 
 pub fn linear_no_bias(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Res<Linear> {
-    let ws = vb.get((in_dim, out_dim), "weight")?;
+    let ws = vb.get((out_dim, in_dim), "weight")?;
     let weight = QMatMul::from_arc(ws)?;
     Ok(Linear { weight, bias: None })
 }
@@ -130,3 +130,43 @@ impl Module for RmsNorm {
         candle_nn::ops::rms_norm(x, &self.weight, self.eps as f32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{GgmlDType, gguf_file};
+    use candle_core::{DType, Device};
+
+    #[test]
+    fn quantized_linear_matches_float() -> Res<()> {
+        let device = Device::Cpu;
+        let (in_dim, out_dim) = (64, 32);
+        let weight = Tensor::randn(0f32, 1., (out_dim, in_dim), &device)?;
+        let bias = Tensor::randn(0f32, 1., out_dim, &device)?;
+        let x = Tensor::randn(0f32, 1., (3, in_dim), &device)?;
+        let expected = candle_nn::Linear::new(weight.clone(), Some(bias.clone())).forward(&x)?;
+
+        for (dtype, tolerance) in [(GgmlDType::F32, 1e-4), (GgmlDType::Q8_0, 0.5)] {
+            let weight = QTensor::quantize(&weight, dtype)?;
+            let bias = QTensor::quantize(&bias, GgmlDType::F32)?;
+            let mut buffer = std::io::Cursor::new(Vec::new());
+            gguf_file::write(
+                &mut buffer,
+                &[],
+                &[("linear.weight", &weight), ("linear.bias", &bias)],
+            )?;
+            let vb = VarBuilder::from_gguf_buffer(buffer.get_ref(), &device)?;
+
+            let linear = linear(in_dim, out_dim, vb.pp("linear"))?;
+            let diff = (linear.forward(&x)? - &expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_dtype(DType::F32)?
+                .to_scalar::<f32>()?;
+            assert!(diff < tolerance, "{dtype:?} differs by {diff}");
+        }
+
+        Ok(())
+    }
+}
@@ -2,64 +2,106 @@
 //!
 //! VarBuilder is a utility to store quantized tensors from a [GGUF model file](https://huggingface.co/docs/hub/gguf).
 //! These tensors can be loaded from disk using `from_gguf` or from an in-memory
-//! buffer using `from_gguf_buffer`.
+//! buffer using `from_gguf_buffer`. Only the header is parsed up front, tensors
+//! are read when they are first requested.
 
 use crate::Res;
 use anyhow::bail;
 use candle_core::quantized::QTensor;
+use candle_core::quantized::gguf_file::{Content, TensorInfo, Value};
 use candle_core::{Device, Shape};
+use memmap2::Mmap;
+use std::collections::HashMap;
+use std::io::Cursor;
 use std::sync::Arc;
 
+/// Bytes of a GGUF file and where its tensor data starts
+struct GgufData {
+    bytes: Box<dyn AsRef<[u8]> + Send + Sync>,
+    tensor_data_offset: u64,
+}
+
 // VarBuilder specialized for QTensors
 #[derive(Clone)]
 pub struct VarBuilder {
-    data: Arc<std::collections::HashMap<String, Arc<QTensor>>>,
+    data: Arc<HashMap<String, Arc<TensorInfo>>>,
+    file: Arc<GgufData>,
+    metadata: Arc<HashMap<String, Value>>,
     path: Vec<String>,
     device: Device,
 }
 
 impl VarBuilder {
+    /// Map the file into memory, pages are read by the OS as tensors are loaded
     pub fn from_gguf<P: AsRef<std::path::Path>>(p: P, device: &Device) -> Res<Self> {
-        let mut file = std::fs::File::open(p)?;
-        let content = candle_core::quantized::gguf_file::Content::read(&mut file)?;
-        let mut data = std::collections::HashMap::new();
-        for tensor_name in content.tensor_infos.keys() {
-            let tensor = content.tensor(&mut file, tensor_name, device)?;
-            data.insert(tensor_name.to_string(), Arc::new(tensor));
-        }
-        Ok(Self {
-            data: Arc::new(data),
-            path: Vec::new(),
-            device: device.clone(),
-        })
+        let file = std::fs::File::open(p)?;
+        // SAFETY: the file is opened read only, like every mmap it must not be
+        // truncated while the model is loading
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::from_bytes(Box::new(mmap), device)
     }
 
     pub fn from_gguf_buffer(buffer: &[u8], device: &Device) -> Res<Self> {
-        let mut cursor = std::io::Cursor::new(buffer);
-        let content = candle_core::quantized::gguf_file::Content::read(&mut cursor)?;
-        let mut data = std::collections::HashMap::new();
-        for tensor_name in content.tensor_infos.keys() {
-            let tensor = content.tensor(&mut cursor, tensor_name, device)?;
-            data.insert(tensor_name.to_string(), Arc::new(tensor));
-        }
+        Self::from_bytes(Box::new(buffer.to_vec()), device)
+    }
+
+    fn from_bytes(bytes: Box<dyn AsRef<[u8]> + Send + Sync>, device: &Device) -> Res<Self> {
+        let content = Content::read(&mut Cursor::new((*bytes).as_ref()))?;
+        let data = content
+            .tensor_infos
+            .into_iter()
+            .map(|(name, info)| (name, Arc::new(info)))
+            .collect();
         Ok(Self {
             data: Arc::new(data),
+            file: Arc::new(GgufData {
+                bytes,
+                tensor_data_offset: content.tensor_data_offset,
+            }),
+            metadata: Arc::new(content.metadata),
             path: Vec::new(),
             device: device.clone(),
         })
     }
 
+    /// Read a tensor out of the file
+    fn load(&self, info: &TensorInfo) -> Res<Arc<QTensor>> {
+        let mut reader = Cursor::new((*self.file.bytes).as_ref());
+        let qtensor = info.read(&mut reader, self.file.tensor_data_offset, &self.device)?;
+        Ok(Arc::new(qtensor))
+    }
+
     pub fn pp<S: ToString>(&self, s: S) -> Self {
         let mut path = self.path.clone();
         path.push(s.to_string());
         Self {
             data: self.data.clone(),
+            file: self.file.clone(),
+            metadata: self.metadata.clone(),
             path,
             device: self.device.clone(),
         }
     }
 
-    fn path(&self, tensor_name: &str) -> String {
+    /// Rename every tensor, for files that name them differently from the model
+    pub fn map_names<F: Fn(&str) -> String>(self, f: F) -> Self {
+        let data = self
+            .data
+            .iter()
+            .map(|(name, info)| (f(name), info.clone()))
+            .collect();
+        Self {
+            data: Arc::new(data),
+            ..self
+        }
+    }
+
+    /// Key value metadata of the file
+    pub fn metadata(&self) -> &HashMap<String, Value> {
+        &self.metadata
+    }
+
+    pub fn path(&self, tensor_name: &str) -> String {
         if self.path.is_empty() {
             tensor_name.to_string()
         } else {
@@ -73,15 +115,15 @@ impl VarBuilder {
             None => {
                 bail!("cannot find tensor {path}")
             }
-            Some(qtensor) => {
+            Some(info) => {
                 let shape = s.into();
-                if qtensor.shape() != &shape {
+                if info.shape != shape {
                     bail!(
                         "shape mismatch for {name}, got {:?}, expected {shape:?}",
-                        qtensor.shape()
+                        info.shape
                     )
                 }
-                Ok(qtensor.clone())
+                self.load(info)
             }
         }
     }
@@ -97,7 +139,7 @@ impl VarBuilder {
             None => {
                 bail!("cannot find tensor {name}")
             }
-            Some(qtensor) => Ok(qtensor.clone()),
+            Some(info) => self.load(info),
         }
     }
 
@@ -0,0 +1,182 @@
+//! Speech recognition models behind one interface
+//!
+//! Every model implements [`SpeechRecognizer`] with its own config type.
+//! Applications pick the model by [`Engine`] name and keep its config as
+//! JSON, so a new model only has to be added here.
+
+use crate::Res;
+use crate::audio::silero_vad::Segment;
+use crate::config::ConfigRefresher;
+use crate::paraformer::Paraformer;
+use crate::sense_voice_small::{Hypothesis, SenseVoiceSmall, Token};
+use crate::util::modelscope::{FileInfo, ModelScopeRepo};
+use crate::whisper::Whisper;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A speech recognition model fed with live audio
+pub trait SpeechRecognizer: ConfigRefresher<Self::Config> + Send + Sized + 'static {
+    type Config: Clone + PartialEq + Serialize + DeserializeOwned + Default + Send + Sync;
+
+    /// Load the model, on a GPU if the config asks for one
+    fn load(config: Self::Config) -> impl Future<Output = Res<Self>> + Send;
+
+    /// Repository the model files are downloaded from
+    fn model_repo(config: &Self::Config) -> impl Future<Output = ModelScopeRepo> + Send;
+
+    /// Files `load` needs, with their download state
+    fn required_files(config: &Self::Config) -> impl Future<Output = Res<Vec<FileInfo>>> + Send;
+
+    /// Whether everything `load` needs is on disk
+    fn check_required_files(config: &Self::Config) -> impl Future<Output = bool> + Send;
+
+    /// Whether going from `old` to `new` needs the model loaded again,
+    /// other changes are applied by `refresh`
+    fn needs_reload(old: &Self::Config, new: &Self::Config) -> bool;
+
+    /// Push audio and take the speech segments the VAD has closed
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>>;
+
+    /// Transcribe closed segments
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>>;
+
+    /// Transcribe the speech the VAD is still collecting, for realtime captions
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>>;
+}
+
+/// Transcribe segments one at a time, token timing made absolute
+///
+/// # Arguments
+/// * `segments` - Speech segments from the VAD
+/// * `process` - Recognizes one segment, token timing relative to it
+pub(crate) fn transpose_each(
+    segments: &mut [Segment],
+    mut process: impl FnMut(&mut [f32]) -> Res<Hypothesis>,
+) -> Res<Vec<Token>> {
+    let mut out = Vec::with_capacity(segments.len());
+    for seg in segments.iter_mut() {
+        let hyp = process(&mut seg.data)?;
+        out.push(hyp.into_segment(seg.start, seg.end));
+    }
+
+    Ok(out)
+}
+
+/// Loaded [`SpeechRecognizer`] with its config as JSON
+pub trait Recognizer: Send {
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>>;
+
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>>;
+
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>>;
+
+    fn refresh(&mut self, old: &Value, new: &Value) -> Res<()>;
+}
+
+impl<R: SpeechRecognizer> Recognizer for R {
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        SpeechRecognizer::segment(self, waveform)
+    }
+
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        SpeechRecognizer::transpose(self, segments)
+    }
+
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        SpeechRecognizer::transpose_vad_cache(self)
+    }
+
+    fn refresh(&mut self, old: &Value, new: &Value) -> Res<()> {
+        ConfigRefresher::refresh(self, &parse_config::<R>(old)?, &parse_config::<R>(new)?)
+    }
+}
+
+/// Speech recognition models, selected by name in configs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Engine {
+    #[default]
+    SenseVoiceSmall,
+    Whisper,
+    Paraformer,
+}
+
+/// Run `$body` with `$r` as the model type of `$engine`
+macro_rules! with_recognizer {
+    ($engine:expr, $r:ident => $body:expr) => {
+        match $engine {
+            Engine::SenseVoiceSmall => {
+                type $r = SenseVoiceSmall;
+                $body
+            }
+            Engine::Whisper => {
+                type $r = Whisper;
+                $body
+            }
+            Engine::Paraformer => {
+                type $r = Paraformer;
+                $body
+            }
+        }
+    };
+}
+
+impl Engine {
+    /// Parse `config` and write it back, filling the missing fields with
+    /// defaults and dropping the ones the model does not know
+    pub fn normalize_config(&self, config: &Value) -> Res<Value> {
+        with_recognizer!(self, R => normalize_config::<R>(config))
+    }
+
+    pub async fn load(&self, config: &Value) -> Res<Box<dyn Recognizer>> {
+        with_recognizer!(self, R => load::<R>(config).await)
+    }
+
+    pub async fn model_repo(&self, config: &Value) -> Res<ModelScopeRepo> {
+        with_recognizer!(self, R => model_repo::<R>(config).await)
+    }
+
+    pub async fn required_files(&self, config: &Value) -> Res<Vec<FileInfo>> {
+        with_recognizer!(self, R => required_files::<R>(config).await)
+    }
+
+    pub async fn check_required_files(&self, config: &Value) -> Res<bool> {
+        with_recognizer!(self, R => check_required_files::<R>(config).await)
+    }
+
+    pub fn needs_reload(&self, old: &Value, new: &Value) -> Res<bool> {
+        with_recognizer!(self, R => needs_reload::<R>(old, new))
+    }
+}
+
+fn parse_config<R: SpeechRecognizer>(config: &Value) -> Res<R::Config> {
+    Ok(serde_json::from_value(config.clone())?)
+}
+
+fn normalize_config<R: SpeechRecognizer>(config: &Value) -> Res<Value> {
+    Ok(serde_json::to_value(parse_config::<R>(config)?)?)
+}
+
+async fn load<R: SpeechRecognizer>(config: &Value) -> Res<Box<dyn Recognizer>> {
+    Ok(Box::new(R::load(parse_config::<R>(config)?).await?))
+}
+
+async fn model_repo<R: SpeechRecognizer>(config: &Value) -> Res<ModelScopeRepo> {
+    Ok(R::model_repo(&parse_config::<R>(config)?).await)
+}
+
+async fn required_files<R: SpeechRecognizer>(config: &Value) -> Res<Vec<FileInfo>> {
+    R::required_files(&parse_config::<R>(config)?).await
+}
+
+async fn check_required_files<R: SpeechRecognizer>(config: &Value) -> Res<bool> {
+    Ok(R::check_required_files(&parse_config::<R>(config)?).await)
+}
+
+fn needs_reload<R: SpeechRecognizer>(old: &Value, new: &Value) -> Res<bool> {
+    Ok(R::needs_reload(
+        &parse_config::<R>(old)?,
+        &parse_config::<R>(new)?,
+    ))
+}
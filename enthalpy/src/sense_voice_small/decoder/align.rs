@@ -0,0 +1,379 @@
+use crate::Res;
+use crate::sense_voice_small::FRAME_MS;
+use crate::sense_voice_small::decoder::tokenizer::Tokenizer;
+use crate::util::is_cjk;
+use anyhow::bail;
+use std::fmt::Write;
+
+/// Words whose tokens are less likely than this are reported as not aligned
+const MIN_CONFIDENCE: f32 = 0.01;
+
+/// A reference word placed on the audio
+#[derive(Debug, Clone)]
+pub struct AlignedWord {
+    pub text: String,
+    pub start: u32,
+    pub end: u32,
+    /// Geometric mean of the token posteriors
+    pub confidence: f32,
+    /// Index of the transcript line the word is on
+    pub line: usize,
+    /// False if the word could not be tokenized, its timing is then taken
+    /// from its neighbours, or if it aligns poorly, it then keeps the timing
+    /// of the alignment
+    pub aligned: bool,
+}
+
+/// A transcript line placed on the audio
+#[derive(Debug, Clone)]
+pub struct AlignedLine {
+    pub text: String,
+    pub start: u32,
+    pub end: u32,
+    /// All words of the line are aligned
+    pub aligned: bool,
+}
+
+/// Timing of a known transcript, all times in milliseconds
+#[derive(Debug, Clone, Default)]
+pub struct Alignment {
+    pub lines: Vec<AlignedLine>,
+    pub words: Vec<AlignedWord>,
+}
+
+impl Alignment {
+    /// Lines of the transcript with unaligned words
+    pub fn failed(&self) -> impl Iterator<Item = &AlignedLine> {
+        self.lines.iter().filter(|l| !l.aligned)
+    }
+
+    /// LRC lyrics, one `[mm:ss.xx]` tag per line
+    pub fn to_lrc(&self) -> String {
+        let mut out = String::new();
+        for line in &self.lines {
+            let cs = line.start / 10;
+            let _ = writeln!(
+                out,
+                "[{:02}:{:02}.{:02}]{}",
+                cs / 6000,
+                cs / 100 % 60,
+                cs % 100,
+                line.text
+            );
+        }
+        out
+    }
+
+    /// SRT subtitles, one cue per line
+    pub fn to_srt(&self) -> String {
+        let mut out = String::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}\n{} --> {}\n{}\n",
+                i + 1,
+                srt_time(line.start),
+                srt_time(line.end),
+                line.text
+            );
+        }
+        out
+    }
+}
+
+fn srt_time(ms: u32) -> String {
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+/// A transcript word and the tokens it is aligned by
+struct Word {
+    text: String,
+    line: usize,
+    ids: Option<Vec<u32>>,
+}
+
+/// Align a transcript to the CTC posteriors of its audio
+///
+/// # Arguments
+/// * `log_probs` - Log posteriors (time, vocab) of the speech frames
+/// * `text` - Transcript, one subtitle line per text line
+/// * `tokenizer` - Model vocabulary
+/// * `offset` - Start of the audio in milliseconds
+pub fn align(
+    log_probs: &[Vec<f32>],
+    text: &str,
+    tokenizer: &Tokenizer,
+    offset: u32,
+) -> Res<Alignment> {
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>();
+
+    let words = lines
+        .iter()
+        .enumerate()
+        .flat_map(|(line, text)| split_words(text).into_iter().map(move |w| (line, w)))
+        .map(|(line, text)| {
+            let core = text.trim_matches(|c: char| !c.is_alphanumeric());
+            let ids = tokenizer.encode(core).filter(|ids| !ids.is_empty());
+            Word { text, line, ids }
+        })
+        .collect::<Vec<Word>>();
+
+    let labels = words
+        .iter()
+        .filter_map(|w| w.ids.as_deref())
+        .flatten()
+        .copied()
+        .collect::<Vec<u32>>();
+
+    let Some(spans) = ctc_viterbi(log_probs, &labels) else {
+        bail!(
+            "Audio of {} frames is too short for {} tokens",
+            log_probs.len(),
+            labels.len()
+        );
+    };
+
+    let tokenized = words.iter().map(|w| w.ids.is_some()).collect::<Vec<bool>>();
+    let mut spans = spans.into_iter();
+    let mut out = words
+        .into_iter()
+        .map(|word| {
+            let Some(ids) = word.ids else {
+                return AlignedWord {
+                    text: word.text,
+                    start: 0,
+                    end: 0,
+                    confidence: 0.0,
+                    line: word.line,
+                    aligned: false,
+                };
+            };
+
+            let spans = spans.by_ref().take(ids.len()).collect::<Vec<_>>();
+            let start = spans.first().map(|s| s.0).unwrap_or_default();
+            let end = spans.last().map(|s| s.1).unwrap_or_default();
+            let sum = spans.iter().map(|s| s.2).sum::<f32>();
+            let confidence = (sum / spans.len().max(1) as f32).exp();
+
+            AlignedWord {
+                text: word.text,
+                start: offset + start as u32 * FRAME_MS,
+                end: offset + end as u32 * FRAME_MS,
+                confidence,
+                line: word.line,
+                aligned: confidence >= MIN_CONFIDENCE,
+            }
+        })
+        .collect::<Vec<AlignedWord>>();
+
+    let end = offset + log_probs.len() as u32 * FRAME_MS;
+    fill_untokenized(&mut out, &tokenized, offset, end);
+
+    let lines = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, text)| {
+            let mut words = out.iter().filter(|w| w.line == i).peekable();
+            let start = words.peek()?.start;
+            let (end, aligned) = words.fold((start, true), |(_, ok), w| (w.end, ok && w.aligned));
+            Some(AlignedLine {
+                text: text.to_string(),
+                start,
+                end,
+                aligned,
+            })
+        })
+        .collect();
+
+    Ok(Alignment { lines, words: out })
+}
+
+/// Split a line into words, CJK characters count as words and punctuation
+/// stays with the word before it
+fn split_words(line: &str) -> Vec<String> {
+    let mut words: Vec<String> = Vec::new();
+    for chunk in line.split_whitespace() {
+        let mut start = true;
+        let mut after_cjk = false;
+        for c in chunk.chars() {
+            let cjk = is_cjk(c);
+            let new_word = start || cjk || (after_cjk && c.is_alphanumeric());
+            match words.last_mut() {
+                Some(word) if !new_word => word.push(c),
+                _ => words.push(c.to_string()),
+            }
+            start = false;
+            after_cjk = cjk || (after_cjk && !c.is_alphanumeric());
+        }
+    }
+    words
+}
+
+/// Give words that failed to tokenize the gap between their neighbours
+fn fill_untokenized(words: &mut [AlignedWord], tokenized: &[bool], start: u32, end: u32) {
+    let mut prev_end = start;
+    for i in 0..words.len() {
+        if tokenized[i] {
+            prev_end = words[i].end;
+            continue;
+        }
+        let next_start = (i + 1..words.len())
+            .find(|&j| tokenized[j])
+            .map(|j| words[j].start)
+            .unwrap_or(end);
+        words[i].start = prev_end;
+        words[i].end = next_start.max(prev_end);
+    }
+}
+
+/// CTC Viterbi alignment of a label sequence
+///
+/// Returns the first frame, the frame after the last and the best log
+/// posterior of every label, None if there are fewer frames than the labels
+/// need.
+fn ctc_viterbi(log_probs: &[Vec<f32>], labels: &[u32]) -> Option<Vec<(usize, usize, f32)>> {
+    const BLANK: u32 = 0;
+
+    if labels.is_empty() {
+        return Some(Vec::new());
+    }
+
+    // Blank interleaved labels: blank l1 blank l2 ... blank
+    let states = (0..labels.len() * 2 + 1)
+        .map(|s| if s % 2 == 0 { BLANK } else { labels[s / 2] })
+        .collect::<Vec<u32>>();
+    let repeats = labels.windows(2).filter(|w| w[0] == w[1]).count();
+    if log_probs.len() < labels.len() + repeats {
+        return None;
+    }
+
+    let n = states.len();
+    let mut score = vec![f32::NEG_INFINITY; n];
+    score[0] = log_probs[0][BLANK as usize];
+    score[1] = log_probs[0][states[1] as usize];
+
+    // Number of states stepped back to reach each state, per frame
+    let mut back = vec![vec![0u8; n]; log_probs.len()];
+    for (t, frame) in log_probs.iter().enumerate().skip(1) {
+        let prev = score.clone();
+        for s in 0..n {
+            let mut best = (prev[s], 0u8);
+            if s >= 1 && prev[s - 1] > best.0 {
+                best = (prev[s - 1], 1);
+            }
+            if s >= 2 && states[s] != BLANK && states[s] != states[s - 2] && prev[s - 2] > best.0 {
+                best = (prev[s - 2], 2);
+            }
+            score[s] = best.0 + frame[states[s] as usize];
+            back[t][s] = best.1;
+        }
+    }
+
+    let mut s = if score[n - 1] >= score[n - 2] {
+        n - 1
+    } else {
+        n - 2
+    };
+    if score[s] == f32::NEG_INFINITY {
+        return None;
+    }
+
+    let mut spans = vec![(usize::MAX, 0, f32::NEG_INFINITY); labels.len()];
+    for t in (0..log_probs.len()).rev() {
+        if s % 2 == 1 {
+            let span = &mut spans[s / 2];
+            span.0 = t;
+            span.1 = span.1.max(t + 1);
+            span.2 = span.2.max(log_probs[t][states[s] as usize]);
+        }
+        s -= back[t][s] as usize;
+    }
+
+    Some(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Log posteriors that peak at the given id of every frame
+    fn planted(path: &[u32], vocab: usize) -> Vec<Vec<f32>> {
+        path.iter()
+            .map(|&id| {
+                let row = (0..vocab)
+                    .map(|v| if v == id as usize { 5.0 } else { 0.0 })
+                    .collect::<Vec<f32>>();
+                let norm = row.iter().map(|x| x.exp()).sum::<f32>().ln();
+                row.iter().map(|x| x - norm).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn viterbi_finds_planted_spans() {
+        let log_probs = planted(&[0, 1, 1, 0, 2, 0, 2, 0], 3);
+        let peak = log_probs[1][1];
+
+        let spans = ctc_viterbi(&log_probs, &[1, 2, 2]).unwrap();
+        let frames = spans.iter().map(|s| (s.0, s.1)).collect::<Vec<_>>();
+        assert_eq!(frames, vec![(1, 3), (4, 5), (6, 7)]);
+        assert!(spans.iter().all(|s| (s.2 - peak).abs() < 1e-6));
+
+        // A repeated label needs a blank between its two frames
+        assert!(ctc_viterbi(&log_probs[..2], &[2, 2]).is_none());
+    }
+
+    #[test]
+    fn poorly_aligned_line_is_flagged() {
+        let tokenizer = Tokenizer::new(&serde_json::json!(["<blank>", "▁hi", "▁yo"]));
+        let log_probs = planted(&[1, 1, 0, 0, 0, 0], 3);
+
+        let alignment = align(&log_probs, "hi\nyo", &tokenizer, 1000).unwrap();
+        assert_eq!(alignment.lines[0].start, 1000);
+        assert!(alignment.lines[0].aligned);
+        let failed = alignment
+            .failed()
+            .map(|l| l.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, vec!["yo"]);
+    }
+
+    #[test]
+    fn lrc_and_srt_times() {
+        let line = |text: &str, start, end, aligned| AlignedLine {
+            text: text.to_string(),
+            start,
+            end,
+            aligned,
+        };
+        let alignment = Alignment {
+            lines: vec![
+                line("first", 5_120, 7_000, true),
+                line("second", 3_725_450, 3_727_000, false),
+            ],
+            words: Vec::new(),
+        };
+
+        assert_eq!(alignment.to_lrc(), "[00:05.12]first\n[62:05.45]second\n");
+        assert_eq!(
+            alignment.to_srt(),
+            "1\n00:00:05,120 --> 00:00:07,000\nfirst\n\n\
+             2\n01:02:05,450 --> 01:02:07,000\nsecond\n\n"
+        );
+        let failed = alignment
+            .failed()
+            .map(|l| l.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(failed, vec!["second"]);
+    }
+}
@@ -0,0 +1,312 @@
+use crate::sense_voice_small::decoder::DecodeConfig;
+use crate::sense_voice_small::decoder::hotwords::{HotwordState, HotwordTrie};
+use crate::sense_voice_small::decoder::lm_scorer::{LmScorer, LmState};
+use std::collections::HashMap;
+
+/// Id of the CTC blank token
+const BLANK: u32 = 0;
+
+/// A prefix in the beam, split by whether its last frame was blank
+#[derive(Clone)]
+struct Beam {
+    /// Log probability of paths ending in blank
+    blank: f32,
+    /// Log probability of paths ending in the last token
+    non_blank: f32,
+    /// Frame each token of the prefix was emitted at
+    frames: Vec<usize>,
+    /// Best single contribution seen for this prefix, decides `frames`
+    best: f32,
+    /// Hotword match progress of the prefix
+    hotword: HotwordState,
+    /// Language model history of the prefix
+    lm: LmState,
+    /// Accumulated hotword and language model bonus, only used for ranking
+    bias: f32,
+}
+
+impl Beam {
+    fn new() -> Self {
+        Self {
+            blank: f32::NEG_INFINITY,
+            non_blank: f32::NEG_INFINITY,
+            frames: Vec::new(),
+            best: f32::NEG_INFINITY,
+            hotword: HotwordState::default(),
+            lm: LmState::default(),
+            bias: 0.0,
+        }
+    }
+
+    /// Same prefix as `parent`
+    fn from_parent(parent: &Beam) -> Self {
+        Self {
+            hotword: parent.hotword,
+            lm: parent.lm.clone(),
+            bias: parent.bias,
+            ..Self::new()
+        }
+    }
+
+    /// `parent` extended by the token `id`
+    fn extend(parent: &Beam, id: u32, hotwords: &HotwordTrie, lm: Option<&LmScorer>) -> Self {
+        let (hotword, bonus) = hotwords.advance(parent.hotword, id);
+        let (lm, lm_score) = match lm {
+            Some(lm) => lm.advance(&parent.lm, id),
+            None => (parent.lm.clone(), 0.0),
+        };
+        Self {
+            hotword,
+            lm,
+            bias: parent.bias + bonus + lm_score,
+            ..Self::new()
+        }
+    }
+
+    fn score(&self) -> f32 {
+        log_add(self.blank, self.non_blank)
+    }
+
+    /// Score used to rank prefixes
+    fn rank(&self) -> f32 {
+        self.score() + self.bias
+    }
+
+    /// Keep the emission frames of the most likely path into this prefix
+    fn update_frames(&mut self, frames: &[usize], score: f32, emitted: Option<usize>) {
+        if score <= self.best {
+            return;
+        }
+        self.best = score;
+        self.frames.clear();
+        self.frames.extend_from_slice(frames);
+        if let Some(t) = emitted {
+            self.frames.push(t);
+        }
+    }
+}
+
+/// CTC prefix beam search over per-frame log probabilities
+///
+/// # Arguments
+/// * `log_probs` - Log posteriors (time, vocab)
+/// * `cfg` - Pruning thresholds
+/// * `beam_size` - Number of prefixes kept per frame
+/// * `hotwords` - Phrases whose tokens get a ranking bonus
+/// * `lm` - Language model fused into the ranking, if any
+///
+/// # Returns
+/// Final prefixes best first, at most `beam_size`, each with
+/// * Collapsed token ids with the frame they were emitted at
+/// * Log probability of the prefix, without hotword and language model bonus
+pub fn prefix_beam_search(
+    log_probs: &[Vec<f32>],
+    cfg: &DecodeConfig,
+    beam_size: usize,
+    hotwords: &HotwordTrie,
+    lm: Option<&LmScorer>,
+) -> Vec<(Vec<(u32, usize)>, f32)> {
+    let beam_size = beam_size.max(1);
+    let blank_skip = cfg.blank_skip_threshold.ln();
+    let token_min = cfg.token_threshold.ln();
+
+    let mut start = Beam::new();
+    start.blank = 0.0;
+    if let Some(lm) = lm {
+        start.lm = lm.begin();
+    }
+    let mut beams: Vec<(Vec<u32>, Beam)> = vec![(Vec::new(), start)];
+
+    for (t, frame) in log_probs.iter().enumerate() {
+        let blank = frame[BLANK as usize];
+
+        // Blank dominated frame, move all mass to the blank ending paths
+        if blank > blank_skip {
+            for (_, beam) in beams.iter_mut() {
+                beam.blank = beam.score() + blank;
+                beam.non_blank = f32::NEG_INFINITY;
+            }
+            continue;
+        }
+
+        let candidates = top_k(frame, beam_size, token_min);
+        let mut next: HashMap<Vec<u32>, Beam> = HashMap::with_capacity(beam_size * 2);
+
+        for (prefix, beam) in beams.iter() {
+            for &(id, p) in candidates.iter() {
+                if id == BLANK {
+                    let entry = next
+                        .entry(prefix.clone())
+                        .or_insert_with(|| Beam::from_parent(beam));
+                    let score = beam.score() + p;
+                    entry.blank = log_add(entry.blank, score);
+                    entry.update_frames(&beam.frames, score, None);
+                    continue;
+                }
+
+                let mut extended = prefix.clone();
+                extended.push(id);
+
+                if prefix.last() == Some(&id) {
+                    // Repeated token needs a blank in between to count twice
+                    let score = beam.blank + p;
+                    let entry = next
+                        .entry(extended)
+                        .or_insert_with(|| Beam::extend(beam, id, hotwords, lm));
+                    entry.non_blank = log_add(entry.non_blank, score);
+                    entry.update_frames(&beam.frames, score, Some(t));
+
+                    let score = beam.non_blank + p;
+                    let entry = next
+                        .entry(prefix.clone())
+                        .or_insert_with(|| Beam::from_parent(beam));
+                    entry.non_blank = log_add(entry.non_blank, score);
+                    entry.update_frames(&beam.frames, score, None);
+                } else {
+                    let score = beam.score() + p;
+                    let entry = next
+                        .entry(extended)
+                        .or_insert_with(|| Beam::extend(beam, id, hotwords, lm));
+                    entry.non_blank = log_add(entry.non_blank, score);
+                    entry.update_frames(&beam.frames, score, Some(t));
+                }
+            }
+        }
+
+        let mut next = next.into_iter().collect::<Vec<_>>();
+        next.sort_by(|a, b| b.1.rank().total_cmp(&a.1.rank()));
+        next.truncate(beam_size);
+
+        // Every candidate of this frame was pruned, keep the old beams
+        if !next.is_empty() {
+            beams = next;
+        }
+    }
+
+    // Complete the last word and close the sentence before the final pick
+    if let Some(lm) = lm {
+        for (_, beam) in beams.iter_mut() {
+            beam.bias += lm.finish(&beam.lm);
+        }
+        beams.sort_by(|a, b| b.1.rank().total_cmp(&a.1.rank()));
+    }
+
+    beams
+        .into_iter()
+        .map(|(prefix, beam)| {
+            let score = beam.score();
+            (prefix.into_iter().zip(beam.frames).collect(), score)
+        })
+        .collect()
+}
+
+/// Most likely `k` tokens of a frame with a log probability of at least `min`
+fn top_k(frame: &[f32], k: usize, min: f32) -> Vec<(u32, f32)> {
+    let mut candidates = frame
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| **p >= min)
+        .map(|(id, p)| (id as u32, *p))
+        .collect::<Vec<_>>();
+
+    if candidates.len() > k {
+        candidates.select_nth_unstable_by(k - 1, |a, b| b.1.total_cmp(&a.1));
+        candidates.truncate(k);
+    }
+
+    candidates
+}
+
+/// log(exp(a) + exp(b)) without overflow
+pub(crate) fn log_add(a: f32, b: f32) -> f32 {
+    if a == f32::NEG_INFINITY {
+        return b;
+    }
+    if b == f32::NEG_INFINITY {
+        return a;
+    }
+    let (max, min) = if a > b { (a, b) } else { (b, a) };
+    max + (min - max).exp().ln_1p()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sense_voice_small::decoder::hotwords::Hotword;
+    use crate::sense_voice_small::decoder::tokenizer::Tokenizer;
+
+    /// Log softmax of each row
+    fn log_softmax(logits: &[&[f32]]) -> Vec<Vec<f32>> {
+        logits
+            .iter()
+            .map(|row| {
+                let norm = row.iter().map(|x| x.exp()).sum::<f32>().ln();
+                row.iter().map(|x| x - norm).collect()
+            })
+            .collect()
+    }
+
+    /// Best token of every frame, repeats and blanks collapsed
+    fn greedy(log_probs: &[Vec<f32>]) -> Vec<(u32, usize)> {
+        let mut out = Vec::new();
+        let mut prev = BLANK;
+        for (t, frame) in log_probs.iter().enumerate() {
+            let id = frame
+                .iter()
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(b.1))
+                .map_or(BLANK, |(id, _)| id as u32);
+            if id != BLANK && id != prev {
+                out.push((id, t));
+            }
+            prev = id;
+        }
+        out
+    }
+
+    fn no_hotwords() -> HotwordTrie {
+        HotwordTrie::new(&[], &Tokenizer::new(&serde_json::json!([])))
+    }
+
+    #[test]
+    fn beam_of_one_is_greedy() {
+        let log_probs = log_softmax(&[
+            &[0.1, 2.0, 0.3, 0.0],
+            &[0.2, 1.9, 0.1, 0.4],
+            &[3.0, 0.2, 0.1, 0.0],
+            &[0.3, 2.2, 0.0, 0.1],
+            &[0.1, 0.2, 1.5, 1.4],
+            &[0.0, 0.2, 0.1, 2.5],
+            &[2.5, 0.1, 0.0, 0.3],
+            &[0.4, 0.0, 0.3, 1.8],
+        ]);
+        let cfg = DecodeConfig::default();
+
+        let beams = prefix_beam_search(&log_probs, &cfg, 1, &no_hotwords(), None);
+        assert_eq!(beams.len(), 1);
+        assert_eq!(beams[0].0, greedy(&log_probs));
+        assert_eq!(beams[0].0, vec![(1, 0), (1, 3), (2, 4), (3, 5), (3, 7)]);
+    }
+
+    #[test]
+    fn hotword_flips_a_near_tie() {
+        let tokenizer = Tokenizer::new(&serde_json::json!(["<blank>", "▁a", "▁b"]));
+        let log_probs = log_softmax(&[&[0.0, 2.0, 2.1], &[3.0, 0.0, 0.0]]);
+        let cfg = DecodeConfig::default();
+
+        let beams = prefix_beam_search(&log_probs, &cfg, 4, &no_hotwords(), None);
+        assert_eq!(beams[0].0, vec![(2, 0)]);
+
+        let hotwords = HotwordTrie::new(
+            &[Hotword {
+                phrase: "a".to_string(),
+                weight: 1.0,
+            }],
+            &tokenizer,
+        );
+        let beams = prefix_beam_search(&log_probs, &cfg, 4, &hotwords, None);
+        assert_eq!(beams[0].0, vec![(1, 0)]);
+        // The reported score stays the acoustic one
+        assert!(beams[0].1 < beams[1].1);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::var_builder::{Linear, VarBuilder};
 use crate::Res;
-use candle_core::{Result, Tensor};
+use candle_core::{DType, Result, Tensor};
 
 pub struct CTCLoss {
     ctc_lo: Option<Linear>,
@@ -37,12 +37,12 @@ impl CTCLoss {
     /// # Arguments
     /// * `hs_pad` - 3D tensor (B, Tmax, eprojs)
     /// # Returns
-    /// * 3D tensor with log softmax applied (B, Tmax, odim)
+    /// * 3D f32 tensor with log softmax applied (B, Tmax, odim)
     pub fn log_softmax(&self, hs_pad: &Tensor) -> Result<Tensor> {
         if let Some(ctc_lo) = &self.ctc_lo {
-            candle_nn::ops::log_softmax(&ctc_lo.forward(hs_pad)?, 2)
+            candle_nn::ops::log_softmax(&ctc_lo.forward(hs_pad)?.to_dtype(DType::F32)?, 2)
         } else {
-            candle_nn::ops::log_softmax(hs_pad, 2)
+            candle_nn::ops::log_softmax(&hs_pad.to_dtype(DType::F32)?, 2)
         }
     }
 }
@@ -0,0 +1,149 @@
+use crate::Res;
+use crate::sense_voice_small::decoder::tokenizer::Tokenizer;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use tracing::{Level, event};
+
+/// A phrase boosted during beam search
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hotword {
+    pub phrase: String,
+    /// Bonus added per matched token
+    pub weight: f32,
+}
+
+impl Hotword {
+    /// Load hotwords from a text file
+    ///
+    /// One phrase per line, optionally followed by ` :weight`. Empty lines and
+    /// lines starting with `#` are ignored.
+    ///
+    /// ```text
+    /// # product names
+    /// EasyCaption :3.0
+    /// enthalpy
+    /// ```
+    pub fn from_file<P: AsRef<Path>>(path: P, default_weight: f32) -> Res<Vec<Hotword>> {
+        let content = fs::read_to_string(path)?;
+        let mut out = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (phrase, weight) = match line.rsplit_once(" :") {
+                Some((phrase, weight)) => (phrase.trim(), weight.trim().parse::<f32>()?),
+                None => (line, default_weight),
+            };
+
+            out.push(Hotword {
+                phrase: phrase.to_string(),
+                weight,
+            });
+        }
+
+        Ok(out)
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<u32, usize>,
+    /// Bonus for reaching this node
+    weight: f32,
+    /// A phrase ends here
+    end: bool,
+}
+
+/// Matching progress of a hypothesis in the hotword trie
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HotwordState {
+    node: usize,
+    /// Bonus of the current partial match, revoked if the match fails
+    partial: f32,
+}
+
+/// Token prefix trie of the hotword phrases
+pub struct HotwordTrie {
+    nodes: Vec<Node>,
+}
+
+impl HotwordTrie {
+    pub fn new(hotwords: &[Hotword], tokenizer: &Tokenizer) -> Self {
+        let mut trie = Self {
+            nodes: vec![Node::default()],
+        };
+
+        for hotword in hotwords {
+            match tokenizer.encode(&hotword.phrase) {
+                Some(ids) if !ids.is_empty() => trie.insert(&ids, hotword.weight),
+                _ => event!(
+                    Level::WARN,
+                    "Hotword can not be tokenized: {}",
+                    hotword.phrase
+                ),
+            }
+        }
+
+        trie
+    }
+
+    fn insert(&mut self, ids: &[u32], weight: f32) {
+        let mut node = 0;
+        for &id in ids {
+            node = match self.nodes[node].children.get(&id) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(id, child);
+                    child
+                }
+            };
+            let child = &mut self.nodes[node];
+            child.weight = child.weight.max(weight);
+        }
+        self.nodes[node].end = true;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].children.is_empty()
+    }
+
+    /// Advance the match by one token
+    ///
+    /// Returns the new state and the bonus to add to the hypothesis score,
+    /// which is negative when a partial match is abandoned.
+    pub fn advance(&self, state: HotwordState, id: u32) -> (HotwordState, f32) {
+        if let Some(&child) = self.nodes[state.node].children.get(&id) {
+            return self.enter(child, state.partial, 0.0);
+        }
+
+        // Give back the partial bonus and try to start a new match
+        let revoke = -state.partial;
+        match self.nodes[0].children.get(&id) {
+            Some(&child) if state.node != 0 => self.enter(child, 0.0, revoke),
+            _ => (HotwordState::default(), revoke),
+        }
+    }
+
+    fn enter(&self, node: usize, partial: f32, bonus: f32) -> (HotwordState, f32) {
+        let n = &self.nodes[node];
+        let bonus = bonus + n.weight;
+
+        // A finished phrase keeps its bonus
+        if n.end {
+            let node = if n.children.is_empty() { 0 } else { node };
+            return (HotwordState { node, partial: 0.0 }, bonus);
+        }
+
+        let state = HotwordState {
+            node,
+            partial: partial + n.weight,
+        };
+        (state, bonus)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::sense_voice_small::decoder::tokenizer::Tokenizer;
+use crate::sense_voice_small::{FRAME_MS, QUERY_FRAMES};
+use serde::{Deserialize, Serialize};
+use tracing::{Level, event};
+
+/// Most frames allowed between two tokens of a keyword
+const MAX_GAP: usize = 10;
+
+/// A phrase to watch for
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keyword {
+    pub phrase: String,
+    /// Detection threshold, the configured default if unset
+    pub threshold: Option<f32>,
+}
+
+/// A keyword found in the audio
+#[derive(Clone, Debug, Serialize)]
+pub struct KeywordDetection {
+    pub keyword: String,
+    pub start: u32,
+    pub end: u32,
+    /// Geometric mean of the keyword token posteriors
+    pub score: f32,
+}
+
+struct Entry {
+    phrase: String,
+    ids: Vec<u32>,
+    /// Log of the detection threshold
+    threshold: f32,
+}
+
+/// Scores keywords against CTC posteriors without decoding the text
+pub struct KeywordSpotter {
+    entries: Vec<Entry>,
+}
+
+impl KeywordSpotter {
+    pub fn new(keywords: &[Keyword], threshold: f32, tokenizer: &Tokenizer) -> Self {
+        let entries = keywords
+            .iter()
+            .filter_map(|keyword| match tokenizer.encode(&keyword.phrase) {
+                Some(ids) if !ids.is_empty() => Some(Entry {
+                    phrase: keyword.phrase.clone(),
+                    ids,
+                    threshold: keyword.threshold.unwrap_or(threshold).ln(),
+                }),
+                _ => {
+                    event!(
+                        Level::WARN,
+                        "Keyword can not be tokenized: {}",
+                        keyword.phrase
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        Self { entries }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find keywords in the log posteriors (time, vocab) of one sequence
+    ///
+    /// Times are in milliseconds from the start of the speech, overlapping
+    /// detections of a keyword are reduced to the best one.
+    pub fn spot(&self, log_probs: &[Vec<f32>]) -> Vec<KeywordDetection> {
+        let speech = log_probs.get(QUERY_FRAMES..).unwrap_or_default();
+
+        let mut out = Vec::new();
+        for entry in &self.entries {
+            let mut found: Vec<(usize, usize, f32)> = Vec::new();
+            for (start, end, score) in best_paths(speech, &entry.ids) {
+                if score < entry.threshold {
+                    continue;
+                }
+                // Neighbouring end frames see the same utterance
+                if let Some(last) = found.last_mut()
+                    && start < last.1 + MAX_GAP
+                {
+                    if score > last.2 {
+                        *last = (start, end, score);
+                    }
+                    continue;
+                }
+                found.push((start, end, score));
+            }
+
+            out.extend(
+                found
+                    .into_iter()
+                    .map(|(start, end, score)| KeywordDetection {
+                        keyword: entry.phrase.clone(),
+                        start: start as u32 * FRAME_MS,
+                        end: end as u32 * FRAME_MS,
+                        score: score.exp(),
+                    }),
+            );
+        }
+
+        out.sort_by_key(|d| d.start);
+        out
+    }
+}
+
+/// Best way to emit `ids` in order ending at every frame
+///
+/// Each token is placed on one frame, at most [`MAX_GAP`] frames after the
+/// previous one. Returns the first frame, the frame after the last and the
+/// mean log posterior of the placed tokens, for every end frame that can be
+/// reached.
+fn best_paths(log_probs: &[Vec<f32>], ids: &[u32]) -> Vec<(usize, usize, f32)> {
+    let frames = log_probs.len();
+    if frames < ids.len() {
+        return Vec::new();
+    }
+
+    // Score and start frame of the best partial match ending at each frame
+    let mut prev = log_probs
+        .iter()
+        .enumerate()
+        .map(|(t, frame)| (frame[ids[0] as usize], t))
+        .collect::<Vec<(f32, usize)>>();
+
+    for &id in &ids[1..] {
+        let mut next = vec![(f32::NEG_INFINITY, 0); frames];
+        for t in 1..frames {
+            let from = t.saturating_sub(MAX_GAP);
+            let best = prev[from..t]
+                .iter()
+                .copied()
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .unwrap_or((f32::NEG_INFINITY, 0));
+            next[t] = (best.0 + log_probs[t][id as usize], best.1);
+        }
+        prev = next;
+    }
+
+    let len = ids.len() as f32;
+    prev.into_iter()
+        .enumerate()
+        .filter(|(_, (score, _))| score.is_finite())
+        .map(|(end, (score, start))| (start, end + 1, score / len))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_paths_finds_a_planted_keyword() {
+        let (frames, vocab) = (30, 6);
+        let background = (0.02f32).ln();
+        let mut log_probs = vec![vec![background; vocab]; frames];
+        for frame in log_probs.iter_mut() {
+            frame[0] = (0.9f32).ln();
+        }
+        // Keyword tokens 3 and 4 a few frames apart, token 3 alone elsewhere
+        log_probs[12][3] = (0.8f32).ln();
+        log_probs[15][4] = (0.7f32).ln();
+        log_probs[25][3] = (0.9f32).ln();
+
+        let paths = best_paths(&log_probs, &[3, 4]);
+        let (start, end, score) = paths
+            .iter()
+            .copied()
+            .max_by(|a, b| a.2.total_cmp(&b.2))
+            .unwrap();
+        assert_eq!((start, end), (12, 16));
+        assert!((score - ((0.8f32).ln() + (0.7f32).ln()) / 2.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::lm::NgramLm;
+use crate::sense_voice_small::decoder::DecodeConfig;
+use crate::sense_voice_small::decoder::tokenizer::WORD_START;
+use crate::util::is_cjk;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Units the language model was trained on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LmUnit {
+    /// Sentencepiece pieces of `tokens.json`, `▁` included
+    #[default]
+    Token,
+    /// Whitespace separated words, CJK characters count as single words
+    Word,
+}
+
+/// What a model token means to the language model
+enum Unit {
+    /// Blank and `<|...|>` tokens, invisible to the language model
+    Skip,
+    /// A piece scored on its own
+    Token(Option<u32>),
+    /// A piece that starts a new word
+    WordStart(String),
+    /// A piece continuing the current word
+    WordPiece(String),
+    /// A CJK character, a complete word
+    Char(Option<u32>),
+}
+
+/// Language model history of a hypothesis
+#[derive(Clone, Debug, Default)]
+pub struct LmState {
+    context: Vec<u32>,
+    /// Word being built from pieces, scored once it is complete
+    word: String,
+}
+
+/// Shallow fusion of an n-gram model into CTC beam search
+pub struct LmScorer {
+    lm: Arc<NgramLm>,
+    units: Vec<Unit>,
+    weight: f32,
+    word_bonus: f32,
+}
+
+impl LmScorer {
+    pub fn new(lm: Arc<NgramLm>, tokens: &Value, config: &DecodeConfig) -> Self {
+        let units = tokens
+            .as_array()
+            .map(|tokens| {
+                tokens
+                    .iter()
+                    .enumerate()
+                    .map(|(id, piece)| {
+                        let piece = piece.as_str().unwrap_or_default();
+                        Self::unit(&lm, id, piece, config.lm_unit)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            lm,
+            units,
+            weight: config.lm_weight,
+            word_bonus: config.word_bonus,
+        }
+    }
+
+    fn unit(lm: &NgramLm, id: usize, piece: &str, unit: LmUnit) -> Unit {
+        if id == 0 || piece.is_empty() || piece.starts_with("<|") {
+            return Unit::Skip;
+        }
+
+        if unit == LmUnit::Token {
+            return Unit::Token(lm.word_id(piece));
+        }
+
+        let mut chars = piece.chars();
+        if let (Some(c), None) = (chars.next(), chars.next())
+            && is_cjk(c)
+        {
+            return Unit::Char(lm.word_id(piece));
+        }
+
+        match piece.strip_prefix(WORD_START) {
+            Some(rest) => Unit::WordStart(rest.to_string()),
+            None => Unit::WordPiece(piece.to_string()),
+        }
+    }
+
+    pub fn begin(&self) -> LmState {
+        LmState {
+            context: vec![self.lm.bos()],
+            word: String::new(),
+        }
+    }
+
+    /// Advance the history by one model token, returns the weighted score
+    pub fn advance(&self, state: &LmState, id: u32) -> (LmState, f32) {
+        let Some(unit) = self.units.get(id as usize) else {
+            return (state.clone(), 0.0);
+        };
+
+        let mut state = state.clone();
+        let score = match unit {
+            Unit::Skip => 0.0,
+            Unit::Token(word) => self.push(&mut state, *word),
+            Unit::WordStart(piece) => {
+                let score = self.flush(&mut state);
+                state.word.push_str(piece);
+                score
+            }
+            Unit::WordPiece(piece) => {
+                state.word.push_str(piece);
+                0.0
+            }
+            Unit::Char(word) => self.flush(&mut state) + self.push(&mut state, *word),
+        };
+
+        (state, score)
+    }
+
+    /// Weighted score of ending the sentence here
+    pub fn finish(&self, state: &LmState) -> f32 {
+        let mut state = state.clone();
+        let score = self.flush(&mut state);
+        score + self.weight * self.lm.score(&state.context, Some(self.lm.eos()))
+    }
+
+    /// Score the pending word, if any
+    fn flush(&self, state: &mut LmState) -> f32 {
+        if state.word.is_empty() {
+            return 0.0;
+        }
+        let word = self.lm.word_id(&state.word);
+        state.word.clear();
+        self.push(state, word)
+    }
+
+    fn push(&self, state: &mut LmState, word: Option<u32>) -> f32 {
+        let score = self.lm.score(&state.context, word);
+        state.context.push(word.unwrap_or(u32::MAX));
+        self.lm.trim_context(&mut state.context);
+        self.weight * score + self.word_bonus
+    }
+}
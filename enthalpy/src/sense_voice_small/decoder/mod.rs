@@ -1,76 +1,661 @@
 use crate::Res;
+use crate::lm::NgramLm;
+use crate::sense_voice_small::{FRAME_MS, QUERY_FRAMES};
+use crate::util::is_cjk;
 use crate::var_builder::VarBuilder;
-use candle_core::Tensor;
+use candle_core::{D, Tensor};
 use ctc::CTCLoss;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::cmp::max;
-use std::fs::File;
-use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{Level, event};
 
+mod align;
+mod beam_search;
 mod ctc;
+mod hotwords;
+mod keywords;
+mod lm_scorer;
+mod tags;
+mod tokenizer;
 
-#[derive(Debug)]
+pub use align::{AlignedLine, AlignedWord, Alignment};
+use beam_search::log_add;
+pub use hotwords::Hotword;
+use hotwords::HotwordTrie;
+use keywords::KeywordSpotter;
+pub use keywords::{Keyword, KeywordDetection};
+use lm_scorer::LmScorer;
+pub use lm_scorer::LmUnit;
+pub use tags::{AudioEvent, Emotion, Tags};
+use tokenizer::{Tokenizer, WORD_START};
+
+#[derive(Clone, Debug)]
 pub struct Token {
     pub text: String,
     pub start: u32,
     pub end: u32,
+    pub tags: Tags,
+    /// Posterior probability, for a segment the geometric mean of its tokens
+    pub confidence: f32,
+    /// Text tokens of a segment, empty for a single token
+    pub tokens: Vec<Token>,
+    /// Tokens of a segment merged into words, empty for a single token
+    pub words: Vec<Token>,
+    /// Keywords spotted in a segment
+    pub keywords: Vec<KeywordDetection>,
+    /// Speaker of a segment found by diarization, None if unknown
+    pub speaker: Option<u32>,
+}
+
+impl Token {
+    /// Label a segment and its tokens and words with a speaker
+    pub fn set_speaker(&mut self, speaker: Option<u32>) {
+        self.speaker = speaker;
+        for token in self.tokens.iter_mut().chain(&mut self.words) {
+            token.speaker = speaker;
+        }
+    }
+}
+
+/// Decoded text tokens of one sequence
+#[derive(Debug)]
+pub struct Hypothesis {
+    pub tokens: Vec<Token>,
+    pub tags: Tags,
+    /// Log probability of the hypothesis
+    pub score: f32,
+    /// Keywords spotted in the sequence, independent of the decoded text
+    pub keywords: Vec<KeywordDetection>,
+    /// Tokens merged into words, filled in by [`Decoder::decode_nbest`]
+    pub words: Vec<Token>,
+}
+
+impl Hypothesis {
+    pub fn text(&self) -> String {
+        self.tokens.iter().map(|t| t.text.as_str()).collect()
+    }
+
+    /// Geometric mean of the token confidences, 0 without tokens
+    pub fn confidence(&self) -> f32 {
+        if self.tokens.is_empty() {
+            return 0.0;
+        }
+        let sum = self.tokens.iter().map(|t| t.confidence.ln()).sum::<f32>();
+        (sum / self.tokens.len() as f32).exp()
+    }
+
+    /// Shift token timing by `offset` milliseconds
+    pub fn shift(&mut self, offset: u32) {
+        for token in self.tokens.iter_mut() {
+            token.start += offset;
+            token.end += offset;
+        }
+        for keyword in self.keywords.iter_mut() {
+            keyword.start += offset;
+            keyword.end += offset;
+        }
+        for word in self.words.iter_mut() {
+            word.start += offset;
+            word.end += offset;
+        }
+    }
+
+    /// Segment level result, token timing is shifted by `start` to be absolute
+    pub fn into_segment(mut self, start: u32, end: u32) -> Token {
+        self.shift(start);
+        let text = self.text();
+        let confidence = self.confidence();
+        let words = merge_words(&self.tokens);
+
+        Token {
+            text,
+            start,
+            end,
+            tags: self.tags,
+            confidence,
+            tokens: self.tokens,
+            words,
+            keywords: self.keywords,
+            speaker: None,
+        }
+    }
+}
+
+/// Collapsed token ids with their frame and the log probability of a beam
+type Beam = (Vec<(u32, usize)>, f32);
+
+/// Merge beams with the same text tokens, in the order of their best beam
+///
+/// Beams that only differ in the tag tokens of the query frames read the same.
+/// A merged beam keeps the path of its best beam and the summed probability.
+fn merge_beams(beams: Vec<Beam>, is_tag: impl Fn(u32) -> bool) -> Vec<Beam> {
+    let mut merged: Vec<(Vec<u32>, Beam)> = Vec::new();
+    for (path, score) in beams {
+        let text = path
+            .iter()
+            .map(|&(id, _)| id)
+            .filter(|&id| !is_tag(id))
+            .collect::<Vec<_>>();
+        match merged.iter_mut().find(|(other, _)| *other == text) {
+            Some((_, (_, total))) => *total = log_add(*total, score),
+            None => merged.push((text, (path, score))),
+        }
+    }
+
+    merged.into_iter().map(|(_, beam)| beam).collect()
+}
+
+/// Best `n` of the merged beams by score
+fn nbest(beams: Vec<Beam>, is_tag: impl Fn(u32) -> bool, n: usize) -> Vec<Beam> {
+    let mut beams = merge_beams(beams, is_tag);
+    beams.sort_by(|a, b| b.1.total_cmp(&a.1));
+    beams.truncate(n);
+    beams
+}
+
+/// Merge sentencepiece tokens into words
+///
+/// A word starts at a token with a leading space or at a CJK character, which
+/// is a word of its own. Punctuation sticks to the word before it. Word text
+/// has no surrounding whitespace and the confidence is the geometric mean of
+/// its tokens.
+fn merge_words(tokens: &[Token]) -> Vec<Token> {
+    let mut groups: Vec<Vec<&Token>> = Vec::new();
+    let mut split = true;
+    for token in tokens {
+        let cjk = token.text.chars().next().is_some_and(is_cjk);
+        let punctuation = !token.text.chars().any(char::is_alphanumeric);
+        if groups.is_empty() || cjk || token.text.starts_with(' ') || (split && !punctuation) {
+            groups.push(Vec::new());
+        }
+        if let Some(group) = groups.last_mut() {
+            group.push(token);
+        }
+        split = cjk || (split && punctuation);
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|group| {
+            let (first, last) = (group.first()?, group.last()?);
+            let text = group.iter().map(|t| t.text.as_str()).collect::<String>();
+            let text = text.trim();
+            if text.is_empty() {
+                return None;
+            }
+            let sum = group.iter().map(|t| t.confidence.ln()).sum::<f32>();
+
+            Some(Token {
+                text: text.to_string(),
+                start: first.start,
+                end: last.end,
+                tags: Tags::default(),
+                confidence: (sum / group.len() as f32).exp(),
+                tokens: Vec::new(),
+                words: Vec::new(),
+                keywords: Vec::new(),
+                speaker: None,
+            })
+        })
+        .collect()
+}
+
+/// CTC search strategy
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DecodeMethod {
+    /// Best token of every frame
+    #[default]
+    Greedy,
+    /// Prefix beam search, slower but more accurate
+    BeamSearch,
+    /// Only spot the configured keywords, no text is decoded
+    KeywordSpotting,
+}
+
+/// Configuration for CTC decoding
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecodeConfig {
+    /// Search strategy
+    pub method: DecodeMethod,
+    /// Number of prefixes kept per frame by beam search
+    pub beam_size: usize,
+    /// Frames whose blank probability exceeds this are skipped by beam search
+    pub blank_skip_threshold: f32,
+    /// Tokens below this probability are not expanded by beam search
+    pub token_threshold: f32,
+    /// Phrases boosted by beam search
+    pub hotwords: Vec<Hotword>,
+    /// Text file with more hotwords, see [`Hotword::from_file`]
+    pub hotwords_file: Option<PathBuf>,
+    /// Weight of hotwords from the file that do not set their own
+    pub hotword_weight: f32,
+    /// N-gram language model fused into beam search, `.arpa` or `.bin`
+    pub lm_file: Option<PathBuf>,
+    /// Units the language model was trained on
+    pub lm_unit: LmUnit,
+    /// Scale of the language model log probability
+    pub lm_weight: f32,
+    /// Bonus per language model unit, balances the length penalty of the model
+    pub word_bonus: f32,
+    /// Phrases reported when spoken, with any decoding method
+    pub keywords: Vec<Keyword>,
+    /// Score a keyword needs to be reported, between 0 and 1
+    pub keyword_threshold: f32,
+}
+
+impl Default for DecodeConfig {
+    fn default() -> Self {
+        Self {
+            method: DecodeMethod::Greedy,
+            beam_size: 10,
+            blank_skip_threshold: 0.99,
+            token_threshold: 1e-4,
+            hotwords: Vec::new(),
+            hotwords_file: None,
+            hotword_weight: 1.5,
+            lm_file: None,
+            lm_unit: LmUnit::Token,
+            lm_weight: 0.3,
+            word_bonus: 0.5,
+            keywords: Vec::new(),
+            keyword_threshold: 0.5,
+        }
+    }
 }
 
 pub struct Decoder {
     ctc: CTCLoss,
     tokens: Value,
+    tokenizer: Tokenizer,
+    config: DecodeConfig,
+    hotwords: HotwordTrie,
+    lm: Option<Arc<NgramLm>>,
+    lm_scorer: Option<LmScorer>,
+    keywords: KeywordSpotter,
 }
 
 impl Decoder {
-    pub fn new(tokens_file: &dyn AsRef<Path>, vb: VarBuilder) -> Res<Self> {
-        let tokens_file = File::open(tokens_file)?;
-
-        let tokens: Value = serde_json::from_reader(tokens_file)?;
+    /// # Arguments
+    /// * `tokens` - Token list in the layout of `tokens.json`
+    pub fn new(tokens: Value, config: DecodeConfig, vb: VarBuilder) -> Res<Self> {
+        let tokenizer = Tokenizer::new(&tokens);
+        let hotwords = Self::init_hotwords(&config, &tokenizer)?;
+        let lm = Self::init_lm(&config)?;
+        let lm_scorer = Self::init_lm_scorer(&config, lm.clone(), &tokens);
+        let keywords = Self::init_keywords(&config, &tokenizer);
         let ctc = CTCLoss::new(25055, 512, true, vb.pp("ctc"))?;
-        Ok(Decoder { ctc, tokens })
+        Ok(Decoder {
+            ctc,
+            tokens,
+            tokenizer,
+            config,
+            hotwords,
+            lm,
+            lm_scorer,
+            keywords,
+        })
+    }
+
+    pub fn set_config(&mut self, config: DecodeConfig) -> Res<()> {
+        self.hotwords = Self::init_hotwords(&config, &self.tokenizer)?;
+        // Loading a language model is slow, keep it unless the file changed
+        if config.lm_file != self.config.lm_file {
+            self.lm = Self::init_lm(&config)?;
+        }
+        self.lm_scorer = Self::init_lm_scorer(&config, self.lm.clone(), &self.tokens);
+        self.keywords = Self::init_keywords(&config, &self.tokenizer);
+        self.config = config;
+        Ok(())
+    }
+
+    fn init_hotwords(config: &DecodeConfig, tokenizer: &Tokenizer) -> Res<HotwordTrie> {
+        let mut hotwords = config.hotwords.clone();
+        if let Some(file) = &config.hotwords_file {
+            hotwords.extend(Hotword::from_file(file, config.hotword_weight)?);
+        }
+
+        let trie = HotwordTrie::new(&hotwords, tokenizer);
+        if !trie.is_empty() && config.method != DecodeMethod::BeamSearch {
+            event!(Level::WARN, "Hotwords are only applied by beam search");
+        }
+
+        Ok(trie)
+    }
+
+    fn init_keywords(config: &DecodeConfig, tokenizer: &Tokenizer) -> KeywordSpotter {
+        let spotter = KeywordSpotter::new(&config.keywords, config.keyword_threshold, tokenizer);
+        if spotter.is_empty() && config.method == DecodeMethod::KeywordSpotting {
+            event!(Level::WARN, "Keyword spotting without keywords");
+        }
+        spotter
+    }
+
+    fn init_lm(config: &DecodeConfig) -> Res<Option<Arc<NgramLm>>> {
+        let Some(file) = &config.lm_file else {
+            return Ok(None);
+        };
+
+        let lm = NgramLm::from_file(file)?;
+        event!(
+            Level::INFO,
+            "Loaded {}-gram language model {}",
+            lm.order(),
+            file.display()
+        );
+        if config.method != DecodeMethod::BeamSearch {
+            event!(Level::WARN, "Language model is only applied by beam search");
+        }
+
+        Ok(Some(Arc::new(lm)))
+    }
+
+    fn init_lm_scorer(
+        config: &DecodeConfig,
+        lm: Option<Arc<NgramLm>>,
+        tokens: &Value,
+    ) -> Option<LmScorer> {
+        lm.map(|lm| LmScorer::new(lm, tokens, config))
+    }
+
+    /// CTC decoding of a single sequence
+    pub fn decode(&self, encoder_out: &Tensor) -> Res<Hypothesis> {
+        let (_, t, _) = encoder_out.dims3()?;
+        let mut out = self.decode_batch(encoder_out, &[t])?;
+        Ok(out.remove(0))
     }
 
-    pub fn decode(&self, encoder_out: &Tensor) -> Res<Vec<Token>> {
+    /// CTC decoding of a padded batch
+    ///
+    /// # Arguments
+    /// * `encoder_out` - Encoder output (batch, time, size)
+    /// * `lens` - Valid frames of each sequence, frames past it are ignored
+    pub fn decode_batch(&self, encoder_out: &Tensor, lens: &[usize]) -> Res<Vec<Hypothesis>> {
         let ctc_logits = self.ctc.log_softmax(encoder_out)?;
-        let ids = ctc_logits.argmax(2)?;
-        let ids = ids.flatten(0, 1)?.to_vec1::<u32>()?;
 
-        let mut results = Vec::<Token>::new();
+        let mut out = match self.config.method {
+            DecodeMethod::Greedy => self.greedy_search(&ctc_logits, lens)?,
+            DecodeMethod::BeamSearch => self.beam_search(&ctc_logits, lens)?,
+            DecodeMethod::KeywordSpotting => {
+                lens.iter().map(|_| self.hypothesis(&[], 0.0)).collect()
+            }
+        };
+
+        if !self.keywords.is_empty() {
+            for (i, (hyp, &len)) in out.iter_mut().zip(lens).enumerate() {
+                let log_probs = ctc_logits.get(i)?.narrow(0, 0, len)?.to_vec2::<f32>()?;
+                hyp.keywords = self.keywords.spot(&log_probs);
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn greedy_search(&self, ctc_logits: &Tensor, lens: &[usize]) -> Res<Vec<Hypothesis>> {
+        let ids = ctc_logits.argmax(D::Minus1)?.to_vec2::<u32>()?;
+        let best = ctc_logits.max(D::Minus1)?.to_vec2::<f32>()?;
+
+        let out = ids
+            .iter()
+            .zip(best)
+            .zip(lens)
+            .map(|((ids, best), &len)| {
+                let path = self
+                    .collapse(&ids[..len])
+                    .into_iter()
+                    .map(|(id, t)| (id, t, best[t]))
+                    .collect::<Vec<_>>();
+                let score = best[..len].iter().sum();
+                self.hypothesis(&path, score)
+            })
+            .collect();
+
+        Ok(out)
+    }
+
+    fn beam_search(&self, ctc_logits: &Tensor, lens: &[usize]) -> Res<Vec<Hypothesis>> {
+        let out = self
+            .beam_search_with(ctc_logits, lens, self.config.beam_size, |beams| {
+                merge_beams(beams, |id| self.is_special(id))
+                    .into_iter()
+                    .take(1)
+                    .collect()
+            })?
+            .into_iter()
+            .filter_map(|mut hyps| (!hyps.is_empty()).then(|| hyps.swap_remove(0)))
+            .collect();
+
+        Ok(out)
+    }
+
+    /// Top `n` hypotheses of each sequence of a padded batch, best first
+    ///
+    /// Always runs beam search, with a beam at least `n` wide. Beams that
+    /// only differ in their tags are merged, so the texts are distinct and
+    /// sorted by score, hotword and language model bonuses only decide which
+    /// beams survive the search.
+    ///
+    /// # Arguments
+    /// * `encoder_out` - Encoder output (batch, time, size)
+    /// * `lens` - Valid frames of each sequence
+    /// * `n` - Hypotheses per sequence
+    pub fn decode_nbest(
+        &self,
+        encoder_out: &Tensor,
+        lens: &[usize],
+        n: usize,
+    ) -> Res<Vec<Vec<Hypothesis>>> {
+        let ctc_logits = self.ctc.log_softmax(encoder_out)?;
+        let beam_size = self.config.beam_size.max(n);
+        let mut out = self.beam_search_with(&ctc_logits, lens, beam_size, |beams| {
+            nbest(beams, |id| self.is_special(id), n)
+        })?;
+        for hyp in out.iter_mut().flatten() {
+            hyp.words = merge_words(&hyp.tokens);
+        }
+
+        Ok(out)
+    }
+
+    /// Beam search each sequence, `select` picks the beams turned into hypotheses
+    fn beam_search_with(
+        &self,
+        ctc_logits: &Tensor,
+        lens: &[usize],
+        beam_size: usize,
+        select: impl Fn(Vec<Beam>) -> Vec<Beam>,
+    ) -> Res<Vec<Vec<Hypothesis>>> {
+        let mut out = Vec::with_capacity(lens.len());
+        for (i, &len) in lens.iter().enumerate() {
+            let log_probs = ctc_logits.get(i)?.narrow(0, 0, len)?.to_vec2::<f32>()?;
+            let beams = beam_search::prefix_beam_search(
+                &log_probs,
+                &self.config,
+                beam_size,
+                &self.hotwords,
+                self.lm_scorer.as_ref(),
+            );
+
+            let hyps = select(beams)
+                .into_iter()
+                .map(|(path, score)| {
+                    let path = path
+                        .into_iter()
+                        .map(|(id, t)| (id, t, log_probs[t][id as usize]))
+                        .collect::<Vec<_>>();
+                    self.hypothesis(&path, score)
+                })
+                .collect();
+            out.push(hyps);
+        }
+
+        Ok(out)
+    }
+
+    /// Align a known transcript to the audio of a single sequence
+    ///
+    /// # Arguments
+    /// * `encoder_out` - Encoder output (1, time, size), queries included
+    /// * `text` - Transcript, one subtitle line per text line
+    /// * `offset` - Start of the audio in milliseconds
+    pub fn align(&self, encoder_out: &Tensor, text: &str, offset: u32) -> Res<Alignment> {
+        let ctc_logits = self.ctc.log_softmax(encoder_out)?;
+        let (_, t, _) = ctc_logits.dims3()?;
+        let speech = t.saturating_sub(QUERY_FRAMES);
+        let log_probs = ctc_logits
+            .get(0)?
+            .narrow(0, t - speech, speech)?
+            .to_vec2::<f32>()?;
+
+        align::align(&log_probs, text, &self.tokenizer, offset)
+    }
 
-        let mut start = 0i32;
+    /// Collapse greedy frame ids into emitted ids with their frame
+    fn collapse(&self, ids: &[u32]) -> Vec<(u32, usize)> {
+        let mut path = Vec::new();
         let mut active = true;
-        for (index, id) in ids.into_iter().enumerate() {
-            let index = index as i32;
+        for (index, &id) in ids.iter().enumerate() {
+            if self.tokens.get(id as usize).is_none() {
+                continue;
+            }
+
+            // build in
+            if self.is_special(id) {
+                path.push((id, index));
+                continue;
+            }
+
+            if id == 0 {
+                active = true;
+                continue;
+            }
+
+            if !active {
+                continue;
+            }
+
+            path.push((id, index));
+            active = false;
+        }
+
+        path
+    }
+
+    fn is_special(&self, id: u32) -> bool {
+        self.tokens
+            .get(id as usize)
+            .and_then(|v| v.as_str())
+            .is_some_and(|v| v.starts_with("<|"))
+    }
+
+    /// Turn emitted ids into text tokens and tags
+    ///
+    /// `path` holds each emitted id with its frame and log posterior.
+    fn hypothesis(&self, path: &[(u32, usize, f32)], score: f32) -> Hypothesis {
+        let mut results = Vec::<Token>::new();
+        let mut tags = Tags::default();
+
+        // Speech frame of the previous token, the query frames hold no audio
+        let mut start = 0u32;
+        for &(id, index, log_prob) in path {
+            let frame = index.saturating_sub(QUERY_FRAMES) as u32;
             if let Some(v) = self.tokens.get(id as usize) {
-                let text = format!("{}", v.as_str().unwrap_or_default().replace("▁", " "));
+                let text = v.as_str().unwrap_or_default().replace(WORD_START, " ");
 
                 // build in
                 if text.starts_with("<|") {
+                    tags.push(&text);
                     continue;
                 }
 
-                if id == 0 {
-                    active = true;
-                    continue;
-                }
-
-                if !active {
-                    continue;
-                }
-
-                let open = max(start * 60 - 30, 0);
-                let close = max(index * 60 - 30, 0);
-                start = index;
+                // A token fires about half a frame after it starts
+                let open = (start * FRAME_MS).saturating_sub(FRAME_MS / 2);
+                let close = (frame * FRAME_MS).saturating_sub(FRAME_MS / 2);
+                start = frame;
 
                 results.push(Token {
                     text,
-                    start: open as u32,
-                    end: close as u32,
+                    start: open,
+                    end: close,
+                    tags: Tags::default(),
+                    confidence: log_prob.exp(),
+                    tokens: Vec::new(),
+                    words: Vec::new(),
+                    keywords: Vec::new(),
+                    speaker: None,
                 });
-                active = false;
             }
         }
 
-        Ok(results)
+        Hypothesis {
+            tokens: results,
+            tags,
+            score,
+            keywords: Vec::new(),
+            words: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Log softmax of each row
+    fn log_softmax(logits: &[&[f32]]) -> Vec<Vec<f32>> {
+        logits
+            .iter()
+            .map(|row| {
+                let norm = row.iter().map(|x| x.exp()).sum::<f32>().ln();
+                row.iter().map(|x| x - norm).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn nbest_texts_are_distinct_and_sorted() {
+        // <blank>, ▁a, ▁b and the tags <|zh|>, <|en|> on a query frame
+        let is_tag = |id: u32| id >= 3;
+        let log_probs = log_softmax(&[
+            &[0.0, 0.0, 0.0, 2.0, 1.9],
+            &[0.0, 2.0, 1.8, 0.0, 0.0],
+            &[3.0, 0.0, 0.0, 0.0, 0.0],
+        ]);
+        let hotwords = HotwordTrie::new(&[], &Tokenizer::new(&serde_json::json!([])));
+        let beams = beam_search::prefix_beam_search(
+            &log_probs,
+            &DecodeConfig::default(),
+            8,
+            &hotwords,
+            None,
+        );
+        let text = |path: &[(u32, usize)]| {
+            path.iter()
+                .map(|&(id, _)| id)
+                .filter(|&id| !is_tag(id))
+                .collect::<Vec<_>>()
+        };
+        // The language tag alone splits the best reading in two beams
+        assert_eq!(text(&beams[0].0), text(&beams[1].0));
+
+        let best = nbest(beams.clone(), is_tag, 3);
+        let texts = best.iter().map(|(path, _)| text(path)).collect::<Vec<_>>();
+        assert_eq!(texts.len(), 3);
+        for (i, a) in texts.iter().enumerate() {
+            assert!(texts[i + 1..].iter().all(|b| a != b), "{texts:?}");
+        }
+        assert!(best.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+        assert_eq!(texts[0], vec![1]);
+        let expected = beams
+            .iter()
+            .filter(|(path, _)| text(path) == texts[0])
+            .fold(f32::NEG_INFINITY, |total, (_, score)| {
+                log_add(total, *score)
+            });
+        assert!((best[0].1 - expected).abs() < 1e-5);
     }
 }
@@ -0,0 +1,128 @@
+use crate::sense_voice_small::Language;
+use serde::Serialize;
+
+/// Speaker emotion detected by SenseVoice
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Emotion {
+    Happy,
+    Sad,
+    Angry,
+    Neutral,
+    Fearful,
+    Disgusted,
+    Surprised,
+    Unknown,
+}
+
+/// Audio event detected by SenseVoice
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioEvent {
+    Speech,
+    Bgm,
+    Applause,
+    Laughter,
+    Cry,
+    Sneeze,
+    Breath,
+    Cough,
+    Unknown,
+}
+
+/// Language, emotion and event tags emitted as `<|...|>` tokens
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Tags {
+    /// Language code such as `zh`, or `de` from engines that know more
+    /// languages than SenseVoice
+    pub language: Option<String>,
+    pub emotion: Option<Emotion>,
+    pub events: Vec<AudioEvent>,
+}
+
+impl Tags {
+    /// Record a special token such as `<|zh|>`, `<|HAPPY|>` or `<|BGM|>`.
+    ///
+    /// Returns false if the token is not a language, emotion or event tag.
+    pub fn push(&mut self, token: &str) -> bool {
+        let Some(name) = token
+            .strip_prefix("<|")
+            .and_then(|name| name.strip_suffix("|>"))
+        else {
+            return false;
+        };
+
+        if Language::from_tag(name).is_some() {
+            self.set_language(name);
+            return true;
+        }
+
+        if let Some(emotion) = Self::emotion(name) {
+            self.emotion.get_or_insert(emotion);
+            return true;
+        }
+
+        if let Some(event) = Self::event(name) {
+            if !self.events.contains(&event) {
+                self.events.push(event);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    /// Record a language code, the first one recorded is kept
+    pub fn set_language(&mut self, code: &str) {
+        self.language.get_or_insert_with(|| code.to_string());
+    }
+
+    fn emotion(name: &str) -> Option<Emotion> {
+        let emotion = match name {
+            "HAPPY" => Emotion::Happy,
+            "SAD" => Emotion::Sad,
+            "ANGRY" => Emotion::Angry,
+            "NEUTRAL" => Emotion::Neutral,
+            "FEARFUL" => Emotion::Fearful,
+            "DISGUSTED" => Emotion::Disgusted,
+            "SURPRISED" => Emotion::Surprised,
+            "EMO_UNKNOWN" => Emotion::Unknown,
+            _ => return None,
+        };
+        Some(emotion)
+    }
+
+    fn event(name: &str) -> Option<AudioEvent> {
+        let event = match name {
+            "Speech" => AudioEvent::Speech,
+            "BGM" => AudioEvent::Bgm,
+            "Applause" => AudioEvent::Applause,
+            "Laughter" => AudioEvent::Laughter,
+            "Cry" => AudioEvent::Cry,
+            "Sneeze" => AudioEvent::Sneeze,
+            "Breath" => AudioEvent::Breath,
+            "Cough" => AudioEvent::Cough,
+            "Event_UNK" => AudioEvent::Unknown,
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_codes_beyond_sense_voice() {
+        let mut tags = Tags::default();
+        tags.set_language("de");
+        assert!(tags.push("<|en|>"));
+        assert_eq!(tags.language.as_deref(), Some("de"));
+
+        let mut tags = Tags::default();
+        assert!(tags.push("<|yue|>"));
+        assert!(!tags.push("<|xx|>"));
+        assert_eq!(tags.language.as_deref(), Some("yue"));
+    }
+}
@@ -0,0 +1,75 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Word boundary marker of sentencepiece pieces
+pub const WORD_START: char = '▁';
+
+/// Maps text to the model's sentencepiece units by longest match
+pub struct Tokenizer {
+    ids: HashMap<String, u32>,
+    /// Longest piece in chars
+    max_chars: usize,
+}
+
+impl Tokenizer {
+    pub fn new(tokens: &Value) -> Self {
+        let mut ids = HashMap::new();
+        let mut max_chars = 1;
+        if let Some(tokens) = tokens.as_array() {
+            for (id, piece) in tokens.iter().enumerate() {
+                if let Some(piece) = piece.as_str() {
+                    max_chars = max_chars.max(piece.chars().count());
+                    ids.entry(piece.to_string()).or_insert(id as u32);
+                }
+            }
+        }
+
+        Self { ids, max_chars }
+    }
+
+    /// Split text into token ids
+    ///
+    /// Returns None if some character is not covered by the vocabulary.
+    pub fn encode(&self, text: &str) -> Option<Vec<u32>> {
+        let mut out = Vec::new();
+        for word in text.split_whitespace() {
+            let word = format!("{WORD_START}{word}");
+            let chars = word.chars().collect::<Vec<char>>();
+
+            let mut start = 0;
+            while start < chars.len() {
+                let rest = &chars[start..];
+                if let Some((id, len)) = self
+                    .longest_match(rest)
+                    .or_else(|| self.longest_match_lowercase(rest))
+                {
+                    out.push(id);
+                    start += len;
+                } else if rest[0] == WORD_START {
+                    // No piece starts with this word, encode it without the boundary
+                    start += 1;
+                } else {
+                    return None;
+                }
+            }
+        }
+
+        Some(out)
+    }
+
+    fn longest_match(&self, chars: &[char]) -> Option<(u32, usize)> {
+        let max = self.max_chars.min(chars.len());
+        (1..=max).rev().find_map(|len| {
+            let piece = chars[..len].iter().collect::<String>();
+            self.ids.get(&piece).map(|&id| (id, len))
+        })
+    }
+
+    fn longest_match_lowercase(&self, chars: &[char]) -> Option<(u32, usize)> {
+        let lower = chars
+            .iter()
+            .map(|c| c.to_lowercase().next().unwrap_or(*c))
+            .collect::<Vec<char>>();
+        self.longest_match(&lower)
+    }
+}
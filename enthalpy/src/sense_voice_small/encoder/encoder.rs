@@ -1,11 +1,11 @@
 use crate::Res;
 
 use crate::sense_voice_small::encoder::{
-    EncoderLayerSANM, MultiHeadedAttentionSANM, PositionwiseFeedForward, SinusoidalPositionEncoder,
+    EncoderLayerSANM, FsmnDevice, MultiHeadedAttentionSANM, PositionwiseFeedForward, SinusoidalPositionEncoder,
 };
 use candle_core::Tensor;
-use candle_nn::{LayerNorm, Module};
-use crate::var_builder::VarBuilder;
+use candle_nn::Module;
+use crate::var_builder::{LayerNorm, VarBuilder};
 
 /// Configuration for SenseVoiceEncoderSmall
 #[derive(PartialEq)]
@@ -30,6 +30,8 @@ pub struct EncoderConfig {
     pub kernel_size: usize,
     /// SANM shift
     pub sanm_shfit: usize,
+    /// Where the FSMN block runs
+    pub fsmn_device: FsmnDevice,
     /// Whether to normalize before computation
     pub normalize_before: bool,
     /// Whether to concatenate after attention
@@ -49,6 +51,7 @@ impl Default for EncoderConfig {
             attention_dropout_rate: 0.0,
             kernel_size: 11,
             sanm_shfit: 0,
+            fsmn_device: FsmnDevice::default(),
             normalize_before: true,
             concat_after: false,
         }
@@ -66,8 +69,8 @@ pub struct Encoder {
     tp_encoders: Vec<EncoderLayerSANM>,
     /// Layer normalization after main encoders
     after_norm: LayerNorm,
-    /// Layer normalization after TP encoders
-    tp_norm: LayerNorm,
+    /// Layer normalization after TP encoders, None without TP blocks
+    tp_norm: Option<LayerNorm>,
     /// Output size
     output_size: usize,
 }
@@ -85,12 +88,9 @@ impl Encoder {
         let create_layer =
             |input_size: usize, output_size: usize, vb: VarBuilder| -> Res<EncoderLayerSANM> {
                 let self_attn = MultiHeadedAttentionSANM::new(
-                    cfg.attention_heads,
                     input_size,
                     output_size,
-                    cfg.attention_dropout_rate,
-                    cfg.kernel_size,
-                    cfg.sanm_shfit,
+                    &cfg,
                     vb.pp("self_attn"),
                 )?;
 
@@ -153,7 +153,11 @@ impl Encoder {
         // Create normalization layers
         
         let after_norm = vb.pp("after_norm").layer_norm(cfg.output_size, 1e-5)?;
-        let tp_norm = vb.pp("tp_norm").layer_norm(cfg.output_size, 1e-5)?;
+        let tp_norm = if cfg.tp_blocks > 0 {
+            Some(vb.pp("tp_norm").layer_norm(cfg.output_size, 1e-5)?)
+        } else {
+            None
+        };
 
         Ok(Self {
             embed,
@@ -170,12 +174,12 @@ impl Encoder {
     ///
     /// # Arguments
     /// * `xs_pad` - Padded audio sequences (batch, time, size)
-    /// * `ilens` - Input sequence lengths (batch,)
+    /// * `mask` - Optional padding mask (batch, time), 1.0 for valid frames.
+    ///   May be omitted when no sequence in the batch is padded.
     ///
     /// # Returns
     /// * Output tensor (batch, time, size)
-    /// * Output sequence lengths (batch,)
-    pub fn forward(&self, xs_pad: &Tensor) -> Res<Tensor> {
+    pub fn forward(&self, xs_pad: &Tensor, mask: Option<&Tensor>) -> Res<Tensor> {
         // Scale audio
         let xs_pad = (xs_pad * (self.output_size as f64).sqrt())?;
 
@@ -184,12 +188,12 @@ impl Encoder {
 
         // Forward through first encoder layers
         for encoder_layer in &self.encoders0 {
-            xs_pad = encoder_layer.forward(&xs_pad)?;
+            xs_pad = encoder_layer.forward(&xs_pad, mask)?;
         }
 
         // Forward through main encoder layers
         for encoder_layer in &self.encoders {
-            xs_pad = encoder_layer.forward(&xs_pad)?;
+            xs_pad = encoder_layer.forward(&xs_pad, mask)?;
         }
 
         // Apply normalization after main encoders
@@ -197,11 +201,13 @@ impl Encoder {
 
         // Forward through TP encoder layers
         for encoder_layer in &self.tp_encoders {
-            xs_pad = encoder_layer.forward(&xs_pad)?;
+            xs_pad = encoder_layer.forward(&xs_pad, mask)?;
         }
 
         // Apply normalization after TP encoders
-        xs_pad = self.tp_norm.forward(&xs_pad)?;
+        if let Some(tp_norm) = &self.tp_norm {
+            xs_pad = tp_norm.forward(&xs_pad)?;
+        }
 
         Ok(xs_pad)
     }
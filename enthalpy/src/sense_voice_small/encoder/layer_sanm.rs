@@ -1,8 +1,8 @@
 use crate::sense_voice_small::encoder::{MultiHeadedAttentionSANM, PositionwiseFeedForward};
-use crate::var_builder::{Linear, VarBuilder};
+use crate::var_builder::{LayerNorm, Linear, VarBuilder};
 use crate::Res;
 use candle_core::Tensor;
-use candle_nn::{Dropout, LayerNorm, Module};
+use candle_nn::{Dropout, Module};
 
 pub struct EncoderLayerSANM {
     /// Self attention module
@@ -78,13 +78,11 @@ impl EncoderLayerSANM {
     ///
     /// # Arguments
     /// * `x` - Input tensor (batch, time, size)
-    /// * `mask` - Mask tensor for the audio (batch, time)
-    /// * `cache` - Cache tensor of the audio (batch, time - 1, size)
+    /// * `mask` - Optional mask tensor for the audio (batch, time)
     ///
     /// # Returns
     /// * Output tensor (batch, time, size)
-    /// * Mask tensor (batch, time)
-    pub fn forward(&self, x: &Tensor) -> Res<Tensor> {
+    pub fn forward(&self, x: &Tensor, mask: Option<&Tensor>) -> Res<Tensor> {
         let stoch_layer_coeff = 1.0;
 
         let residual = x.clone();
@@ -94,7 +92,7 @@ impl EncoderLayerSANM {
         }
 
         if self.concat_after {
-            let attn = self.self_attn.forward(&x)?;
+            let attn = self.self_attn.forward(&x, mask)?;
             let x_concat = Tensor::cat(&[&x, &attn], 2)?;
 
             if let Some(concat_linear) = &self.concat_linear {
@@ -106,7 +104,7 @@ impl EncoderLayerSANM {
                 };
             }
         } else {
-            let attn = self.self_attn.forward(&x)?;
+            let attn = self.self_attn.forward(&x, mask)?;
             let dropout_attn = self.dropout.forward(&attn, false)?;
 
             if self.in_size == self.size {
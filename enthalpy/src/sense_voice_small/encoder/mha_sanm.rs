@@ -1,19 +1,58 @@
 use crate::Res;
+use crate::sense_voice_small::encoder::EncoderConfig;
 use crate::var_builder::{Linear, VarBuilder};
-use candle_core::{Device, Tensor};
+use candle_core::{DType, Device, Tensor};
 use candle_nn::{Conv1d, Conv1dConfig, Dropout, Module};
+use serde::{Deserialize, Serialize};
 
-pub struct MultiHeadedAttentionSANM {
-    /// The dimension of each head
-    d_k: usize,
-    /// The number of heads
-    h: usize,
-    /// Linear layer for output transformation
-    linear_out: Linear,
-    /// Combined linear layer for Q, K, V transformations
-    linear_q_k_v: Linear,
-    /// FSMN convolution block
-    fsmn_block: Conv1d,
+/// Where the FSMN memory block of the attention layers runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsmnDevice {
+    /// Same device as the rest of the encoder
+    #[default]
+    Encoder,
+    /// Grouped conv1d on the CPU, activations are copied there and back
+    Cpu,
+}
+
+/// Depthwise convolution of the FSMN memory block
+enum Fsmn {
+    /// Kernel taps (channels) on the encoder device, one per frame offset
+    Taps(Vec<Tensor>),
+    /// Grouped convolution on the CPU
+    Conv(Conv1d),
+}
+
+impl Fsmn {
+    /// Convolve padded frames (batch, time + kernel - 1, channels)
+    ///
+    /// The taps sum shifted frames weighted per channel, which needs only
+    /// element-wise ops and so runs on any device.
+    fn forward(&self, x: &Tensor, time: usize) -> Res<Tensor> {
+        match self {
+            Fsmn::Taps(taps) => {
+                let mut out = x.narrow(1, 0, time)?.broadcast_mul(&taps[0])?;
+                for (k, tap) in taps.iter().enumerate().skip(1) {
+                    out = (out + x.narrow(1, k, time)?.broadcast_mul(tap)?)?;
+                }
+                Ok(out)
+            }
+            Fsmn::Conv(conv) => {
+                let y = conv.forward(&x.transpose(1, 2)?.to_device(&Device::Cpu)?)?;
+                Ok(y.to_device(x.device())?.transpose(1, 2)?)
+            }
+        }
+    }
+}
+
+/// FSMN memory block, a depthwise convolution over time added to its input
+///
+/// This is the memory of the SANM attention layers, and on its own the self
+/// attention of the Paraformer decoder.
+pub struct FsmnBlock {
+    /// Depthwise convolution
+    fsmn: Fsmn,
     /// Padding values for FSMN
     left_padding: usize,
     /// Padding values for FSMN
@@ -22,37 +61,30 @@ pub struct MultiHeadedAttentionSANM {
     dropout: Dropout,
 }
 
-impl MultiHeadedAttentionSANM {
-    /// Creates a new MultiHeadedAttentionSANM instance
+impl FsmnBlock {
+    /// Creates a new FsmnBlock instance
     ///
     /// # Arguments
-    /// * `n_head` - The number of heads
-    /// * `in_feat` - The audio feature size
     /// * `n_feat` - The feature size
+    /// * `kernel_size` - Kernel size of the convolution
+    /// * `sanm_shfit` - Extra left padding, shifting the kernel to the past
+    /// * `fsmn_device` - Where the convolution runs
     /// * `dropout_rate` - Dropout rate
-    /// * `kernel_size` - Kernel size for FSMN
-    /// * `sanm_shfit` - Shift for SANM (default: 0)
-    /// * `vb` - VarBuilder for creating layers
+    /// * `vb` - VarBuilder of the `fsmn_block` convolution
     pub fn new(
-        n_head: usize,
-        in_feat: usize,
         n_feat: usize,
-        dropout_rate: f32,
         kernel_size: usize,
         sanm_shfit: usize,
+        fsmn_device: FsmnDevice,
+        dropout_rate: f32,
         vb: VarBuilder,
     ) -> Res<Self> {
-        assert_eq!(n_feat % n_head, 0, "n_feat must be divisible by n_head");
-
-        // We assume d_v always equals d_k
-        let d_k = n_feat / n_head;
-        let h = n_head;
-
-        let linear_out = vb.pp("linear_out").linear(n_feat, n_feat)?;
-        let linear_q_k_v = vb.pp("linear_q_k_v").linear(in_feat, n_feat * 3)?;
-
-        // FSMN block - Conv1d with groups=n_feat and no bias
-        let fsmn_block = vb.pp("fsmn_block").conv1d_no_bias_d(
+        // Conv1d with groups=n_feat and no bias
+        let device = match fsmn_device {
+            FsmnDevice::Encoder => vb.device().clone(),
+            FsmnDevice::Cpu => Device::Cpu,
+        };
+        let conv = vb.conv1d_no_bias_d(
             n_feat,
             n_feat,
             kernel_size,
@@ -62,41 +94,125 @@ impl MultiHeadedAttentionSANM {
                 padding: 0,
                 ..Conv1dConfig::default()
             },
-            
-            // Metal run slowly conv1d, so we use CPU
-            &Device::Cpu,
+            &device,
         )?;
+        let fsmn = match fsmn_device {
+            FsmnDevice::Encoder => {
+                // (channels, 1, kernel) -> kernel x (channels)
+                let weight = conv.weight().squeeze(1)?;
+                let taps = (0..kernel_size)
+                    .map(|k| weight.narrow(1, k, 1)?.squeeze(1)?.contiguous())
+                    .collect::<candle_core::Result<_>>()?;
+                Fsmn::Taps(taps)
+            }
+            FsmnDevice::Cpu => Fsmn::Conv(conv),
+        };
 
         // Calculate padding
         let left_padding = (kernel_size - 1) / 2 + sanm_shfit;
         let right_padding = kernel_size - 1 - left_padding;
 
-        let dropout = Dropout::new(dropout_rate);
-
         Ok(Self {
-            d_k,
-            h,
-            linear_out,
-            linear_q_k_v,
-            fsmn_block,
+            fsmn,
             left_padding,
             right_padding,
-            dropout,
+            dropout: Dropout::new(dropout_rate),
         })
     }
 
-    /// Forward pass for FSMN
-    fn forward_fsmn(&self, inputs: &Tensor) -> Res<Tensor> {
-        let x = inputs.transpose(1, 2)?;
-        let x = x.pad_with_zeros(2, self.left_padding, self.right_padding)?;
-        let x = self.fsmn_block.forward(&x.to_device(&Device::Cpu)?)?;
-        let x = x.to_device(inputs.device())?;
-        let x = x.transpose(1, 2)?;
-        let x = (x + inputs)?;
+    /// Forward pass
+    ///
+    /// Padded frames are zeroed before the convolution, so they look exactly
+    /// like the zero padding an unbatched sequence would see.
+    ///
+    /// # Arguments
+    /// * `inputs` - Input tensor (batch, time, size)
+    /// * `mask` - Optional padding mask (batch, time), 1.0 for valid frames
+    pub fn forward(&self, inputs: &Tensor, mask: Option<&Tensor>) -> Res<Tensor> {
+        let mask = match mask {
+            Some(mask) => Some(mask.unsqueeze(2)?.to_dtype(inputs.dtype())?),
+            None => None,
+        };
+        let inputs = match &mask {
+            Some(mask) => inputs.broadcast_mul(mask)?,
+            None => inputs.clone(),
+        };
+
+        let x = inputs.pad_with_zeros(1, self.left_padding, self.right_padding)?;
+        let x = self.fsmn.forward(&x, inputs.dim(1)?)?;
+        let x = (x + &inputs)?;
         let x = self.dropout.forward(&x, false)?;
 
+        let x = match &mask {
+            Some(mask) => x.broadcast_mul(mask)?,
+            None => x,
+        };
+
         Ok(x)
     }
+}
+
+pub struct MultiHeadedAttentionSANM {
+    /// The dimension of each head
+    d_k: usize,
+    /// The number of heads
+    h: usize,
+    /// Linear layer for output transformation
+    linear_out: Linear,
+    /// Combined linear layer for Q, K, V transformations
+    linear_q_k_v: Linear,
+    /// FSMN memory block
+    fsmn_block: FsmnBlock,
+    /// Dropout layer
+    dropout: Dropout,
+}
+
+impl MultiHeadedAttentionSANM {
+    /// Creates a new MultiHeadedAttentionSANM instance
+    ///
+    /// Heads, attention dropout rate, FSMN kernel size, SANM shift and FSMN
+    /// device are taken from the encoder configuration.
+    ///
+    /// # Arguments
+    /// * `in_feat` - The audio feature size
+    /// * `n_feat` - The feature size
+    /// * `cfg` - Configuration of the encoder
+    /// * `vb` - VarBuilder for creating layers
+    pub fn new(in_feat: usize, n_feat: usize, cfg: &EncoderConfig, vb: VarBuilder) -> Res<Self> {
+        let n_head = cfg.attention_heads;
+        let kernel_size = cfg.kernel_size;
+        let sanm_shfit = cfg.sanm_shfit;
+        let fsmn_device = cfg.fsmn_device;
+
+        assert_eq!(n_feat % n_head, 0, "n_feat must be divisible by n_head");
+
+        // We assume d_v always equals d_k
+        let d_k = n_feat / n_head;
+        let h = n_head;
+
+        let linear_out = vb.pp("linear_out").linear(n_feat, n_feat)?;
+        let linear_q_k_v = vb.pp("linear_q_k_v").linear(in_feat, n_feat * 3)?;
+
+        let fsmn_block = FsmnBlock::new(
+            n_feat,
+            kernel_size,
+            sanm_shfit,
+            fsmn_device,
+            cfg.attention_dropout_rate,
+            vb.pp("fsmn_block"),
+        )?;
+
+        let dropout = Dropout::new(cfg.attention_dropout_rate);
+
+        Ok(Self {
+            d_k,
+            h,
+            linear_out,
+            linear_q_k_v,
+            fsmn_block,
+            dropout,
+        })
+    }
 
     /// Transform query, key and value
     fn forward_qkv(&self, x: &Tensor) -> Res<(Tensor, Tensor, Tensor, Tensor)> {
@@ -116,8 +232,32 @@ impl MultiHeadedAttentionSANM {
     }
 
     /// Compute attention context vector
-    fn forward_attention(&self, value: &Tensor, scores: &Tensor) -> Res<Tensor> {
-        let attn = candle_nn::ops::softmax(&scores, 3)?;
+    ///
+    /// Keys outside of the mask get a zero attention weight. Softmax runs in
+    /// f32 so half precision scores do not overflow.
+    fn forward_attention(
+        &self,
+        value: &Tensor,
+        scores: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Res<Tensor> {
+        let scores = scores.to_dtype(DType::F32)?;
+        let attn = match mask {
+            Some(mask) => {
+                let (b, t) = mask.dims2()?;
+                let keep = mask
+                    .ne(0f32)?
+                    .reshape((b, 1, 1, t))?
+                    .broadcast_as(scores.shape())?;
+                let neg_inf = Tensor::new(f32::NEG_INFINITY, scores.device())?
+                    .to_dtype(scores.dtype())?
+                    .broadcast_as(scores.shape())?;
+                let scores = keep.where_cond(&scores, &neg_inf)?;
+                candle_nn::ops::softmax(&scores, 3)?
+            }
+            None => candle_nn::ops::softmax(&scores, 3)?,
+        };
+        let attn = attn.to_dtype(value.dtype())?;
         let p_attn = self.dropout.forward(&attn, false)?;
         let x = p_attn.matmul(&value.contiguous()?)?; // (batch, head, time1, d_k)
         let x = x.transpose(1, 2)?.flatten_from(2)?; // (batch, time1, d_model)
@@ -128,24 +268,73 @@ impl MultiHeadedAttentionSANM {
     }
 
     /// Forward pass
-    pub fn forward(&self, x: &Tensor) -> Res<Tensor> {
+    ///
+    /// # Arguments
+    /// * `x` - Input tensor (batch, time, size)
+    /// * `mask` - Optional padding mask (batch, time), 1.0 for valid frames
+    pub fn forward(&self, x: &Tensor, mask: Option<&Tensor>) -> Res<Tensor> {
         let (q_h, k_h, v_h, v) = self.forward_qkv(x)?;
 
-        let fsmn_memory = self.forward_fsmn(&v)?;
+        let fsmn_memory = self.fsmn_block.forward(&v, mask)?;
 
         // Scale query
-        let scale = (self.d_k as f32).powf(-0.5);
-        let scale_tensor = Tensor::new(scale, q_h.device())?;
-
-        let q_h = q_h.broadcast_mul(&scale_tensor)?;
+        let scale = (self.d_k as f64).powf(-0.5);
+        let q_h = (q_h * scale)?;
 
         let k_h = k_h.transpose(2, 3)?;
         let k_h = k_h.contiguous()?;
 
         let scores = q_h.matmul(&k_h)?;
 
-        let att_outs = self.forward_attention(&v_h, &scores)?;
+        let att_outs = self.forward_attention(&v_h, &scores, mask)?;
 
         Ok((att_outs + fsmn_memory)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+
+    #[test]
+    fn cpu_fallback_matches_encoder_taps() -> Res<()> {
+        let device = Device::Cpu;
+        let (channels, kernel_size, time) = (16, 11, 23);
+        let lens = [time, 15];
+        let mask = lens
+            .iter()
+            .flat_map(|&len| (0..time).map(move |t| if t < len { 1f32 } else { 0f32 }))
+            .collect::<Vec<_>>();
+        let mask = Tensor::from_vec(mask, (lens.len(), time), &device)?;
+        let x = Tensor::randn(0f32, 1., (lens.len(), time, channels), &device)?;
+
+        for sanm_shfit in [0, 3] {
+            let varmap = VarMap::new();
+            let block = |fsmn_device| {
+                let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &device);
+                FsmnBlock::new(
+                    channels,
+                    kernel_size,
+                    sanm_shfit,
+                    fsmn_device,
+                    0.0,
+                    VarBuilder::Normal(vb).pp("fsmn_block"),
+                )
+            };
+            let taps = block(FsmnDevice::Encoder)?;
+            let conv = block(FsmnDevice::Cpu)?;
+
+            for mask in [None, Some(&mask)] {
+                let diff = (taps.forward(&x, mask)? - conv.forward(&x, mask)?)?
+                    .abs()?
+                    .flatten_all()?
+                    .max(0)?
+                    .to_scalar::<f32>()?;
+                assert!(diff < 1e-5, "sanm_shfit {sanm_shfit} differs by {diff}");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -6,6 +6,6 @@ mod sinusoidal;
 
 pub use encoder::{Encoder, EncoderConfig};
 pub use layer_sanm::EncoderLayerSANM;
-pub use mha_sanm::MultiHeadedAttentionSANM;
+pub use mha_sanm::{FsmnBlock, FsmnDevice, MultiHeadedAttentionSANM};
 pub use positionwise_feed::PositionwiseFeedForward;
 pub use sinusoidal::SinusoidalPositionEncoder;
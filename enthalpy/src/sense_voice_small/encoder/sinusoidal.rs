@@ -58,9 +58,11 @@ impl SinusoidalPositionEncoder {
         let positions =
             Tensor::arange(1i64, (timesteps + 1) as i64, device)?.reshape((1, timesteps))?;
 
-        // Generate positional encoding
-        let position_encoding = self.encode(&positions, input_dim, dtype, device)?;
+        // Generate positional encoding in f32, half precision can not hold the positions
+        let position_encoding = self
+            .encode(&positions, input_dim, DType::F32, device)?
+            .to_dtype(dtype)?;
 
-        Ok((x + position_encoding)?)
+        Ok(x.broadcast_add(&position_encoding)?)
     }
 }
@@ -0,0 +1,277 @@
+use crate::Res;
+use crate::audio::WavFrontend;
+use crate::sense_voice_small::encoder::EncoderConfig;
+use crate::sense_voice_small::encoder_config;
+use crate::var_builder::VarBuilder;
+use candle_core::quantized::gguf_file::{self, Value};
+use candle_core::quantized::{GgmlDType, QTensor};
+use candle_core::{DType, Tensor};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use tracing::{Level, event};
+
+/// `general.architecture` of SenseVoiceSmall files, prefix of its metadata keys
+const ARCHITECTURE: &str = "sense_voice";
+
+/// Vocabulary, one piece per token id
+const TOKENS_KEY: &str = "tokenizer.ggml.tokens";
+
+/// Encoder layers, named without the `encoder.` prefix by some converters
+const ENCODER_PREFIXES: [&str; 5] = [
+    "encoders0.",
+    "encoders.",
+    "tp_encoders.",
+    "after_norm.",
+    "tp_norm.",
+];
+
+/// Model settings stored in the metadata of a GGUF file
+pub(crate) struct GgufMetadata {
+    pub encoder: EncoderConfig,
+    /// Token list in the layout of `tokens.json`
+    pub tokens: Option<serde_json::Value>,
+    /// CMVN means and scales in the layout of `am.mvn`
+    pub cmvn: Option<(Vec<f32>, Vec<f32>)>,
+}
+
+impl GgufMetadata {
+    /// Read files written by [`export_gguf`] or by SenseVoice.cpp
+    ///
+    /// Encoder hyperparameters are looked up under `sense_voice.encoder.*`
+    /// and `encoder.*`, missing ones keep the SenseVoiceSmall values.
+    pub fn new(metadata: &HashMap<String, Value>) -> Self {
+        let get = |name: &str| {
+            [
+                format!("{ARCHITECTURE}.encoder.{name}"),
+                format!("encoder.{name}"),
+            ]
+            .iter()
+            .find_map(|key| metadata.get(key))
+            .and_then(to_usize)
+        };
+
+        let mut encoder = encoder_config();
+        let fields = [
+            ("input_size", &mut encoder.input_size),
+            ("output_size", &mut encoder.output_size),
+            ("attention_heads", &mut encoder.attention_heads),
+            ("linear_units", &mut encoder.linear_units),
+            ("num_blocks", &mut encoder.num_blocks),
+            ("tp_blocks", &mut encoder.tp_blocks),
+            ("kernel_size", &mut encoder.kernel_size),
+            ("sanm_shift", &mut encoder.sanm_shfit),
+        ];
+        for (name, field) in fields {
+            if let Some(value) = get(name) {
+                *field = value;
+            }
+        }
+
+        let tokens = match metadata.get(TOKENS_KEY) {
+            Some(Value::Array(tokens)) => tokens
+                .iter()
+                .map(|token| token.to_string().ok().cloned())
+                .collect::<Option<Vec<String>>>()
+                .map(serde_json::Value::from),
+            _ => None,
+        };
+
+        let cmvn = f32_vec(metadata.get(&format!("{ARCHITECTURE}.cmvn.means")))
+            .zip(f32_vec(metadata.get(&format!("{ARCHITECTURE}.cmvn.vars"))));
+
+        Self {
+            encoder,
+            tokens,
+            cmvn,
+        }
+    }
+
+    /// Metadata of a GGUF file read from its header, None for other formats
+    pub fn read(path: &Path) -> Res<Option<Self>> {
+        if path.extension().is_none_or(|ext| ext != "gguf") {
+            return Ok(None);
+        }
+        let content = gguf_file::Content::read(&mut File::open(path)?)?;
+        Ok(Some(Self::new(&content.metadata)))
+    }
+}
+
+/// Rename the tensors of a GGUF checkpoint to the names of `model.pt`
+///
+/// Converters differ in whether they keep a `model.` prefix and the
+/// `encoder.` and `ctc.` module names, other checkpoints are returned as is.
+pub(crate) fn funasr_names(vb: VarBuilder) -> VarBuilder {
+    match vb {
+        VarBuilder::Quantiled(vb) => VarBuilder::Quantiled(vb.map_names(funasr_name)),
+        vb => vb,
+    }
+}
+
+fn funasr_name(name: &str) -> String {
+    let name = name.strip_prefix("model.").unwrap_or(name);
+    if ENCODER_PREFIXES.iter().any(|p| name.starts_with(p)) {
+        return format!("encoder.{name}");
+    }
+    if name.starts_with("ctc_lo.") {
+        return format!("ctc.{name}");
+    }
+    name.to_string()
+}
+
+/// Storage format of the linear layer weights in an exported file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GgufQuant {
+    F16,
+    Q8_0,
+    Q5K,
+    Q4K,
+}
+
+impl GgufQuant {
+    /// Parse `f16`, `q8_0`, `q5_k` or `q4_k`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let quant = match name.to_lowercase().as_str() {
+            "f16" => GgufQuant::F16,
+            "q8_0" => GgufQuant::Q8_0,
+            "q5_k" => GgufQuant::Q5K,
+            "q4_k" => GgufQuant::Q4K,
+            _ => return None,
+        };
+        Some(quant)
+    }
+
+    fn dtype(&self) -> GgmlDType {
+        match self {
+            GgufQuant::F16 => GgmlDType::F16,
+            GgufQuant::Q8_0 => GgmlDType::Q8_0,
+            GgufQuant::Q5K => GgmlDType::Q5K,
+            GgufQuant::Q4K => GgmlDType::Q4K,
+        }
+    }
+}
+
+/// Write a SenseVoiceSmall model directory as a single GGUF file
+///
+/// Linear layer weights are stored as `quant`, or the closest format their
+/// row length allows. Norms, embeddings, biases and FSMN kernels stay f32.
+/// The encoder hyperparameters, token list and CMVN statistics are stored
+/// as metadata, tensor names are kept as in `model.pt`.
+///
+/// # Arguments
+/// * `model_dir` - Directory with `model.pt`, `tokens.json` and `am.mvn`
+/// * `quant` - Format of the linear layer weights
+/// * `output` - Path of the GGUF file
+pub fn export_gguf(model_dir: &Path, quant: GgufQuant, output: &Path) -> Res<()> {
+    let tensors = candle_core::pickle::read_all(model_dir.join("model.pt"))?;
+    let tokens: Vec<String> = serde_json::from_reader(File::open(model_dir.join("tokens.json"))?)?;
+    let (means, vars) = WavFrontend::load_cmvn(&model_dir.join("am.mvn"))?;
+
+    let mut qtensors = Vec::with_capacity(tensors.len());
+    for (name, tensor) in tensors {
+        if !tensor.dtype().is_float() {
+            event!(Level::DEBUG, "Skipping non float tensor {}", name);
+            continue;
+        }
+        let dtype = tensor_dtype(&name, &tensor, quant);
+        let qtensor = QTensor::quantize(&tensor.to_dtype(DType::F32)?, dtype)?;
+        qtensors.push((name, qtensor));
+    }
+
+    let cfg = encoder_config();
+    let encoder = [
+        ("input_size", cfg.input_size),
+        ("output_size", cfg.output_size),
+        ("attention_heads", cfg.attention_heads),
+        ("linear_units", cfg.linear_units),
+        ("num_blocks", cfg.num_blocks),
+        ("tp_blocks", cfg.tp_blocks),
+        ("kernel_size", cfg.kernel_size),
+        ("sanm_shift", cfg.sanm_shfit),
+    ];
+
+    let mut metadata = vec![
+        (
+            "general.architecture".to_string(),
+            Value::String(ARCHITECTURE.to_string()),
+        ),
+        (
+            "general.name".to_string(),
+            Value::String("SenseVoiceSmall".to_string()),
+        ),
+    ];
+    metadata.extend(encoder.into_iter().map(|(key, value)| {
+        let key = format!("{ARCHITECTURE}.encoder.{key}");
+        (key, Value::U32(value as u32))
+    }));
+    metadata.extend([
+        (
+            format!("{ARCHITECTURE}.encoder.normalize_before"),
+            Value::Bool(cfg.normalize_before),
+        ),
+        (
+            format!("{ARCHITECTURE}.vocab_size"),
+            Value::U32(tokens.len() as u32),
+        ),
+        (format!("{ARCHITECTURE}.cmvn.means"), f32_array(&means)?),
+        (format!("{ARCHITECTURE}.cmvn.vars"), f32_array(&vars)?),
+        (
+            TOKENS_KEY.to_string(),
+            Value::Array(tokens.into_iter().map(Value::String).collect()),
+        ),
+    ]);
+
+    let metadata = metadata
+        .iter()
+        .map(|(key, value)| (key.as_str(), value))
+        .collect::<Vec<_>>();
+    let qtensors = qtensors
+        .iter()
+        .map(|(name, qtensor)| (name.as_str(), qtensor))
+        .collect::<Vec<_>>();
+
+    let mut file = BufWriter::new(File::create(output)?);
+    gguf_file::write(&mut file, &metadata, &qtensors)?;
+
+    Ok(())
+}
+
+/// Storage format of a tensor, only 2D linear weights are quantized
+///
+/// Quantized formats need rows that are a multiple of their block size, the
+/// 560 wide input of the first encoder layer only fits f16.
+fn tensor_dtype(name: &str, tensor: &Tensor, quant: GgufQuant) -> GgmlDType {
+    let linear = tensor.rank() == 2 && name.ends_with(".weight") && !name.starts_with("embed.");
+    if !linear {
+        return GgmlDType::F32;
+    }
+
+    let row = tensor.dims()[1];
+    [quant.dtype(), GgmlDType::Q8_0, GgmlDType::F16]
+        .into_iter()
+        .find(|dtype| row.is_multiple_of(dtype.block_size()))
+        .unwrap_or(GgmlDType::F32)
+}
+
+fn f32_array(tensor: &Tensor) -> Res<Value> {
+    let values = tensor.to_vec1::<f32>()?;
+    Ok(Value::Array(values.into_iter().map(Value::F32).collect()))
+}
+
+fn to_usize(value: &Value) -> Option<usize> {
+    match value {
+        Value::U32(v) => Some(*v as usize),
+        Value::I32(v) => usize::try_from(*v).ok(),
+        Value::U64(v) => Some(*v as usize),
+        Value::I64(v) => usize::try_from(*v).ok(),
+        _ => None,
+    }
+}
+
+fn f32_vec(value: Option<&Value>) -> Option<Vec<f32>> {
+    match value? {
+        Value::Array(values) => values.iter().map(|v| v.to_f32().ok()).collect(),
+        _ => None,
+    }
+}
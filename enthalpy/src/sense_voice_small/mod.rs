@@ -1,57 +1,207 @@
 use crate::Res;
-use crate::audio::resample::Resampler;
-use crate::audio::silero_vad::{Segment, VadConfig, VadProcessor};
+use crate::audio::segmenter::Segmenter;
+use crate::audio::silero_vad::{Segment, VadConfig};
 use crate::audio::{WavFrontend, WavFrontendConfig};
 use crate::config::ConfigRefresher;
+use crate::recognizer::{SpeechRecognizer, transpose_each};
 use crate::util::modelscope::{FileInfo, ModelScopeRepo, RepoFile};
+use crate::util::select_device;
 use crate::var_builder::VarBuilder;
 use anyhow::Error;
-use candle_core::{Device, Module, Tensor};
+use candle_core::{DType, Device, Module, Tensor};
 use candle_nn::Embedding;
 use decoder::Decoder;
 use encoder::{Encoder, EncoderConfig};
+use gguf::GgufMetadata;
 use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::Instant;
 use tracing::{Level, event};
 
 mod decoder;
-mod encoder;
+pub(crate) mod encoder;
+mod gguf;
 
-pub use decoder::Token;
+pub use decoder::{
+    AlignedLine, AlignedWord, Alignment, AudioEvent, DecodeConfig, DecodeMethod, Emotion, Hotword,
+    Hypothesis, Keyword, KeywordDetection, Tags, Token,
+};
+pub use encoder::FsmnDevice;
+pub use gguf::{GgufQuant, export_gguf};
+
+/// Checkpoint files, listed in [`SenseVoiceSmall::model_repo`] for offline use
+const MODEL_FILES: [&str; 3] = ["am.mvn", "model.pt", "tokens.json"];
 
 const EMBEDDING_DIM: usize = 560;
 
+/// Frames of the language, event, emotion and text norm queries before the speech
+const QUERY_FRAMES: usize = 4;
+
+/// Duration of one encoder frame in milliseconds
+const FRAME_MS: u32 = 60;
+
 #[derive(Clone, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct SenseVoiceSmallConfig {
     pub model_dir: PathBuf,
     pub vad: VadConfig,
     pub resample: Option<(u32, u32)>,
     pub use_gpu: bool,
+    pub language: Language,
+    pub text_norm: TextNorm,
+    /// Number of segments encoded together by `transpose`, 0 or 1 disables batching
+    pub batch_size: usize,
+    pub decode: DecodeConfig,
+    pub chunk: ChunkConfig,
+    /// Precision the weights are loaded in, changing it reloads the model
+    pub dtype: ModelDtype,
+    /// Checkpoint loaded instead of the downloaded `model.pt`: a `.pt`,
+    /// `.safetensors`, `.safetensors.index.json` or `.gguf` file
+    ///
+    /// GGUF files may carry the tokens and CMVN statistics, `tokens.json` and
+    /// `am.mvn` are then only used if they are in the model directory.
+    pub weight_file: Option<PathBuf>,
+    /// Where the FSMN memory blocks run, the CPU fallback copies the
+    /// activations of every attention layer to the CPU and back
+    pub fsmn_device: FsmnDevice,
+}
+
+/// Floating point precision of the model weights
+///
+/// Half precision halves the memory use and is faster on GPUs, layer norms
+/// and softmax still run in f32. bf16 needs a GPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelDtype {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl ModelDtype {
+    pub fn dtype(&self) -> DType {
+        match self {
+            ModelDtype::F32 => DType::F32,
+            ModelDtype::F16 => DType::F16,
+            ModelDtype::Bf16 => DType::BF16,
+        }
+    }
+}
+
+/// Long segments are encoded in overlapping windows to bound memory and latency
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkConfig {
+    /// Longest speech encoded at once in milliseconds, 0 encodes segments whole
+    pub chunk_ms: u32,
+    /// Audio shared by neighbouring windows in milliseconds, at most half a chunk
+    pub overlap_ms: u32,
+}
+
+impl Default for ChunkConfig {
+    fn default() -> Self {
+        Self {
+            chunk_ms: 30_000,
+            overlap_ms: 2_000,
+        }
+    }
+}
+
+impl ChunkConfig {
+    /// Chunk and overlap length in encoder frames
+    fn frames(&self) -> (usize, usize) {
+        let chunk = (self.chunk_ms / FRAME_MS) as usize;
+        let overlap = ((self.overlap_ms / FRAME_MS) as usize).min(chunk / 2);
+        (chunk, overlap)
+    }
+}
+
+/// Recognition language, sent to the model as the language query
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    Auto,
+    Zh,
+    En,
+    Yue,
+    Ja,
+    Ko,
+    NoSpeech,
+}
+
+impl Language {
+    /// Parse the language name of a `<|zh|>` style tag
+    fn from_tag(name: &str) -> Option<Self> {
+        let language = match name {
+            "zh" => Language::Zh,
+            "en" => Language::En,
+            "yue" => Language::Yue,
+            "ja" => Language::Ja,
+            "ko" => Language::Ko,
+            "nospeech" => Language::NoSpeech,
+            _ => return None,
+        };
+        Some(language)
+    }
+
+    /// Index in SenseVoice's LID dictionary
+    fn query_id(&self) -> u32 {
+        match self {
+            Language::Auto => 0,
+            Language::Zh => 3,
+            Language::En => 4,
+            Language::Yue => 7,
+            Language::Ja => 11,
+            Language::Ko => 12,
+            Language::NoSpeech => 13,
+        }
+    }
+}
+
+/// Inverse text normalization, sent to the model as the text norm query
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TextNorm {
+    /// Punctuated output with numbers written as digits
+    WithItn,
+    /// Raw spoken-form output
+    #[default]
+    WoItn,
+}
+
+impl TextNorm {
+    /// Index in SenseVoice's text norm dictionary
+    fn query_id(&self) -> u32 {
+        match self {
+            TextNorm::WithItn => 14,
+            TextNorm::WoItn => 15,
+        }
+    }
 }
 
 pub struct SenseVoiceSmall {
     device: Device,
-    resampler: Option<Resampler>,
-    vad: VadProcessor,
+    /// Compute dtype of the encoder input
+    dtype: DType,
+    segmenter: Segmenter,
     embed: Embedding,
     frontend: WavFrontend,
     encoder: Encoder,
     decoder: Decoder,
+    language: Language,
+    text_norm: TextNorm,
+    batch_size: usize,
+    chunk: ChunkConfig,
 }
 
 impl SenseVoiceSmall {
     pub async fn with_config(cfg: SenseVoiceSmallConfig) -> Res<Self> {
-        if cfg.use_gpu {
-            if candle_core::utils::cuda_is_available() {
-                let device = Device::new_cuda(0)?;
-                return Self::new(cfg, &device).await;
-            }
-            if candle_core::utils::metal_is_available() {
-                let device = Device::new_metal(0)?;
-                return Self::new(cfg, &device).await;
-            }
-        }
-        Self::new(cfg, &Device::Cpu).await
+        let device = select_device(cfg.use_gpu)?;
+        Self::new(cfg, &device).await
     }
 
     pub async fn new(cfg: SenseVoiceSmallConfig, device: &Device) -> Res<Self> {
@@ -59,46 +209,94 @@ impl SenseVoiceSmall {
 
         let repo = Self::model_repo(cfg.model_dir).await;
 
-        let cmvn_file = repo.get("am.mvn").await?;
-        let weight_file = repo.get("model.pt").await?;
-        let tokens_file = repo.get("tokens.json").await?;
+        let weight_file = match &cfg.weight_file {
+            Some(file) => file.clone(),
+            None => repo.get("model.pt").await?,
+        };
+
+        let start = Instant::now();
+        let dtype = match cfg.dtype {
+            ModelDtype::Bf16 if device.is_cpu() => {
+                event!(Level::WARN, "bf16 is not supported on CPU, loading in f32");
+                DType::F32
+            }
+            dtype => dtype.dtype(),
+        };
+        let vb = VarBuilder::from_file(&weight_file, dtype, &device)?;
+        let dtype = vb.dtype();
+        let gguf = vb.gguf_metadata().map(GgufMetadata::new);
+        let vb = gguf::funasr_names(vb);
+
+        let (mut encoder_cfg, tokens, cmvn) = match gguf {
+            Some(gguf) => (gguf.encoder, gguf.tokens, gguf.cmvn),
+            None => (encoder_config(), None, None),
+        };
+        encoder_cfg.fsmn_device = cfg.fsmn_device;
+
+        let tokens = match tokens {
+            Some(tokens) if !repo.has_files(&["tokens.json"]) => tokens,
+            _ => serde_json::from_reader(File::open(repo.get("tokens.json").await?)?)?,
+        };
+
+        let frontend = match cmvn {
+            Some((means, vars)) if !repo.has_files(&["am.mvn"]) => {
+                WavFrontend::with_cmvn(WavFrontendConfig::default(), means, vars)?
+            }
+            _ => Self::init_frontend(&repo.get("am.mvn").await?)?,
+        };
 
-        let embed = Self::init_embedding()?;
-        let frontend = Self::init_frontend(&cmvn_file)?;
-        let (encoder, decoder) = Self::init_encoder_decoder(weight_file, tokens_file, &device)?;
-        let vad = Self::init_vad(&cfg.vad)?;
-        let resampler = Self::init_resampler(cfg.resample)?;
+        let embed = Self::init_embedding(vb.clone())?;
+        let (encoder, decoder) = Self::init_encoder_decoder(vb, encoder_cfg, tokens, &cfg.decode)?;
+        let segmenter = Segmenter::new(cfg.resample, &cfg.vad)?;
+        event!(
+            Level::INFO,
+            "Loaded {} in {:.2?}",
+            weight_file.display(),
+            start.elapsed()
+        );
 
         Ok(Self {
             device,
-            resampler,
-            vad,
+            dtype,
+            segmenter,
             embed,
             frontend,
             encoder,
             decoder,
+            language: cfg.language,
+            text_norm: cfg.text_norm,
+            batch_size: cfg.batch_size,
+            chunk: cfg.chunk,
         })
     }
 
     pub async fn get_required_files<P: Into<PathBuf>>(model_dir: P) -> Res<Vec<FileInfo>> {
         let repo = Self::model_repo(model_dir).await;
-
-        let cmvn_file = repo.get_file_info("am.mvn").await?;
-        let weight_file = repo.get_file_info("model.pt").await?;
-        let tokens_file = repo.get_file_info("tokens.json").await?;
-
-        Ok(vec![cmvn_file, weight_file, tokens_file])
+        repo.get_files_info(&MODEL_FILES).await
     }
 
     pub async fn check_required_files<P: Into<PathBuf>>(model_dir: P) -> bool {
-        let files = Self::get_required_files(model_dir).await;
-        match files {
-            Ok(files) => files.iter().all(|file| file.existed),
-            Err(e) => {
-                event!(Level::ERROR, "Failed to get required files: {}", e);
-                false
-            }
+        Self::model_repo(model_dir).await.has_files(&MODEL_FILES)
+    }
+
+    /// Repository files loaded with `cfg`
+    ///
+    /// A `weight_file` takes the place of `model.pt`, and a GGUF one can
+    /// embed the tokens and CMVN stats as well.
+    fn config_files(cfg: &SenseVoiceSmallConfig) -> Res<Vec<&'static str>> {
+        let Some(weight_file) = &cfg.weight_file else {
+            return Ok(MODEL_FILES.to_vec());
+        };
+
+        let gguf = GgufMetadata::read(weight_file)?;
+        let mut files = Vec::new();
+        if gguf.as_ref().is_none_or(|gguf| gguf.cmvn.is_none()) {
+            files.push("am.mvn");
         }
+        if gguf.as_ref().is_none_or(|gguf| gguf.tokens.is_none()) {
+            files.push("tokens.json");
+        }
+        Ok(files)
     }
 
     pub async fn model_repo<P: Into<PathBuf>>(model_dir: P) -> ModelScopeRepo {
@@ -132,57 +330,27 @@ impl SenseVoiceSmall {
         repo
     }
 
-    fn init_embedding() -> Res<Embedding> {
+    /// Query embeddings for language, event/emotion and text norm prompts
+    fn init_embedding(vb: VarBuilder) -> Res<Embedding> {
         let lid_dict_len = 7;
         let textnorm_dict_len = 2;
         let num_embeddings = 7 + lid_dict_len + textnorm_dict_len;
-        let weight =
-            Tensor::randn::<_, f32>(0.0, 1.0, (num_embeddings, EMBEDDING_DIM), &Device::Cpu)?;
-        let embed = Embedding::new(weight, EMBEDDING_DIM);
-        Ok(embed)
+        vb.pp("embed").embedding(num_embeddings, EMBEDDING_DIM)
     }
 
     fn init_encoder_decoder(
-        weight_file: PathBuf,
-        tokens_file: PathBuf,
-        device: &Device,
+        vb: VarBuilder,
+        encoder_cfg: EncoderConfig,
+        tokens: serde_json::Value,
+        decode: &DecodeConfig,
     ) -> Res<(Encoder, Decoder)> {
-        let vb = VarBuilder::from_file(&weight_file, &device)?;
-
-        let encoder = Encoder::new_with_config(
-            EncoderConfig {
-                input_size: EMBEDDING_DIM,
-                output_size: 512,
-                attention_heads: 4,
-                linear_units: 2048,
-                num_blocks: 50,
-                tp_blocks: 20,
-                dropout_rate: 0.1,
-                attention_dropout_rate: 0.1,
-                kernel_size: 11,
-                sanm_shfit: 0,
-                normalize_before: true,
-                concat_after: false,
-            },
-            vb.clone(),
-        )?;
+        let encoder = Encoder::new_with_config(encoder_cfg, vb.clone())?;
 
-        let decoder = Decoder::new(&tokens_file, vb)?;
+        let decoder = Decoder::new(tokens, decode.clone(), vb)?;
 
         Ok((encoder, decoder))
     }
 
-    fn init_resampler(sample_config: Option<(u32, u32)>) -> Res<Option<Resampler>> {
-        Ok(match sample_config {
-            Some((from, to)) => Some(Resampler::new(from, to)?),
-            None => None,
-        })
-    }
-
-    fn init_vad(cfg: &VadConfig) -> Res<VadProcessor> {
-        VadProcessor::new(cfg.clone())
-    }
-
     fn init_frontend(cmvn_file: &PathBuf) -> Res<WavFrontend> {
         WavFrontend::new(WavFrontendConfig {
             cmvn_file: Some(cmvn_file.clone()),
@@ -191,97 +359,486 @@ impl SenseVoiceSmall {
     }
 
     pub fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
-        let waveform = match &self.resampler {
-            None => waveform,
-            Some(sampler) => &mut sampler.apply_resample(&waveform)?,
-        };
-
-        self.vad.push(waveform);
-
-        Ok(self.vad.segment())
+        self.segmenter.segment(waveform)
     }
 
     pub fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
         let mut out = Vec::with_capacity(segments.len());
-        for seg in segments {
-            let text = self.process(&mut seg.data)?;
-            out.push(Token {
-                text,
-                start: seg.start,
-                end: seg.end,
-            });
+        for batch in segments.chunks_mut(self.batch_size.max(1)) {
+            let results = self.process_batch(batch)?;
+            for (seg, hyp) in batch.iter().zip(results) {
+                out.push(hyp.into_segment(seg.start, seg.end));
+            }
         }
 
         Ok(out)
     }
 
-    pub fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
-        let segment = self.vad.samples();
-        let mut out = Vec::with_capacity(1);
-        if let Some(mut seg) = segment {
-            let text = self.process(&mut seg.data)?;
-            out.push(Token {
-                text,
-                start: seg.start,
-                end: seg.end,
-            });
+    /// Top `n` hypotheses of each segment, best first
+    ///
+    /// Decodes with beam search whatever the configured method, token timing
+    /// is absolute.
+    pub fn transpose_nbest(
+        &mut self,
+        segments: &mut [Segment],
+        n: usize,
+    ) -> Res<Vec<Vec<Hypothesis>>> {
+        let mut out = Vec::with_capacity(segments.len());
+        for batch in segments.chunks_mut(self.batch_size.max(1)) {
+            let (encoder_out, lens) = self.encode_batch(batch)?;
+            let results = self.decoder.decode_nbest(&encoder_out, &lens, n)?;
+            for (seg, mut hyps) in batch.iter().zip(results) {
+                hyps.iter_mut().for_each(|hyp| hyp.shift(seg.start));
+                out.push(hyps);
+            }
         }
+
         Ok(out)
     }
 
-    fn process(&mut self, waveform: &mut [f32]) -> Res<String> {
-        let mut text = String::with_capacity(1024);
+    pub fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        let mut segment = self.segmenter.samples();
+        transpose_each(segment.as_mut_slice(), |data| self.process(data))
+    }
+
+    /// Align a known transcript, such as a script or lyrics, to its audio
+    ///
+    /// The whole waveform is encoded at once, split long recordings into
+    /// pieces of a few minutes. Waveform is resampled like in `segment`.
+    ///
+    /// # Arguments
+    /// * `waveform` - Audio of the transcript
+    /// * `text` - Transcript, one subtitle line per text line
+    /// * `offset` - Start of the audio in milliseconds
+    pub fn align(&mut self, waveform: &mut [f32], text: &str, offset: u32) -> Res<Alignment> {
+        let waveform = match self.segmenter.resampler() {
+            None => waveform,
+            Some(sampler) => &mut sampler.apply_resample(waveform)?,
+        };
+
         let features = self.frontend(waveform)?;
-        let encoder_out = self.encoder.forward(&features)?;
-        let out = self.decoder.decode(&encoder_out)?;
+        let encoder_out = self.encode(&features)?;
+        self.decoder.align(&encoder_out, text, offset)
+    }
 
-        for item in out.iter() {
-            text += &item.text;
+    fn process(&mut self, waveform: &mut [f32]) -> Res<Hypothesis> {
+        let features = self.frontend(waveform)?;
+        let encoder_out = self.encode(&features)?;
+        self.decoder.decode(&encoder_out)
+    }
+
+    /// Encode and decode several segments at once
+    fn process_batch(&mut self, segments: &mut [Segment]) -> Res<Vec<Hypothesis>> {
+        if let [seg] = segments {
+            return Ok(vec![self.process(&mut seg.data)?]);
         }
 
-        Ok(text)
+        let (encoder_out, lens) = self.encode_batch(segments)?;
+        self.decoder.decode_batch(&encoder_out, &lens)
+    }
+
+    /// Encode several segments at once, see [`encode_padded`]
+    fn encode_batch(&mut self, segments: &mut [Segment]) -> Res<(Tensor, Vec<usize>)> {
+        let mut features = Vec::with_capacity(segments.len());
+        for seg in segments.iter_mut() {
+            features.push(self.frontend(&mut seg.data)?);
+        }
+
+        encode_padded(&self.encoder, &self.chunk, &features)
+    }
+
+    /// Encode the features (1, time, size) of one segment, see [`encode_chunked`]
+    fn encode(&self, features: &Tensor) -> Res<Tensor> {
+        encode_chunked(&self.encoder, &self.chunk, features)
     }
 
     fn frontend(&self, waveform: &mut [f32]) -> Res<Tensor> {
-        let cpu = Device::Cpu;
+        let device = &self.device;
         let speech = self
             .frontend
             .extract_features_f32(waveform)
             .map_err(|e| Error::msg(e.to_string()))?
-            .to_device(&cpu)?
+            .to_device(device)?
+            .to_dtype(self.dtype)?
             .unsqueeze(0)?;
 
-        let language_query = Tensor::new(&[[0i64]], &cpu)?;
+        let language_query = Tensor::new(&[[self.language.query_id()]], device)?;
         let language_query = self.embed.forward(&language_query)?;
 
-        let text_norm_query = Tensor::new(&[[15i64]], &cpu)?;
+        let text_norm_query = Tensor::new(&[[self.text_norm.query_id()]], device)?;
         let text_norm_query = self.embed.forward(&text_norm_query)?;
 
-        let event_emo_query = Tensor::new(&[[1i64, 2]], &cpu)?;
+        let event_emo_query = Tensor::new(&[[1u32, 2]], device)?;
         let event_emo_query = self.embed.forward(&event_emo_query)?;
 
         let speech = Tensor::cat(&[&text_norm_query, &speech], 1)?;
         let input_query = Tensor::cat(&[&language_query, &event_emo_query], 1)?;
         let speech = Tensor::cat(&[&input_query, &speech], 1)?;
 
-        let speech = speech.to_device(&self.device)?;
-
         Ok(speech)
     }
 }
 
 impl ConfigRefresher<SenseVoiceSmallConfig> for SenseVoiceSmall {
     fn refresh(&mut self, old: &SenseVoiceSmallConfig, new: &SenseVoiceSmallConfig) -> Res<()> {
-        if old.vad != new.vad {
-            event!(Level::DEBUG, "Refreshing VAD");
-            self.vad = Self::init_vad(&new.vad)?;
+        self.segmenter.refresh(new.resample, &new.vad)?;
+
+        if old.language != new.language {
+            event!(Level::DEBUG, "Refreshing language");
+            self.language = new.language;
+        }
+
+        if old.text_norm != new.text_norm {
+            event!(Level::DEBUG, "Refreshing text norm");
+            self.text_norm = new.text_norm;
+        }
+
+        if old.batch_size != new.batch_size {
+            event!(Level::DEBUG, "Refreshing batch size");
+            self.batch_size = new.batch_size;
+        }
+
+        if old.decode != new.decode {
+            event!(Level::DEBUG, "Refreshing decoder");
+            self.decoder.set_config(new.decode.clone())?;
+        }
+
+        if old.chunk != new.chunk {
+            event!(Level::DEBUG, "Refreshing chunking");
+            self.chunk = new.chunk.clone();
+        }
+
+        Ok(())
+    }
+}
+
+impl SpeechRecognizer for SenseVoiceSmall {
+    type Config = SenseVoiceSmallConfig;
+
+    async fn load(config: SenseVoiceSmallConfig) -> Res<Self> {
+        Self::with_config(config).await
+    }
+
+    async fn model_repo(config: &SenseVoiceSmallConfig) -> ModelScopeRepo {
+        Self::model_repo(&config.model_dir).await
+    }
+
+    async fn required_files(config: &SenseVoiceSmallConfig) -> Res<Vec<FileInfo>> {
+        let repo = Self::model_repo(&config.model_dir).await;
+        repo.get_files_info(&Self::config_files(config)?).await
+    }
+
+    async fn check_required_files(config: &SenseVoiceSmallConfig) -> bool {
+        if config
+            .weight_file
+            .as_ref()
+            .is_some_and(|file| !file.exists())
+        {
+            return false;
         }
+        match Self::config_files(config) {
+            Ok(files) => Self::model_repo(&config.model_dir).await.has_files(&files),
+            Err(e) => {
+                event!(Level::ERROR, "Failed to read the weight file: {}", e);
+                false
+            }
+        }
+    }
+
+    fn needs_reload(old: &SenseVoiceSmallConfig, new: &SenseVoiceSmallConfig) -> bool {
+        old.model_dir != new.model_dir
+            || old.use_gpu != new.use_gpu
+            || old.dtype != new.dtype
+            || old.weight_file != new.weight_file
+            || old.fsmn_device != new.fsmn_device
+    }
 
-        if old.resample != new.resample {
-            event!(Level::DEBUG, "Refreshing resampler");
-            self.resampler = Self::init_resampler(new.resample)?;
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        Self::segment(self, waveform)
+    }
+
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        Self::transpose(self, segments)
+    }
+
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        Self::transpose_vad_cache(self)
+    }
+}
+
+/// Encode the features (1, time, size) of several segments at once
+///
+/// Features are padded to the longest segment and a length mask keeps the
+/// padding out of attention, FSMN memory and CTC decoding.
+///
+/// # Returns
+/// * Encoder output (batch, time, size)
+/// * Valid frames of each segment
+fn encode_padded(
+    encoder: &Encoder,
+    chunk: &ChunkConfig,
+    features: &[Tensor],
+) -> Res<(Tensor, Vec<usize>)> {
+    let lens = features
+        .iter()
+        .map(|f| f.dim(1))
+        .collect::<candle_core::Result<Vec<usize>>>()?;
+    if let [features] = features {
+        return Ok((encode_chunked(encoder, chunk, features)?, lens));
+    }
+    let max_len = lens.iter().copied().max().unwrap_or_default();
+
+    // Too long to encode at once, encode one by one in windows and pad the outputs
+    let (chunk_frames, _) = chunk.frames();
+    if chunk_frames > 0 && max_len > QUERY_FRAMES + chunk_frames {
+        let mut outputs = Vec::with_capacity(features.len());
+        for (f, &len) in features.iter().zip(&lens) {
+            outputs.push(encode_chunked(encoder, chunk, f)?.pad_with_zeros(1, 0, max_len - len)?);
         }
+        return Ok((Tensor::cat(&outputs, 0)?, lens));
+    }
+
+    let device = features[0].device();
+    let features = features
+        .iter()
+        .zip(&lens)
+        .map(|(f, &len)| f.pad_with_zeros(1, 0, max_len - len))
+        .collect::<candle_core::Result<Vec<Tensor>>>()?;
+    let features = Tensor::cat(&features, 0)?;
+
+    let mask = lens
+        .iter()
+        .flat_map(|&len| (0..max_len).map(move |i| if i < len { 1f32 } else { 0f32 }))
+        .collect::<Vec<f32>>();
+    let mask = Tensor::from_vec(mask, (lens.len(), max_len), device)?;
+
+    let encoder_out = encoder.forward(&features, Some(&mask))?;
+    Ok((encoder_out, lens))
+}
 
+/// Encode the features (1, time, size) of one segment
+///
+/// Speech longer than a chunk is encoded in overlapping windows, each with
+/// the query frames in front. Every window keeps its frames up to the middle
+/// of the overlap with the next one, so the stitched output has one frame
+/// per input frame like an unchunked pass.
+fn encode_chunked(encoder: &Encoder, chunk: &ChunkConfig, features: &Tensor) -> Res<Tensor> {
+    let (chunk, overlap) = chunk.frames();
+    let speech = features.dim(1)?.saturating_sub(QUERY_FRAMES);
+    if chunk == 0 || speech <= chunk {
+        return encoder.forward(features, None);
+    }
+
+    let windows = chunk_windows(speech, chunk, overlap);
+    event!(
+        Level::DEBUG,
+        "Encoding {} frames in {} windows",
+        speech,
+        windows.len()
+    );
+
+    let queries = features.narrow(1, 0, QUERY_FRAMES)?;
+    let mut outputs = Vec::with_capacity(windows.len());
+    for window in &windows {
+        let speech = features.narrow(1, QUERY_FRAMES + window.start, window.len)?;
+        let window = Tensor::cat(&[&queries, &speech], 1)?;
+        outputs.push(encoder.forward(&window, None)?);
+    }
+
+    stitch_windows(&outputs, &windows)
+}
+
+/// A window of chunked encoding, in speech frames after the queries
+struct ChunkWindow {
+    start: usize,
+    len: usize,
+    /// Frames of the window kept in the stitched output
+    keep: Range<usize>,
+}
+
+/// Overlapping windows covering `speech` frames
+///
+/// Every window but the last keeps its frames up to the middle of the overlap
+/// with the next one, which keeps the frames from there on.
+fn chunk_windows(speech: usize, chunk: usize, overlap: usize) -> Vec<ChunkWindow> {
+    let step = chunk - overlap;
+    let mut starts = vec![0];
+    while starts[starts.len() - 1] + chunk < speech {
+        starts.push(starts[starts.len() - 1] + step);
+    }
+
+    let last = starts.len() - 1;
+    starts
+        .into_iter()
+        .enumerate()
+        .map(|(i, start)| {
+            let len = chunk.min(speech - start);
+            let keep_from = if i == 0 { 0 } else { overlap / 2 };
+            let keep_to = if i == last { len } else { step + overlap / 2 };
+            ChunkWindow {
+                start,
+                len,
+                keep: keep_from..keep_to,
+            }
+        })
+        .collect()
+}
+
+/// Join the encoder outputs (1, queries + len, size) of the windows into
+/// one output with a frame per input frame
+fn stitch_windows(outputs: &[Tensor], windows: &[ChunkWindow]) -> Res<Tensor> {
+    let mut parts = Vec::with_capacity(windows.len() + 1);
+    for (i, (out, window)) in outputs.iter().zip(windows).enumerate() {
+        if i == 0 {
+            parts.push(out.narrow(1, 0, QUERY_FRAMES)?);
+        }
+        parts.push(out.narrow(1, QUERY_FRAMES + window.keep.start, window.keep.len())?);
+    }
+
+    Ok(Tensor::cat(&parts, 1)?)
+}
+
+/// Hyperparameters of the SenseVoiceSmall encoder
+fn encoder_config() -> EncoderConfig {
+    EncoderConfig {
+        input_size: EMBEDDING_DIM,
+        output_size: 512,
+        attention_heads: 4,
+        linear_units: 2048,
+        num_blocks: 50,
+        tp_blocks: 20,
+        dropout_rate: 0.1,
+        attention_dropout_rate: 0.1,
+        kernel_size: 11,
+        sanm_shfit: 0,
+        fsmn_device: FsmnDevice::default(),
+        normalize_before: true,
+        concat_after: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::D;
+    use candle_core::quantized::{GgmlDType, QTensor, gguf_file};
+    use candle_nn::VarMap;
+
+    #[test]
+    fn stitched_windows_match_unchunked_argmax() -> Res<()> {
+        let device = Device::Cpu;
+        let (speech, chunk, overlap, vocab) = (30, 20, 6, 8);
+        let full = Tensor::randn(0f32, 1., (1, QUERY_FRAMES + speech, vocab), &device)?;
+
+        let windows = chunk_windows(speech, chunk, overlap);
+        assert_eq!(windows.len(), 2);
+
+        // A window sees less context than the whole segment, so frames it
+        // does not keep come out different
+        let mut outputs = Vec::new();
+        for window in &windows {
+            let queries = full.narrow(1, 0, QUERY_FRAMES)?;
+            let speech = full.narrow(1, QUERY_FRAMES + window.start, window.len)?;
+            let noise = Tensor::randn(0f32, 10., (1, window.len, vocab), &device)?;
+            let keep = (0..window.len)
+                .map(|t| u8::from(window.keep.contains(&t)))
+                .collect::<Vec<_>>();
+            let keep = Tensor::from_vec(keep, (1, window.len, 1), &device)?
+                .broadcast_as(speech.shape())?;
+            let speech = keep.where_cond(&speech, &noise)?;
+            outputs.push(Tensor::cat(&[&queries, &speech], 1)?);
+        }
+
+        let stitched = stitch_windows(&outputs, &windows)?;
+        assert_eq!(stitched.dims(), full.dims());
+        assert_eq!(
+            stitched.argmax(D::Minus1)?.to_vec2::<u32>()?,
+            full.argmax(D::Minus1)?.to_vec2::<u32>()?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn batch_matches_single_segments() -> Res<()> {
+        let device = Device::Cpu;
+        let size = 16;
+        let varmap = VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let encoder = Encoder::new_with_config(
+            EncoderConfig {
+                input_size: size,
+                output_size: size,
+                attention_heads: 2,
+                linear_units: 32,
+                num_blocks: 3,
+                tp_blocks: 1,
+                dropout_rate: 0.0,
+                attention_dropout_rate: 0.0,
+                ..EncoderConfig::default()
+            },
+            VarBuilder::Normal(vb),
+        )?;
+
+        // Masked batch, then one segment longer than a chunk of 20 frames
+        for (speech, chunk_ms) in [([10, 19, 14], 1200), ([10, 25, 14], 1200)] {
+            let chunk = ChunkConfig {
+                chunk_ms,
+                overlap_ms: 360,
+            };
+            let features = speech
+                .iter()
+                .map(|&len| Tensor::randn(0f32, 1., (1, QUERY_FRAMES + len, size), &device))
+                .collect::<candle_core::Result<Vec<_>>>()?;
+
+            let (batch, lens) = encode_padded(&encoder, &chunk, &features)?;
+            assert_eq!(batch.dims(), &[3, QUERY_FRAMES + speech[1], size]);
+            for (i, (features, &len)) in features.iter().zip(&lens).enumerate() {
+                assert_eq!(len, features.dim(1)?);
+                let single = encode_chunked(&encoder, &chunk, features)?;
+                assert!(single.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()? > 0.1);
+
+                let diff = (batch.narrow(0, i, 1)?.narrow(1, 0, len)? - single)?
+                    .abs()?
+                    .flatten_all()?
+                    .max(0)?
+                    .to_scalar::<f32>()?;
+                assert!(diff < 1e-5, "segment {i} of {speech:?} differs by {diff}");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_files_follow_the_weight_file() -> Res<()> {
+        let dir = std::env::temp_dir().join(format!("sense_voice_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let gguf = dir.join("model.gguf");
+        let tokens = gguf_file::Value::Array(vec![gguf_file::Value::String("<unk>".into())]);
+        let weight = QTensor::quantize(
+            &Tensor::zeros(32, DType::F32, &Device::Cpu)?,
+            GgmlDType::F32,
+        )?;
+        gguf_file::write(
+            &mut File::create(&gguf)?,
+            &[("tokenizer.ggml.tokens", &tokens)],
+            &[("weight", &weight)],
+        )?;
+
+        let cfg = |weight_file: Option<PathBuf>| SenseVoiceSmallConfig {
+            weight_file,
+            ..SenseVoiceSmallConfig::default()
+        };
+        let files = SenseVoiceSmall::config_files(&cfg(None))?;
+        assert_eq!(files, MODEL_FILES);
+        let files = SenseVoiceSmall::config_files(&cfg(Some(dir.join("model.safetensors"))))?;
+        assert_eq!(files, ["am.mvn", "tokens.json"]);
+        // The tokens are embedded, the CMVN stats are not
+        let files = SenseVoiceSmall::config_files(&cfg(Some(gguf)))?;
+        assert_eq!(files, ["am.mvn"]);
+
+        std::fs::remove_dir_all(&dir)?;
         Ok(())
     }
 }
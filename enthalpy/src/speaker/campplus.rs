@@ -0,0 +1,415 @@
+use crate::Res;
+use crate::var_builder::VarBuilder;
+use candle_core::{D, Module, ModuleT, Tensor};
+use candle_nn::{BatchNorm, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig};
+
+/// Frames of the segment average in the context of a CAM layer
+const SEG_LEN: usize = 100;
+
+/// Batch norm followed by ReLU, the `batchnorm-relu` nonlinearity
+struct BatchNormRelu {
+    batchnorm: BatchNorm,
+}
+
+impl BatchNormRelu {
+    fn new(channels: usize, vb: VarBuilder) -> Res<Self> {
+        Ok(Self {
+            batchnorm: vb.pp("batchnorm").batch_norm(channels, 1e-5)?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        Ok(self.batchnorm.forward_t(x, false)?.relu()?)
+    }
+}
+
+/// Keep every `stride`th row of the frequency axis (batch, channels, freq, time)
+///
+/// Candle convolutions have one stride for both axes, the frequency only
+/// convolutions of the head run with stride 1 and are subsampled after.
+fn subsample_freq(x: &Tensor, stride: usize) -> Res<Tensor> {
+    if stride == 1 {
+        return Ok(x.clone());
+    }
+    let rows = (0..x.dim(2)? as u32).step_by(stride).collect::<Vec<_>>();
+    let rows = Tensor::new(rows, x.device())?;
+    Ok(x.index_select(&rows, 2)?)
+}
+
+/// 3x3 convolution striding only the frequency axis, without bias
+struct FreqConv {
+    conv: Conv2d,
+    stride: usize,
+}
+
+impl FreqConv {
+    fn new(in_planes: usize, planes: usize, stride: usize, vb: VarBuilder) -> Res<Self> {
+        let cfg = Conv2dConfig {
+            padding: 1,
+            ..Conv2dConfig::default()
+        };
+        Ok(Self {
+            conv: vb.conv2d_no_bias(in_planes, planes, 3, cfg)?,
+            stride,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        subsample_freq(&self.conv.forward(x)?, self.stride)
+    }
+}
+
+struct BasicResBlock {
+    conv1: FreqConv,
+    bn1: BatchNorm,
+    conv2: FreqConv,
+    bn2: BatchNorm,
+    /// 1x1 convolution and batch norm when the block changes the shape
+    shortcut: Option<(Conv2d, BatchNorm)>,
+    stride: usize,
+}
+
+impl BasicResBlock {
+    fn new(in_planes: usize, planes: usize, stride: usize, vb: VarBuilder) -> Res<Self> {
+        let shortcut = if stride != 1 || in_planes != planes {
+            let vb = vb.pp("shortcut");
+            Some((
+                vb.pp(0)
+                    .conv2d_no_bias(in_planes, planes, 1, Conv2dConfig::default())?,
+                vb.pp(1).batch_norm(planes, 1e-5)?,
+            ))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            conv1: FreqConv::new(in_planes, planes, stride, vb.pp("conv1"))?,
+            bn1: vb.pp("bn1").batch_norm(planes, 1e-5)?,
+            conv2: FreqConv::new(planes, planes, 1, vb.pp("conv2"))?,
+            bn2: vb.pp("bn2").batch_norm(planes, 1e-5)?,
+            shortcut,
+            stride,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        let out = self.bn1.forward_t(&self.conv1.forward(x)?, false)?.relu()?;
+        let out = self.bn2.forward_t(&self.conv2.forward(&out)?, false)?;
+        let shortcut = match &self.shortcut {
+            Some((conv, bn)) => {
+                let x = subsample_freq(x, self.stride)?;
+                bn.forward_t(&conv.forward(&x)?, false)?
+            }
+            None => x.clone(),
+        };
+        Ok((out + shortcut)?.relu()?)
+    }
+}
+
+/// Front-end convolution module over the fbank as an image
+struct Fcm {
+    conv1: FreqConv,
+    bn1: BatchNorm,
+    layer1: Vec<BasicResBlock>,
+    layer2: Vec<BasicResBlock>,
+    conv2: FreqConv,
+    bn2: BatchNorm,
+}
+
+impl Fcm {
+    /// Channels of the convolutions
+    const M_CHANNELS: usize = 32;
+
+    fn new(vb: VarBuilder) -> Res<Self> {
+        let m = Self::M_CHANNELS;
+        let layer = |vb: VarBuilder| -> Res<Vec<BasicResBlock>> {
+            Ok(vec![
+                BasicResBlock::new(m, m, 2, vb.pp(0))?,
+                BasicResBlock::new(m, m, 1, vb.pp(1))?,
+            ])
+        };
+
+        Ok(Self {
+            conv1: FreqConv::new(1, m, 1, vb.pp("conv1"))?,
+            bn1: vb.pp("bn1").batch_norm(m, 1e-5)?,
+            layer1: layer(vb.pp("layer1"))?,
+            layer2: layer(vb.pp("layer2"))?,
+            conv2: FreqConv::new(m, m, 2, vb.pp("conv2"))?,
+            bn2: vb.pp("bn2").batch_norm(m, 1e-5)?,
+        })
+    }
+
+    /// Output channels for `feat_dim` mel bins, the frequency axis is
+    /// reduced 8 times and folded into the channels
+    fn out_channels(feat_dim: usize) -> usize {
+        Self::M_CHANNELS * (feat_dim / 8)
+    }
+
+    /// (batch, freq, time) to (batch, channels, time)
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        let x = x.unsqueeze(1)?;
+        let mut out = self
+            .bn1
+            .forward_t(&self.conv1.forward(&x)?, false)?
+            .relu()?;
+        for block in self.layer1.iter().chain(&self.layer2) {
+            out = block.forward(&out)?;
+        }
+        let out = self
+            .bn2
+            .forward_t(&self.conv2.forward(&out)?, false)?
+            .relu()?;
+        let (b, c, f, t) = out.dims4()?;
+        Ok(out.reshape((b, c * f, t))?)
+    }
+}
+
+/// Context-aware masking: a local convolution scaled by a gate computed from
+/// the utterance and segment averages
+struct CamLayer {
+    linear_local: Conv1d,
+    linear1: Conv1d,
+    linear2: Conv1d,
+}
+
+impl CamLayer {
+    fn new(
+        bn_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        dilation: usize,
+        vb: VarBuilder,
+    ) -> Res<Self> {
+        let local = Conv1dConfig {
+            padding: (kernel_size - 1) / 2 * dilation,
+            dilation,
+            ..Conv1dConfig::default()
+        };
+        let reduced = bn_channels / 2;
+
+        Ok(Self {
+            linear_local: vb.pp("linear_local").conv1d_no_bias(
+                bn_channels,
+                out_channels,
+                kernel_size,
+                local,
+            )?,
+            linear1: vb
+                .pp("linear1")
+                .conv1d(bn_channels, reduced, 1, Conv1dConfig::default())?,
+            linear2: vb
+                .pp("linear2")
+                .conv1d(reduced, out_channels, 1, Conv1dConfig::default())?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        let y = self.linear_local.forward(x)?;
+        let context = x.mean_keepdim(D::Minus1)?.broadcast_add(&seg_pooling(x)?)?;
+        let context = self.linear1.forward(&context)?.relu()?;
+        let m = candle_nn::ops::sigmoid(&self.linear2.forward(&context)?)?;
+        Ok((y * m)?)
+    }
+}
+
+/// Average of every `SEG_LEN` frames, repeated over the frames it covers
+fn seg_pooling(x: &Tensor) -> Res<Tensor> {
+    let frames = x.dim(D::Minus1)?;
+    let mut segs = Vec::with_capacity(frames.div_ceil(SEG_LEN));
+    for start in (0..frames).step_by(SEG_LEN) {
+        let len = SEG_LEN.min(frames - start);
+        let seg = x.narrow(D::Minus1, start, len)?.mean_keepdim(D::Minus1)?;
+        segs.push(seg.repeat((1, 1, len))?);
+    }
+    Ok(Tensor::cat(&segs, D::Minus1)?)
+}
+
+struct CamDenseTdnnLayer {
+    nonlinear1: BatchNormRelu,
+    linear1: Conv1d,
+    nonlinear2: BatchNormRelu,
+    cam_layer: CamLayer,
+}
+
+impl CamDenseTdnnLayer {
+    fn new(
+        in_channels: usize,
+        out_channels: usize,
+        bn_channels: usize,
+        kernel_size: usize,
+        dilation: usize,
+        vb: VarBuilder,
+    ) -> Res<Self> {
+        Ok(Self {
+            nonlinear1: BatchNormRelu::new(in_channels, vb.pp("nonlinear1"))?,
+            linear1: vb.pp("linear1").conv1d_no_bias(
+                in_channels,
+                bn_channels,
+                1,
+                Conv1dConfig::default(),
+            )?,
+            nonlinear2: BatchNormRelu::new(bn_channels, vb.pp("nonlinear2"))?,
+            cam_layer: CamLayer::new(
+                bn_channels,
+                out_channels,
+                kernel_size,
+                dilation,
+                vb.pp("cam_layer"),
+            )?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        let x = self.linear1.forward(&self.nonlinear1.forward(x)?)?;
+        self.cam_layer.forward(&self.nonlinear2.forward(&x)?)
+    }
+}
+
+/// Batch norm, ReLU and a 1x1 convolution halving the channels
+struct TransitLayer {
+    nonlinear: BatchNormRelu,
+    linear: Conv1d,
+}
+
+impl TransitLayer {
+    fn new(in_channels: usize, out_channels: usize, vb: VarBuilder) -> Res<Self> {
+        Ok(Self {
+            nonlinear: BatchNormRelu::new(in_channels, vb.pp("nonlinear"))?,
+            linear: vb.pp("linear").conv1d_no_bias(
+                in_channels,
+                out_channels,
+                1,
+                Conv1dConfig::default(),
+            )?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Res<Tensor> {
+        Ok(self.linear.forward(&self.nonlinear.forward(x)?)?)
+    }
+}
+
+/// CAM++ speaker embedding network of 3D-Speaker
+///
+/// A 2D convolution front-end, a densely connected TDNN with context-aware
+/// masking and statistics pooling into one embedding per utterance.
+pub struct CamPlus {
+    head: Fcm,
+    tdnn: Conv1d,
+    tdnn_nonlinear: BatchNormRelu,
+    /// Dense blocks, each layer's output is concatenated to its input
+    blocks: Vec<(Vec<CamDenseTdnnLayer>, TransitLayer)>,
+    out_nonlinear: BatchNormRelu,
+    dense: Conv1d,
+    dense_norm: BatchNorm,
+}
+
+impl CamPlus {
+    /// Creates a new CamPlus instance
+    ///
+    /// # Arguments
+    /// * `feat_dim` - Mel bins of the input
+    /// * `embedding_size` - Size of the speaker embedding
+    /// * `vb` - VarBuilder for creating layers
+    pub fn new(feat_dim: usize, embedding_size: usize, vb: VarBuilder) -> Res<Self> {
+        let growth_rate = 32;
+        let bn_channels = 4 * growth_rate;
+        let init_channels = 128;
+
+        let head = Fcm::new(vb.pp("head"))?;
+        let vb = vb.pp("xvector");
+        let tdnn = vb.pp("tdnn").pp("linear").conv1d_no_bias(
+            Fcm::out_channels(feat_dim),
+            init_channels,
+            5,
+            Conv1dConfig {
+                padding: 2,
+                stride: 2,
+                ..Conv1dConfig::default()
+            },
+        )?;
+        let tdnn_nonlinear = BatchNormRelu::new(init_channels, vb.pp("tdnn").pp("nonlinear"))?;
+
+        let mut channels = init_channels;
+        let mut blocks = Vec::with_capacity(3);
+        for (i, (num_layers, dilation)) in [(12, 1), (24, 2), (16, 2)].into_iter().enumerate() {
+            let vb_block = vb.pp(format!("block{}", i + 1));
+            let layers = (0..num_layers)
+                .map(|j| {
+                    CamDenseTdnnLayer::new(
+                        channels + j * growth_rate,
+                        growth_rate,
+                        bn_channels,
+                        3,
+                        dilation,
+                        vb_block.pp(format!("tdnnd{}", j + 1)),
+                    )
+                })
+                .collect::<Res<Vec<_>>>()?;
+            channels += num_layers * growth_rate;
+
+            let transit =
+                TransitLayer::new(channels, channels / 2, vb.pp(format!("transit{}", i + 1)))?;
+            channels /= 2;
+            blocks.push((layers, transit));
+        }
+
+        let out_nonlinear = BatchNormRelu::new(channels, vb.pp("out_nonlinear"))?;
+        let dense = vb.pp("dense").pp("linear").conv1d_no_bias(
+            channels * 2,
+            embedding_size,
+            1,
+            Conv1dConfig::default(),
+        )?;
+        // `batchnorm_` of the config, without weight and bias
+        let dense_norm = vb.pp("dense").pp("nonlinear").pp("batchnorm").batch_norm(
+            embedding_size,
+            candle_nn::BatchNormConfig {
+                affine: false,
+                ..candle_nn::BatchNormConfig::default()
+            },
+        )?;
+
+        Ok(Self {
+            head,
+            tdnn,
+            tdnn_nonlinear,
+            blocks,
+            out_nonlinear,
+            dense,
+            dense_norm,
+        })
+    }
+
+    /// Forward pass
+    ///
+    /// # Arguments
+    /// * `features` - Mean normalized fbank (batch, time, feat_dim)
+    ///
+    /// # Returns
+    /// * Speaker embeddings (batch, embedding_size)
+    pub fn forward(&self, features: &Tensor) -> Res<Tensor> {
+        let x = self
+            .head
+            .forward(&features.transpose(1, 2)?.contiguous()?)?;
+        let mut x = self.tdnn_nonlinear.forward(&self.tdnn.forward(&x)?)?;
+        for (layers, transit) in &self.blocks {
+            for layer in layers {
+                let out = layer.forward(&x)?;
+                x = Tensor::cat(&[&x, &out], 1)?;
+            }
+            x = transit.forward(&x)?;
+        }
+        let x = self.out_nonlinear.forward(&x)?;
+
+        // Mean and unbiased standard deviation over time
+        let frames = x.dim(D::Minus1)?;
+        let mean = x.mean_keepdim(D::Minus1)?;
+        let var =
+            (x.broadcast_sub(&mean)?.sqr()?.sum_keepdim(D::Minus1)? / (frames.max(2) - 1) as f64)?;
+        let stats = Tensor::cat(&[mean, var.sqrt()?], 1)?;
+
+        let x = self.dense.forward(&stats)?.squeeze(D::Minus1)?;
+        Ok(self.dense_norm.forward_t(&x, false)?)
+    }
+}
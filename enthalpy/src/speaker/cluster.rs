@@ -0,0 +1,181 @@
+/// Cosine similarity of two embeddings
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    dot / (norm(a) * norm(b)).max(f32::EPSILON)
+}
+
+/// Speakers of a live session, each the running mean of its embeddings
+///
+/// An embedding joins the most similar speaker if the similarity reaches the
+/// threshold and starts a new speaker otherwise, so ids never change once
+/// given out.
+pub struct OnlineClustering {
+    threshold: f32,
+    /// Sum of the embeddings of each speaker, the id is the index
+    centroids: Vec<Vec<f32>>,
+}
+
+impl OnlineClustering {
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            centroids: Vec::new(),
+        }
+    }
+
+    /// Clustering that starts with known speakers
+    ///
+    /// # Arguments
+    /// * `threshold` - Cosine similarity an embedding needs to join a speaker
+    /// * `centroids` - Sum or mean of the embeddings of each speaker
+    pub fn with_centroids(threshold: f32, centroids: Vec<Vec<f32>>) -> Self {
+        Self {
+            threshold,
+            centroids,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
+    }
+
+    /// Speaker of an embedding
+    ///
+    /// With `update` false the embedding is too short to be trusted, it is
+    /// matched against the speakers without changing them and gets None
+    /// instead of a new speaker.
+    pub fn assign(&mut self, embedding: &[f32], update: bool) -> Option<u32> {
+        let best = self
+            .centroids
+            .iter()
+            .map(|c| cosine_similarity(c, embedding))
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+
+        match best {
+            Some((id, similarity)) if similarity >= self.threshold => {
+                if update {
+                    let centroid = &mut self.centroids[id];
+                    centroid
+                        .iter_mut()
+                        .zip(embedding)
+                        .for_each(|(c, e)| *c += e);
+                }
+                Some(id as u32)
+            }
+            _ if update => {
+                self.centroids.push(embedding.to_vec());
+                Some(self.centroids.len() as u32 - 1)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.centroids.clear();
+    }
+}
+
+/// Agglomerative clustering with average linkage
+///
+/// The two most similar clusters are merged until no pair reaches
+/// `threshold`. Clusters are numbered in order of their first embedding.
+pub fn cluster(embeddings: &[&[f32]], threshold: f32) -> Vec<u32> {
+    let n = embeddings.len();
+    let mut similarity = vec![vec![0f32; n]; n];
+    for i in 0..n {
+        for j in i + 1..n {
+            let s = cosine_similarity(embeddings[i], embeddings[j]);
+            similarity[i][j] = s;
+            similarity[j][i] = s;
+        }
+    }
+
+    // Members of each cluster, merged clusters are left empty
+    let mut clusters = (0..n).map(|i| vec![i]).collect::<Vec<_>>();
+    loop {
+        let mut best = None;
+        for i in 0..n {
+            if clusters[i].is_empty() {
+                continue;
+            }
+            for j in i + 1..n {
+                if clusters[j].is_empty() {
+                    continue;
+                }
+                if best.is_none_or(|(_, _, s)| similarity[i][j] > s) {
+                    best = Some((i, j, similarity[i][j]));
+                }
+            }
+        }
+
+        let Some((a, b, _)) = best.filter(|&(_, _, s)| s >= threshold) else {
+            break;
+        };
+
+        // Average linkage of the merged cluster to every other one
+        let (size_a, size_b) = (clusters[a].len() as f32, clusters[b].len() as f32);
+        for k in 0..n {
+            if k == a || k == b || clusters[k].is_empty() {
+                continue;
+            }
+            let s = (size_a * similarity[a][k] + size_b * similarity[b][k]) / (size_a + size_b);
+            similarity[a][k] = s;
+            similarity[k][a] = s;
+        }
+        let members = std::mem::take(&mut clusters[b]);
+        clusters[a].extend(members);
+    }
+
+    let mut labels = vec![0; n];
+    let mut clusters = clusters
+        .into_iter()
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    clusters.sort_by_key(|c| c.iter().min().copied());
+    for (label, members) in clusters.into_iter().enumerate() {
+        for i in members {
+            labels[i] = label as u32;
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cluster_merges_similar_embeddings() {
+        let embeddings: [&[f32]; 5] = [
+            &[0.0, 1.0, 0.0],
+            &[1.0, 0.0, 0.0],
+            &[0.9, 0.1, 0.0],
+            &[0.1, 0.9, 0.0],
+            &[0.0, 0.0, 1.0],
+        ];
+        assert_eq!(cluster(&embeddings, 0.8), [0, 1, 1, 0, 2]);
+        assert_eq!(cluster(&embeddings, -1.0), [0; 5]);
+        assert_eq!(cluster(&embeddings, 1.1), [0, 1, 2, 3, 4]);
+        assert!(cluster(&[], 0.8).is_empty());
+    }
+
+    #[test]
+    fn short_embeddings_do_not_change_speakers() {
+        let mut clustering = OnlineClustering::new(0.8);
+        assert_eq!(clustering.assign(&[1.0, 0.0], false), None);
+        assert_eq!(clustering.assign(&[1.0, 0.0], true), Some(0));
+
+        // Matched but not added to the centroid
+        assert_eq!(clustering.assign(&[0.8, 0.6], false), Some(0));
+        assert_eq!(clustering.centroids, [[1.0, 0.0]]);
+        // Unlike every speaker, no new one is started
+        assert_eq!(clustering.assign(&[0.0, 1.0], false), None);
+        assert_eq!(clustering.centroids.len(), 1);
+
+        assert_eq!(clustering.assign(&[0.0, 1.0], true), Some(1));
+        assert_eq!(clustering.assign(&[0.8, 0.6], true), Some(0));
+        assert_eq!(clustering.centroids[0], [1.8, 0.6]);
+    }
+}
@@ -0,0 +1,293 @@
+//! Speaker diarization
+//!
+//! CAM++ of 3D-Speaker embeds every speech segment of the VAD. Segments of a
+//! live session are clustered as they arrive, so a speaker keeps its id, and
+//! the segments of a file can be clustered again once all of them are known.
+
+use crate::Res;
+use crate::audio::silero_vad::Segment;
+use crate::audio::{WavFrontend, WavFrontendConfig};
+use crate::config::ConfigRefresher;
+use crate::util::modelscope::{FileInfo, ModelScopeRepo};
+use crate::util::select_device;
+use crate::var_builder::VarBuilder;
+use anyhow::Error;
+use campplus::CamPlus;
+use candle_core::{DType, Device};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Instant;
+use tracing::{Level, event};
+
+mod campplus;
+mod cluster;
+
+pub use cluster::{OnlineClustering, cluster, cosine_similarity};
+
+const MODEL_ID: &str = "iic/speech_campplus_sv_zh-cn_16k-common";
+
+/// Weights of the CAM++ speaker model
+const MODEL_FILES: [&str; 1] = ["campplus_cn_common.bin"];
+
+/// Mel bins of the fbank
+const FEAT_DIM: usize = 80;
+
+/// Size of the speaker embeddings
+const EMBEDDING_SIZE: usize = 192;
+
+/// Fbank frames a segment needs to be embedded at all
+const MIN_FRAMES: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpeakerConfig {
+    pub model_dir: PathBuf,
+    pub use_gpu: bool,
+    /// Cosine similarity a segment needs to belong to a speaker, lower
+    /// values merge more voices into one speaker
+    pub threshold: f32,
+    /// Shorter segments are matched to the known speakers but never start a
+    /// new one, their embeddings are unreliable
+    pub min_segment_ms: u32,
+}
+
+impl Default for SpeakerConfig {
+    fn default() -> Self {
+        Self {
+            model_dir: PathBuf::default(),
+            use_gpu: false,
+            threshold: 0.5,
+            min_segment_ms: 1000,
+        }
+    }
+}
+
+/// Embedding of an assigned segment, kept for reclustering
+struct SegmentEmbedding {
+    /// None if the segment is too short to embed
+    embedding: Option<Vec<f32>>,
+    /// Whether the segment reaches `min_segment_ms`
+    long: bool,
+}
+
+pub struct Diarizer {
+    device: Device,
+    frontend: WavFrontend,
+    model: CamPlus,
+    clustering: OnlineClustering,
+    threshold: f32,
+    min_segment_ms: u32,
+    /// Every segment assigned since the last reset
+    history: Vec<SegmentEmbedding>,
+}
+
+impl Diarizer {
+    pub async fn with_config(cfg: SpeakerConfig) -> Res<Self> {
+        let device = select_device(cfg.use_gpu)?;
+        Self::new(cfg, &device).await
+    }
+
+    pub async fn new(cfg: SpeakerConfig, device: &Device) -> Res<Self> {
+        let device = device.clone();
+        let repo = Self::model_repo(&cfg.model_dir).await;
+
+        let start = Instant::now();
+        let weight_file = repo.get(MODEL_FILES[0]).await?;
+        let vb = VarBuilder::from_file(&weight_file, DType::F32, &device)?;
+        let model = CamPlus::new(FEAT_DIM, EMBEDDING_SIZE, vb)?;
+        event!(
+            Level::INFO,
+            "Loaded {} in {:.2?}",
+            weight_file.display(),
+            start.elapsed()
+        );
+
+        // Kaldi fbank as computed by torchaudio for 3D-Speaker
+        let frontend = WavFrontend::new(WavFrontendConfig {
+            n_mels: FEAT_DIM,
+            lfr_m: 1,
+            lfr_n: 1,
+            window_type: c"povey",
+            dither: 0.0,
+            ..WavFrontendConfig::default()
+        })?;
+
+        Ok(Self {
+            device,
+            frontend,
+            model,
+            clustering: OnlineClustering::new(cfg.threshold),
+            threshold: cfg.threshold,
+            min_segment_ms: cfg.min_segment_ms,
+            history: Vec::new(),
+        })
+    }
+
+    pub async fn model_repo<P: Into<PathBuf>>(model_dir: P) -> ModelScopeRepo {
+        ModelScopeRepo::new(MODEL_ID, model_dir.into())
+    }
+
+    pub async fn get_required_files<P: Into<PathBuf>>(model_dir: P) -> Res<Vec<FileInfo>> {
+        let repo = Self::model_repo(model_dir).await;
+        repo.get_files_info(&MODEL_FILES).await
+    }
+
+    pub async fn check_required_files<P: Into<PathBuf>>(model_dir: P) -> bool {
+        Self::model_repo(model_dir).await.has_files(&MODEL_FILES)
+    }
+
+    /// Unit length speaker embedding of a 16 kHz segment, None if it is too
+    /// short
+    pub fn embed(&self, segment: &Segment) -> Res<Option<Vec<f32>>> {
+        let features = self
+            .frontend
+            .compute_fbank_features(&mut segment.data.clone())
+            .map_err(|e| Error::msg(e.to_string()))?;
+        if features.dim(0)? < MIN_FRAMES {
+            return Ok(None);
+        }
+
+        let features = features.broadcast_sub(&features.mean_keepdim(0)?)?;
+        let embedding = self
+            .model
+            .forward(&features.to_device(&self.device)?.unsqueeze(0)?)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?;
+
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        Ok(Some(
+            embedding
+                .into_iter()
+                .map(|x| x / norm.max(f32::EPSILON))
+                .collect(),
+        ))
+    }
+
+    /// Speakers of segments as they arrive, ids stay the same for the session
+    ///
+    /// Segments too short to embed get None, as do short segments unlike any
+    /// known speaker.
+    pub fn assign(&mut self, segments: &[Segment]) -> Res<Vec<Option<u32>>> {
+        let mut out = Vec::with_capacity(segments.len());
+        for segment in segments {
+            let embedding = self.embed(segment)?;
+            let long = segment.end - segment.start >= self.min_segment_ms;
+            let speaker = embedding
+                .as_deref()
+                .and_then(|e| self.clustering.assign(e, long));
+            out.push(speaker);
+            self.history.push(SegmentEmbedding { embedding, long });
+        }
+
+        Ok(out)
+    }
+
+    /// Speakers of every segment assigned since the last reset, clustered
+    /// again with all of them known
+    ///
+    /// Online ids depend on the order segments arrived in, this pass does not
+    /// and may merge or split speakers, so its ids can differ.
+    pub fn recluster(&self) -> Vec<Option<u32>> {
+        recluster(&self.history, self.threshold)
+    }
+
+    /// Speakers of all segments of a file, clustered offline
+    ///
+    /// Starts a new session, see [`Self::reset`].
+    pub fn diarize(&mut self, segments: &[Segment]) -> Res<Vec<Option<u32>>> {
+        self.reset();
+        self.assign(segments)?;
+        Ok(self.recluster())
+    }
+
+    /// Forget the speakers of the session
+    pub fn reset(&mut self) {
+        self.clustering.clear();
+        self.history.clear();
+    }
+}
+
+impl ConfigRefresher<SpeakerConfig> for Diarizer {
+    fn refresh(&mut self, old: &SpeakerConfig, new: &SpeakerConfig) -> Res<()> {
+        if old.threshold != new.threshold {
+            event!(Level::DEBUG, "Refreshing speaker threshold");
+            self.threshold = new.threshold;
+            self.clustering.set_threshold(new.threshold);
+        }
+
+        if old.min_segment_ms != new.min_segment_ms {
+            event!(Level::DEBUG, "Refreshing minimum speaker segment length");
+            self.min_segment_ms = new.min_segment_ms;
+        }
+
+        Ok(())
+    }
+}
+
+/// Labels of a session history, see [`Diarizer::recluster`]
+fn recluster(history: &[SegmentEmbedding], threshold: f32) -> Vec<Option<u32>> {
+    let long = history
+        .iter()
+        .filter(|s| s.long)
+        .filter_map(|s| s.embedding.as_deref())
+        .collect::<Vec<_>>();
+    let labels = cluster(&long, threshold);
+
+    let speakers = labels.iter().max().map_or(0, |&l| l as usize + 1);
+    let mut centroids = vec![vec![0f32; EMBEDDING_SIZE]; speakers];
+    for (embedding, &label) in long.iter().zip(&labels) {
+        let centroid = &mut centroids[label as usize];
+        centroid
+            .iter_mut()
+            .zip(*embedding)
+            .for_each(|(c, e)| *c += e);
+    }
+    // Short segments only join the speakers found from the long ones
+    let mut matcher = OnlineClustering::with_centroids(threshold, centroids);
+
+    let mut labels = labels.into_iter();
+    history
+        .iter()
+        .map(|s| match (&s.embedding, s.long) {
+            (Some(_), true) => labels.next(),
+            (Some(embedding), false) => matcher.assign(embedding, false),
+            (None, _) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unit embedding along `axis`, tilted towards the next axis by `tilt`
+    fn embedding(axis: usize, tilt: f32) -> Vec<f32> {
+        let mut e = vec![0f32; EMBEDDING_SIZE];
+        e[axis] = 1.0;
+        e[axis + 1] = tilt;
+        e
+    }
+
+    fn segment(embedding: Option<Vec<f32>>, long: bool) -> SegmentEmbedding {
+        SegmentEmbedding { embedding, long }
+    }
+
+    #[test]
+    fn recluster_labels_in_order_of_first_segment() {
+        let history = [
+            segment(Some(embedding(10, 0.1)), true),
+            segment(Some(embedding(0, 0.0)), true),
+            segment(None, true),
+            segment(Some(embedding(0, 0.2)), false),
+            segment(Some(embedding(10, 0.0)), true),
+            // Short and unlike every long segment
+            segment(Some(embedding(20, 0.0)), false),
+            segment(Some(embedding(0, 0.1)), true),
+        ];
+        assert_eq!(
+            recluster(&history, 0.7),
+            [Some(0), Some(1), None, Some(1), Some(0), None, Some(1)]
+        );
+        assert!(recluster(&[], 0.7).is_empty());
+    }
+}
@@ -1 +1,29 @@
 pub mod modelscope;
+
+use crate::Res;
+use candle_core::Device;
+
+/// Prefer CUDA, then Metal, when `use_gpu` is set and fall back to the CPU
+pub fn select_device(use_gpu: bool) -> Res<Device> {
+    if use_gpu {
+        if candle_core::utils::cuda_is_available() {
+            return Ok(Device::new_cuda(0)?);
+        }
+        if candle_core::utils::metal_is_available() {
+            return Ok(Device::new_metal(0)?);
+        }
+    }
+    Ok(Device::Cpu)
+}
+
+/// CJK ideographs, kana and hangul are words of their own
+pub(crate) fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // Hiragana, Katakana
+        | 0x3400..=0x4DBF // CJK Extension A
+        | 0x4E00..=0x9FFF // CJK Unified Ideographs
+        | 0xAC00..=0xD7AF // Hangul Syllables
+        | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+        | 0x20000..=0x2FA1F // CJK Extension B and later
+    )
+}
@@ -48,6 +48,8 @@ pub struct RepoFile {
 
 #[derive(Serialize, Clone)]
 pub struct FileInfo {
+    /// Repository the file is downloaded from
+    model_id: String,
     name: String,
     path: String,
     size: u64,
@@ -75,6 +77,10 @@ impl ModelScopeRepo {
         }
     }
 
+    pub fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
     pub async fn get(&self, file: &str) -> Res<PathBuf> {
         let file_path = self.save_dir.join(file);
         if file_path.exists() {
@@ -97,6 +103,7 @@ impl ModelScopeRepo {
         };
 
         Ok(FileInfo {
+            model_id: self.model_id.clone(),
             name: repo_file.name,
             path: repo_file.path,
             size: repo_file.size,
@@ -107,6 +114,20 @@ impl ModelScopeRepo {
         })
     }
 
+    /// File info of each of `files`, in order
+    pub async fn get_files_info(&self, files: &[&str]) -> Res<Vec<FileInfo>> {
+        let mut infos = Vec::with_capacity(files.len());
+        for file in files {
+            infos.push(self.get_file_info(file).await?);
+        }
+        Ok(infos)
+    }
+
+    /// Whether all of `files` are downloaded, without asking the repository
+    pub fn has_files(&self, files: &[&str]) -> bool {
+        files.iter().all(|file| self.save_dir.join(file).exists())
+    }
+
     /// Set repo files manually in offline mode
     pub async fn set_repo_files(&self, repo_files: Vec<RepoFile>) {
         self.repo_files.write().await.replace(repo_files);
@@ -1,26 +1,65 @@
 use crate::{Res, quantized_nn, quantized_var_builder};
 use VarBuilder::{Normal, Quantiled};
-use anyhow::Error;
-use candle_core::{DType, Device, Module, Var};
-use candle_nn::{Conv1d, Conv1dConfig, LayerNorm, LayerNormConfig, VarMap, init, var_builder};
+use anyhow::{Error, bail};
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device, Module, Tensor};
+use candle_nn::{
+    BatchNorm, BatchNormConfig, Conv1d, Conv1dConfig, Conv2d, Conv2dConfig, Embedding,
+    LayerNormConfig, init, var_builder,
+};
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
 use std::path::Path;
 
 pub type Linear = Box<dyn Module + Send + Sync>;
 
+/// Layer norm computed in f32 whatever the input dtype, half precision
+/// variance is not stable enough
+pub struct LayerNorm(candle_nn::LayerNorm);
+
+impl Module for LayerNorm {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let dtype = xs.dtype();
+        self.0.forward(&xs.to_dtype(DType::F32)?)?.to_dtype(dtype)
+    }
+}
+
 pub enum VarBuilder<'a> {
     Normal(var_builder::VarBuilder<'a>),
     Quantiled(quantized_var_builder::VarBuilder),
 }
 
 impl<'a> VarBuilder<'a> {
-    pub fn from_file<P: AsRef<Path>>(path: P, device: &Device) -> Res<VarBuilder<'a>> {
+    /// Load a checkpoint, float tensors of `.pt` and safetensors files are
+    /// converted to `dtype`
+    ///
+    /// Sharded safetensors are loaded from their `*.safetensors.index.json`.
+    /// GGUF tensors are dequantized to f32 whatever `dtype` is. Only headers
+    /// are read here, tensors are read when they are requested, safetensors
+    /// and GGUF files are memory mapped.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        dtype: DType,
+        device: &Device,
+    ) -> Res<VarBuilder<'a>> {
         let path = path.as_ref();
         let ext = path
             .extension()
             .ok_or_else(|| Error::msg("No extension found"))?;
 
         if ext == "bin" || ext == "pt" {
-            return Self::from_pt(path, device);
+            let vb = candle_nn::VarBuilder::from_pth(path, dtype, device)?;
+            return Ok(Normal(vb));
+        }
+
+        if ext == "safetensors" {
+            return Self::from_safetensors(&[path], dtype, device);
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".safetensors.index.json") {
+            return Self::from_safetensors_index(path, dtype, device);
         }
 
         if ext == "gguf" {
@@ -31,21 +70,35 @@ impl<'a> VarBuilder<'a> {
         Err(Error::msg("Unsupported file extension"))
     }
 
-    fn from_pt(path: &Path, device: &Device) -> Res<Self> {
-        let tensors = candle_core::pickle::read_all(path)?;
-        let vm = VarMap::new();
+    /// Load the shards listed in the `weight_map` of a safetensors index
+    fn from_safetensors_index(path: &Path, dtype: DType, device: &Device) -> Res<Self> {
+        #[derive(Deserialize)]
+        struct Index {
+            weight_map: HashMap<String, String>,
+        }
+
+        let index: Index = serde_json::from_slice(&fs::read(path)?)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let shards = index
+            .weight_map
+            .values()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|shard| dir.join(shard))
+            .collect::<Vec<_>>();
 
-        {
-            let mut vm_data_map = vm.data().lock().map_err(|e| Error::msg(e.to_string()))?;
-            for (name, tensor) in tensors.iter() {
-                vm_data_map.insert(
-                    String::from(name),
-                    Var::from_tensor(&tensor.to_device(device)?)?,
-                );
-            }
+        let vb = Self::from_safetensors(&shards, dtype, device)?;
+        if let Some(name) = index.weight_map.keys().find(|n| !vb.contains_tensor(n)) {
+            bail!("Tensor {} is missing from its shard", name);
         }
-        let vb = candle_nn::VarBuilder::from_varmap(&vm, DType::F32, device);
 
+        Ok(vb)
+    }
+
+    fn from_safetensors<P: AsRef<Path>>(paths: &[P], dtype: DType, device: &Device) -> Res<Self> {
+        // SAFETY: the files are opened read only, like every mmap they must not
+        // be truncated while the model is loading
+        let vb = unsafe { candle_nn::VarBuilder::from_mmaped_safetensors(paths, dtype, device)? };
         Ok(Normal(vb))
     }
 
@@ -62,6 +115,39 @@ impl<'a> VarBuilder<'a> {
             Quantiled(vb) => vb.contains_tensor(name),
         }
     }
+
+    /// Full dotted name of a tensor under the current prefix
+    pub fn path(&self, name: &str) -> String {
+        match self {
+            Normal(vb) => {
+                let prefix = vb.prefix();
+                if prefix.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{prefix}.{name}")
+                }
+            }
+            Quantiled(vb) => vb.path(name),
+        }
+    }
+
+    /// Load an embedding table, the weight must exist in the checkpoint
+    pub fn embedding(self, num_embeddings: usize, dim: usize) -> Res<Embedding> {
+        // VarMap backed builders silently initialize missing tensors, which
+        // would hand out random embeddings instead of failing.
+        if !self.contains_tensor("weight") {
+            bail!("Tensor `{}` not found in checkpoint", self.path("weight"));
+        }
+
+        let weight = match self {
+            Normal(vb) => vb.get((num_embeddings, dim), "weight")?,
+            Quantiled(vb) => vb
+                .get((num_embeddings, dim), "weight")?
+                .dequantize(vb.device())?,
+        };
+
+        Ok(Embedding::new(weight, dim))
+    }
     pub fn linear(self, in_dim: usize, out_dim: usize) -> Res<Linear> {
         match self {
             Normal(vb) => Ok(Box::new(candle_nn::linear(in_dim, out_dim, vb)?)),
@@ -69,13 +155,31 @@ impl<'a> VarBuilder<'a> {
         }
     }
 
+    pub fn linear_no_bias(self, in_dim: usize, out_dim: usize) -> Res<Linear> {
+        match self {
+            Normal(vb) => Ok(Box::new(candle_nn::linear_no_bias(in_dim, out_dim, vb)?)),
+            Quantiled(vb) => Ok(Box::new(quantized_nn::linear_no_bias(in_dim, out_dim, vb)?)),
+        }
+    }
+
+    /// Layer norm with f32 weights, see [`LayerNorm`]
     pub fn layer_norm<C: Into<LayerNormConfig>>(self, size: usize, config: C) -> Res<LayerNorm> {
         let out = match self {
-            Normal(vb) => candle_nn::layer_norm(size, config.into(), vb)?,
+            Normal(vb) => {
+                let config = config.into();
+                let norm = candle_nn::layer_norm(size, config, vb)?;
+                let weight = norm.weight().to_dtype(DType::F32)?;
+                match norm.bias() {
+                    Some(bias) => {
+                        candle_nn::LayerNorm::new(weight, bias.to_dtype(DType::F32)?, config.eps)
+                    }
+                    None => candle_nn::LayerNorm::new_no_bias(weight, config.eps),
+                }
+            }
             Quantiled(vb) => quantized_nn::layer_norm(size, config.into().eps, vb)?,
         };
 
-        Ok(out)
+        Ok(LayerNorm(out))
     }
 
     pub fn conv1d_no_bias(
@@ -129,6 +233,90 @@ impl<'a> VarBuilder<'a> {
         Ok(out)
     }
 
+    pub fn conv1d(
+        self,
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        cfg: Conv1dConfig,
+    ) -> Res<Conv1d> {
+        let shape = (out_channels, in_channels / cfg.groups, kernel_size);
+        let out = match self {
+            Normal(vb) => candle_nn::conv1d(in_channels, out_channels, kernel_size, cfg, vb)?,
+            Quantiled(vb) => {
+                let weight = vb.get(shape, "weight")?.dequantize(vb.device())?;
+                let bias = vb.get(out_channels, "bias")?.dequantize(vb.device())?;
+                Conv1d::new(weight, Some(bias), cfg)
+            }
+        };
+
+        Ok(out)
+    }
+
+    pub fn conv2d_no_bias(
+        self,
+        in_channels: usize,
+        out_channels: usize,
+        kernel_size: usize,
+        cfg: Conv2dConfig,
+    ) -> Res<Conv2d> {
+        let shape = (
+            out_channels,
+            in_channels / cfg.groups,
+            kernel_size,
+            kernel_size,
+        );
+        let out = match self {
+            Normal(vb) => {
+                candle_nn::conv2d_no_bias(in_channels, out_channels, kernel_size, cfg, vb)?
+            }
+            Quantiled(vb) => {
+                let weight = vb.get(shape, "weight")?.dequantize(vb.device())?;
+                Conv2d::new(weight, None, cfg)
+            }
+        };
+
+        Ok(out)
+    }
+
+    /// Batch norm with its running statistics, applied with
+    /// `forward_t(x, false)`
+    pub fn batch_norm<C: Into<BatchNormConfig>>(self, size: usize, config: C) -> Res<BatchNorm> {
+        let config = config.into();
+        let out = match self {
+            Normal(vb) => candle_nn::batch_norm(size, config, vb)?,
+            Quantiled(vb) => {
+                let get = |name: &str| -> Res<Tensor> {
+                    Ok(vb.get(size, name)?.dequantize(vb.device())?)
+                };
+                let (mean, var) = (get("running_mean")?, get("running_var")?);
+                if config.affine {
+                    BatchNorm::new(size, mean, var, get("weight")?, get("bias")?, config.eps)?
+                } else {
+                    BatchNorm::new_no_bias(size, mean, var, config.eps)?
+                }
+            }
+        };
+
+        Ok(out)
+    }
+
+    /// Metadata of GGUF checkpoints, None for other formats
+    pub fn gguf_metadata(&self) -> Option<&HashMap<String, gguf_file::Value>> {
+        match self {
+            Normal(_) => None,
+            Quantiled(vb) => Some(vb.metadata()),
+        }
+    }
+
+    /// Dtype of the loaded weights, and so of the activations
+    pub fn dtype(&self) -> DType {
+        match self {
+            Normal(vb) => vb.dtype(),
+            Quantiled(_) => DType::F32,
+        }
+    }
+
     pub fn device(&self) -> &Device {
         match self {
             Normal(vb) => vb.device(),
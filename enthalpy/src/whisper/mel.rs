@@ -0,0 +1,170 @@
+use crate::Res;
+use candle_core::{Device, Tensor};
+use std::f64::consts::PI;
+
+/// Sample rate Whisper is trained on
+pub const SAMPLE_RATE: usize = 16000;
+
+/// Audio the encoder sees at once, shorter input is padded with silence
+pub const CHUNK_SAMPLES: usize = 30 * SAMPLE_RATE;
+
+const N_FFT: usize = 400;
+
+const HOP_LENGTH: usize = 160;
+
+/// Spectrogram frames of a chunk, the encoder halves them
+pub const N_FRAMES: usize = CHUNK_SAMPLES / HOP_LENGTH;
+
+/// Log-mel spectrogram as computed by `whisper.audio.log_mel_spectrogram`
+///
+/// The STFT is a matmul of the framed audio with a windowed DFT basis, the
+/// mel filters are librosa's slaney filters.
+pub struct LogMel {
+    /// Hann windowed cosine and sine terms (n_fft, 2 * bins)
+    basis: Tensor,
+    /// Mel filterbank (bins, n_mels)
+    filters: Tensor,
+}
+
+impl LogMel {
+    pub fn new(n_mels: usize) -> Res<Self> {
+        let bins = N_FFT / 2 + 1;
+
+        let mut basis = vec![0f32; N_FFT * 2 * bins];
+        for n in 0..N_FFT {
+            let window = 0.5 - 0.5 * (2.0 * PI * n as f64 / N_FFT as f64).cos();
+            for k in 0..bins {
+                let angle = 2.0 * PI * (k * n % N_FFT) as f64 / N_FFT as f64;
+                basis[n * 2 * bins + k] = (window * angle.cos()) as f32;
+                basis[n * 2 * bins + bins + k] = (window * angle.sin()) as f32;
+            }
+        }
+
+        let filters = mel_filters(n_mels, bins);
+
+        Ok(Self {
+            basis: Tensor::from_vec(basis, (N_FFT, 2 * bins), &Device::Cpu)?,
+            filters: Tensor::from_vec(filters, (bins, n_mels), &Device::Cpu)?,
+        })
+    }
+
+    /// Spectrogram of up to 30 s of audio (n_mels, 3000)
+    pub fn compute(&self, samples: &[f32]) -> Res<Tensor> {
+        let samples = &samples[..samples.len().min(CHUNK_SAMPLES)];
+
+        // Center the frames with reflect padding, like torch.stft
+        let pad = N_FFT / 2;
+        let mut padded = Vec::with_capacity(CHUNK_SAMPLES + 2 * pad);
+        let mut audio = samples.to_vec();
+        audio.resize(CHUNK_SAMPLES, 0.0);
+        padded.extend(audio[1..=pad].iter().rev());
+        padded.extend_from_slice(&audio);
+        padded.extend(
+            audio[CHUNK_SAMPLES - pad - 1..CHUNK_SAMPLES - 1]
+                .iter()
+                .rev(),
+        );
+
+        // The last STFT frame is dropped by Whisper
+        let mut frames = Vec::with_capacity(N_FRAMES * N_FFT);
+        for i in 0..N_FRAMES {
+            frames.extend_from_slice(&padded[i * HOP_LENGTH..i * HOP_LENGTH + N_FFT]);
+        }
+        let frames = Tensor::from_vec(frames, (N_FRAMES, N_FFT), &Device::Cpu)?;
+
+        let bins = N_FFT / 2 + 1;
+        let spectrum = frames.matmul(&self.basis)?;
+        let power =
+            (spectrum.narrow(1, 0, bins)?.sqr()? + spectrum.narrow(1, bins, bins)?.sqr()?)?;
+        let mel = power.matmul(&self.filters)?;
+
+        let log_spec = (mel.clamp(1e-10f32, f32::MAX)?.log()? / 10f64.ln())?;
+        let max = log_spec.max_all()?.to_scalar::<f32>()?;
+        let log_spec = log_spec.clamp(max - 8.0, f32::MAX)?;
+        let log_spec = ((log_spec + 4.0)? / 4.0)?;
+
+        Ok(log_spec.t()?.contiguous()?)
+    }
+}
+
+/// `librosa.filters.mel(sr=16000, n_fft=400, n_mels)`, transposed to (bins, n_mels)
+fn mel_filters(n_mels: usize, bins: usize) -> Vec<f32> {
+    let max_mel = hz_to_mel(SAMPLE_RATE as f64 / 2.0);
+    let mel_f = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (n_mels + 1) as f64))
+        .collect::<Vec<_>>();
+
+    let mut filters = vec![0f32; bins * n_mels];
+    for m in 0..n_mels {
+        // Slaney normalization, each filter has the same area
+        let norm = 2.0 / (mel_f[m + 2] - mel_f[m]);
+        for k in 0..bins {
+            let freq = (k * SAMPLE_RATE) as f64 / N_FFT as f64;
+            let lower = (freq - mel_f[m]) / (mel_f[m + 1] - mel_f[m]);
+            let upper = (mel_f[m + 2] - freq) / (mel_f[m + 2] - mel_f[m + 1]);
+            filters[k * n_mels + m] = (lower.min(upper).max(0.0) * norm) as f32;
+        }
+    }
+    filters
+}
+
+/// Slaney mel scale, linear below 1 kHz and logarithmic above
+const MIN_LOG_HZ: f64 = 1000.0;
+const HZ_PER_MEL: f64 = 200.0 / 3.0;
+
+fn log_step() -> f64 {
+    6.4f64.ln() / 27.0
+}
+
+fn hz_to_mel(hz: f64) -> f64 {
+    let min_log_mel = MIN_LOG_HZ / HZ_PER_MEL;
+    if hz >= MIN_LOG_HZ {
+        min_log_mel + (hz / MIN_LOG_HZ).ln() / log_step()
+    } else {
+        hz / HZ_PER_MEL
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    let min_log_mel = MIN_LOG_HZ / HZ_PER_MEL;
+    if mel >= min_log_mel {
+        MIN_LOG_HZ * (log_step() * (mel - min_log_mel)).exp()
+    } else {
+        mel * HZ_PER_MEL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mel_scale_matches_librosa() {
+        // librosa.hz_to_mel(..., htk=False)
+        assert!((hz_to_mel(440.0) - 6.6).abs() < 1e-9);
+        assert!((hz_to_mel(1000.0) - 15.0).abs() < 1e-9);
+        assert!((hz_to_mel(8000.0) - 45.245640471924965).abs() < 1e-9);
+        assert!((mel_to_hz(hz_to_mel(3000.0)) - 3000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn mel_filters_match_librosa() {
+        let (n_mels, bins) = (80, N_FFT / 2 + 1);
+        let filters = mel_filters(n_mels, bins);
+        let at = |bin: usize, mel: usize| filters[bin * n_mels + mel];
+
+        // librosa.filters.mel(sr=16000, n_fft=400, n_mels=80)[mel, bin]
+        let expected = [
+            (0, 1, 0.024862594),
+            (0, 2, 0.0),
+            (40, 42, 0.005411105),
+            (40, 43, 0.014735566),
+            (40, 44, 0.00651819),
+            (79, 196, 0.001795036),
+        ];
+        for (mel, bin, value) in expected {
+            assert!((at(bin, mel) - value).abs() < 1e-7, "mel {mel} bin {bin}");
+        }
+        assert_eq!((0..bins).filter(|&bin| at(bin, 40) > 0.0).count(), 3);
+    }
+}
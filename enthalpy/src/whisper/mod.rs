@@ -0,0 +1,385 @@
+//! Whisper speech recognition
+//!
+//! Multilingual tiny, base and small checkpoints in the Hugging Face layout
+//! (`config.json`, `model.safetensors`, `tokenizer.json`). Segments are
+//! decoded greedily without timestamps, so tokens span their segment.
+
+use crate::Res;
+use crate::audio::segmenter::Segmenter;
+use crate::audio::silero_vad::{Segment, VadConfig};
+use crate::config::ConfigRefresher;
+use crate::recognizer::{SpeechRecognizer, transpose_each};
+use crate::sense_voice_small::{Hypothesis, Tags, Token};
+use crate::util::modelscope::{FileInfo, ModelScopeRepo};
+use crate::util::select_device;
+use crate::var_builder::VarBuilder;
+use anyhow::bail;
+use candle_core::{D, DType, Device, Tensor};
+use mel::{CHUNK_SAMPLES, LogMel, SAMPLE_RATE};
+use model::{AudioEncoder, ModelConfig, TextDecoder, argmax};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokenizer::Tokenizer;
+use tracing::{Level, event};
+
+mod mel;
+mod model;
+mod tokenizer;
+
+/// Config, weights and tokenizer of each checkpoint size
+const MODEL_FILES: [&str; 3] = ["config.json", "model.safetensors", "tokenizer.json"];
+
+/// Language value that detects the language of every segment
+const AUTO: &str = "auto";
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WhisperConfig {
+    pub model_dir: PathBuf,
+    pub size: WhisperSize,
+    pub vad: VadConfig,
+    pub resample: Option<(u32, u32)>,
+    pub use_gpu: bool,
+    /// Code such as `de` or `fr`, `auto` detects the language of each segment
+    pub language: String,
+}
+
+impl Default for WhisperConfig {
+    fn default() -> Self {
+        Self {
+            model_dir: PathBuf::default(),
+            size: WhisperSize::default(),
+            vad: VadConfig::default(),
+            resample: None,
+            use_gpu: false,
+            language: AUTO.to_string(),
+        }
+    }
+}
+
+/// Multilingual Whisper checkpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum WhisperSize {
+    Tiny,
+    #[default]
+    Base,
+    Small,
+}
+
+impl WhisperSize {
+    /// ModelScope mirror of the `openai/whisper-*` Hugging Face repository
+    pub fn model_id(&self) -> &'static str {
+        match self {
+            WhisperSize::Tiny => "openai-mirror/whisper-tiny",
+            WhisperSize::Base => "openai-mirror/whisper-base",
+            WhisperSize::Small => "openai-mirror/whisper-small",
+        }
+    }
+}
+
+pub struct Whisper {
+    device: Device,
+    segmenter: Segmenter,
+    mel: LogMel,
+    encoder: AudioEncoder,
+    decoder: TextDecoder,
+    tokenizer: Tokenizer,
+    /// Fixed language token, None detects it
+    language: Option<u32>,
+    /// Added to the logits, -inf for special tokens other than end of text
+    suppress: Tensor,
+    /// Most text tokens of one 30 s chunk
+    max_tokens: usize,
+}
+
+impl Whisper {
+    pub async fn with_config(cfg: WhisperConfig) -> Res<Self> {
+        let device = select_device(cfg.use_gpu)?;
+        Self::new(cfg, &device).await
+    }
+
+    pub async fn new(cfg: WhisperConfig, device: &Device) -> Res<Self> {
+        let device = device.clone();
+        let repo = Self::model_repo(&cfg.model_dir, cfg.size).await;
+
+        let start = Instant::now();
+        let config: ModelConfig =
+            serde_json::from_reader(File::open(repo.get("config.json").await?)?)?;
+        let tokenizer = Tokenizer::new(&repo.get("tokenizer.json").await?)?;
+        let weight_file = repo.get("model.safetensors").await?;
+        let vb = VarBuilder::from_file(&weight_file, DType::F32, &device)?;
+        let encoder = AudioEncoder::new(&config, vb.pp("model.encoder"))?;
+        let decoder = TextDecoder::new(&config, vb.pp("model.decoder"))?;
+
+        let language = Self::init_language(&tokenizer, &cfg.language)?;
+        let suppress = (0..config.vocab_size as u32)
+            .map(|id| {
+                if id > tokenizer.eot {
+                    f32::NEG_INFINITY
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<_>>();
+        let suppress = Tensor::from_vec(suppress, (1, config.vocab_size), &device)?;
+        event!(
+            Level::INFO,
+            "Loaded {} in {:.2?}",
+            weight_file.display(),
+            start.elapsed()
+        );
+
+        Ok(Self {
+            device,
+            segmenter: Segmenter::new(cfg.resample, &cfg.vad)?,
+            mel: LogMel::new(config.num_mel_bins)?,
+            encoder,
+            decoder,
+            tokenizer,
+            language,
+            suppress,
+            max_tokens: config.max_target_positions / 2,
+        })
+    }
+
+    pub async fn model_repo<P: Into<PathBuf>>(model_dir: P, size: WhisperSize) -> ModelScopeRepo {
+        ModelScopeRepo::new(size.model_id(), model_dir.into())
+    }
+
+    pub async fn get_required_files<P: Into<PathBuf>>(
+        model_dir: P,
+        size: WhisperSize,
+    ) -> Res<Vec<FileInfo>> {
+        let repo = Self::model_repo(model_dir, size).await;
+        repo.get_files_info(&MODEL_FILES).await
+    }
+
+    pub async fn check_required_files<P: Into<PathBuf>>(model_dir: P, size: WhisperSize) -> bool {
+        Self::model_repo(model_dir, size)
+            .await
+            .has_files(&MODEL_FILES)
+    }
+
+    fn init_language(tokenizer: &Tokenizer, language: &str) -> Res<Option<u32>> {
+        if language == AUTO {
+            return Ok(None);
+        }
+        match tokenizer.language(language) {
+            Some(token) => Ok(Some(token)),
+            None => bail!("Whisper does not know the language {}", language),
+        }
+    }
+
+    pub fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        self.segmenter.segment(waveform)
+    }
+
+    pub fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        transpose_each(segments, |data| self.process(data))
+    }
+
+    pub fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        let mut segment = self.segmenter.samples();
+        transpose_each(segment.as_mut_slice(), |data| self.process(data))
+    }
+
+    /// Decode a segment in 30 s chunks, token timing is relative to it
+    fn process(&mut self, samples: &[f32]) -> Res<Hypothesis> {
+        let mut hyp = Hypothesis {
+            tokens: Vec::new(),
+            tags: Tags::default(),
+            score: 0.0,
+            keywords: Vec::new(),
+            words: Vec::new(),
+        };
+
+        for (i, chunk) in samples.chunks(CHUNK_SAMPLES).enumerate() {
+            let start = (i * CHUNK_SAMPLES * 1000 / SAMPLE_RATE) as u32;
+            let end = start + (chunk.len() * 1000 / SAMPLE_RATE) as u32;
+
+            let mel = self
+                .mel
+                .compute(chunk)?
+                .unsqueeze(0)?
+                .to_device(&self.device)?;
+            let audio = self.encoder.forward(&mel)?;
+            let decoded = self.decode(&audio)?;
+
+            hyp.tags.set_language(&decoded.language);
+            hyp.score += decoded.score;
+            hyp.tokens
+                .extend(self.to_tokens(&decoded.tokens, start, end));
+        }
+
+        Ok(hyp)
+    }
+
+    /// Greedy decoding of one chunk
+    fn decode(&mut self, audio: &Tensor) -> Res<Decoded> {
+        self.decoder.reset();
+
+        let sot = Tensor::new(&[[self.tokenizer.sot]], &self.device)?;
+        let mut logits = self.decoder.forward(&sot, audio, 0)?;
+        let language = match self.language {
+            Some(language) => language,
+            None => self.detect_language(&logits)?,
+        };
+
+        let prompt = [
+            language,
+            self.tokenizer.transcribe,
+            self.tokenizer.no_timestamps,
+        ];
+        let mut offset = 1;
+        logits = self
+            .decoder
+            .forward(&Tensor::new(&[prompt], &self.device)?, audio, offset)?;
+        offset += prompt.len();
+
+        let mut tokens = Vec::new();
+        let mut score = 0.0;
+        while tokens.len() < self.max_tokens {
+            let log_probs = candle_nn::ops::log_softmax(&(logits + &self.suppress)?, D::Minus1)?;
+            let token = argmax(&log_probs)?;
+            let log_prob = log_probs
+                .squeeze(0)?
+                .get(token as usize)?
+                .to_scalar::<f32>()?;
+            if token == self.tokenizer.eot {
+                break;
+            }
+            tokens.push((token, log_prob));
+            score += log_prob;
+
+            logits =
+                self.decoder
+                    .forward(&Tensor::new(&[[token]], &self.device)?, audio, offset)?;
+            offset += 1;
+        }
+
+        let code = self
+            .tokenizer
+            .languages
+            .iter()
+            .find(|(id, _)| *id == language)
+            .map(|(_, code)| code.clone())
+            .unwrap_or_default();
+
+        Ok(Decoded {
+            language: code,
+            tokens,
+            score,
+        })
+    }
+
+    /// Most likely language token after the start of transcript
+    fn detect_language(&self, logits: &Tensor) -> Res<u32> {
+        let logits = logits.squeeze(0)?.to_vec1::<f32>()?;
+        let language = self
+            .tokenizer
+            .languages
+            .iter()
+            .map(|(id, _)| *id)
+            .max_by(|a, b| logits[*a as usize].total_cmp(&logits[*b as usize]));
+
+        match language {
+            Some(language) => Ok(language),
+            None => bail!("No language tokens in the tokenizer"),
+        }
+    }
+
+    /// Text tokens spanning `start..end`
+    ///
+    /// Tokens ending inside a UTF-8 character are merged with the following
+    /// ones, their confidence is the product of the merged tokens.
+    fn to_tokens(&self, tokens: &[(u32, f32)], start: u32, end: u32) -> Vec<Token> {
+        let mut out = Vec::with_capacity(tokens.len());
+        let mut bytes = Vec::new();
+        let mut log_prob = 0.0;
+        for (token, token_log_prob) in tokens {
+            bytes.extend_from_slice(self.tokenizer.bytes(*token));
+            log_prob += token_log_prob;
+            let Ok(text) = std::str::from_utf8(&bytes) else {
+                continue;
+            };
+            if !text.is_empty() {
+                out.push(Token {
+                    text: text.to_string(),
+                    start,
+                    end,
+                    tags: Tags::default(),
+                    confidence: log_prob.exp(),
+                    tokens: Vec::new(),
+                    words: Vec::new(),
+                    keywords: Vec::new(),
+                    speaker: None,
+                });
+            }
+            bytes.clear();
+            log_prob = 0.0;
+        }
+        out
+    }
+}
+
+/// Text of one chunk
+struct Decoded {
+    /// Language code, detected or configured
+    language: String,
+    /// Text tokens with their log probability
+    tokens: Vec<(u32, f32)>,
+    /// Log probability of the sequence
+    score: f32,
+}
+
+impl ConfigRefresher<WhisperConfig> for Whisper {
+    fn refresh(&mut self, old: &WhisperConfig, new: &WhisperConfig) -> Res<()> {
+        self.segmenter.refresh(new.resample, &new.vad)?;
+
+        if old.language != new.language {
+            event!(Level::DEBUG, "Refreshing language");
+            self.language = Self::init_language(&self.tokenizer, &new.language)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SpeechRecognizer for Whisper {
+    type Config = WhisperConfig;
+
+    async fn load(config: WhisperConfig) -> Res<Self> {
+        Self::with_config(config).await
+    }
+
+    async fn model_repo(config: &WhisperConfig) -> ModelScopeRepo {
+        Self::model_repo(&config.model_dir, config.size).await
+    }
+
+    async fn required_files(config: &WhisperConfig) -> Res<Vec<FileInfo>> {
+        Self::get_required_files(&config.model_dir, config.size).await
+    }
+
+    async fn check_required_files(config: &WhisperConfig) -> bool {
+        Self::check_required_files(&config.model_dir, config.size).await
+    }
+
+    fn needs_reload(old: &WhisperConfig, new: &WhisperConfig) -> bool {
+        old.model_dir != new.model_dir || old.size != new.size || old.use_gpu != new.use_gpu
+    }
+
+    fn segment(&mut self, waveform: &mut [f32]) -> Res<Vec<Segment>> {
+        Self::segment(self, waveform)
+    }
+
+    fn transpose(&mut self, segments: &mut [Segment]) -> Res<Vec<Token>> {
+        Self::transpose(self, segments)
+    }
+
+    fn transpose_vad_cache(&mut self) -> Res<Vec<Token>> {
+        Self::transpose_vad_cache(self)
+    }
+}
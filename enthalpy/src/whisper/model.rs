@@ -0,0 +1,311 @@
+use crate::Res;
+use crate::var_builder::{LayerNorm, Linear, VarBuilder};
+use candle_core::{D, DType, Device, Module, Tensor};
+use candle_nn::Conv1dConfig;
+use serde::Deserialize;
+
+const LAYER_NORM_EPS: f64 = 1e-5;
+
+/// Hyperparameters from the `config.json` of a Hugging Face Whisper checkpoint
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelConfig {
+    pub num_mel_bins: usize,
+    pub d_model: usize,
+    pub encoder_layers: usize,
+    pub encoder_attention_heads: usize,
+    pub encoder_ffn_dim: usize,
+    pub decoder_layers: usize,
+    pub decoder_attention_heads: usize,
+    pub decoder_ffn_dim: usize,
+    /// Encoder frames of 30 s of audio
+    pub max_source_positions: usize,
+    /// Longest token sequence of the decoder, prompt included
+    pub max_target_positions: usize,
+    pub vocab_size: usize,
+}
+
+struct MultiHeadAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    out: Linear,
+    n_head: usize,
+    /// Keep keys and values between calls, for incremental decoding
+    use_cache: bool,
+    cache: Option<(Tensor, Tensor)>,
+}
+
+impl MultiHeadAttention {
+    fn new(d_model: usize, n_head: usize, use_cache: bool, vb: VarBuilder) -> Res<Self> {
+        Ok(Self {
+            query: vb.pp("q_proj").linear(d_model, d_model)?,
+            key: vb.pp("k_proj").linear_no_bias(d_model, d_model)?,
+            value: vb.pp("v_proj").linear(d_model, d_model)?,
+            out: vb.pp("out_proj").linear(d_model, d_model)?,
+            n_head,
+            use_cache,
+            cache: None,
+        })
+    }
+
+    /// Attend `x` (batch, time, d_model) to itself, or to the encoder output
+    /// `xa` whose keys and values are computed once until `reset`
+    fn forward(&mut self, x: &Tensor, xa: Option<&Tensor>, mask: Option<&Tensor>) -> Res<Tensor> {
+        let q = self.query.forward(x)?;
+        let (k, v) = match (xa, &self.cache) {
+            (Some(_), Some(cache)) => cache.clone(),
+            (Some(xa), None) => (self.key.forward(xa)?, self.value.forward(xa)?),
+            (None, Some((k, v))) => (
+                Tensor::cat(&[k, &self.key.forward(x)?], 1)?,
+                Tensor::cat(&[v, &self.value.forward(x)?], 1)?,
+            ),
+            (None, None) => (self.key.forward(x)?, self.value.forward(x)?),
+        };
+        if self.use_cache {
+            self.cache = Some((k.clone(), v.clone()));
+        }
+
+        let out = self.attention(&q, &k, &v, mask)?;
+        Ok(self.out.forward(&out)?)
+    }
+
+    fn attention(&self, q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>) -> Res<Tensor> {
+        let (b, t, d) = q.dims3()?;
+        let head_dim = d / self.n_head;
+        let heads = |x: &Tensor| -> Res<Tensor> {
+            let t = x.dim(1)?;
+            Ok(x.reshape((b, t, self.n_head, head_dim))?
+                .transpose(1, 2)?
+                .contiguous()?)
+        };
+        let (q, k, v) = (heads(q)?, heads(k)?, heads(v)?);
+
+        let scale = (head_dim as f64).powf(-0.5);
+        let scores = ((q * scale)?.matmul(&k.t()?)?).to_dtype(DType::F32)?;
+        let scores = match mask {
+            Some(mask) => scores.broadcast_add(mask)?,
+            None => scores,
+        };
+        let weights = candle_nn::ops::softmax_last_dim(&scores)?.to_dtype(v.dtype())?;
+
+        Ok(weights.matmul(&v)?.transpose(1, 2)?.reshape((b, t, d))?)
+    }
+
+    fn reset(&mut self) {
+        self.cache = None;
+    }
+}
+
+struct ResidualAttentionBlock {
+    attn: MultiHeadAttention,
+    attn_ln: LayerNorm,
+    cross_attn: Option<(MultiHeadAttention, LayerNorm)>,
+    fc1: Linear,
+    fc2: Linear,
+    mlp_ln: LayerNorm,
+}
+
+impl ResidualAttentionBlock {
+    fn new(
+        cfg: &ModelConfig,
+        n_head: usize,
+        ffn_dim: usize,
+        decoder: bool,
+        vb: VarBuilder,
+    ) -> Res<Self> {
+        let d = cfg.d_model;
+        let attn = MultiHeadAttention::new(d, n_head, decoder, vb.pp("self_attn"))?;
+        let attn_ln = vb
+            .pp("self_attn_layer_norm")
+            .layer_norm(d, LAYER_NORM_EPS)?;
+        let cross_attn = if decoder {
+            let attn = MultiHeadAttention::new(d, n_head, true, vb.pp("encoder_attn"))?;
+            let ln = vb
+                .pp("encoder_attn_layer_norm")
+                .layer_norm(d, LAYER_NORM_EPS)?;
+            Some((attn, ln))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            attn,
+            attn_ln,
+            cross_attn,
+            fc1: vb.pp("fc1").linear(d, ffn_dim)?,
+            fc2: vb.pp("fc2").linear(ffn_dim, d)?,
+            mlp_ln: vb.pp("final_layer_norm").layer_norm(d, LAYER_NORM_EPS)?,
+        })
+    }
+
+    fn forward(&mut self, x: &Tensor, xa: Option<&Tensor>, mask: Option<&Tensor>) -> Res<Tensor> {
+        let x = (x + self.attn.forward(&self.attn_ln.forward(x)?, None, mask)?)?;
+        let x = match &mut self.cross_attn {
+            Some((attn, ln)) => (&x + attn.forward(&ln.forward(&x)?, xa, None)?)?,
+            None => x,
+        };
+        let mlp = self
+            .fc2
+            .forward(&self.fc1.forward(&self.mlp_ln.forward(&x)?)?.gelu_erf()?)?;
+        Ok((x + mlp)?)
+    }
+
+    fn reset(&mut self) {
+        self.attn.reset();
+        if let Some((attn, _)) = &mut self.cross_attn {
+            attn.reset();
+        }
+    }
+}
+
+pub struct AudioEncoder {
+    conv1: candle_nn::Conv1d,
+    conv2: candle_nn::Conv1d,
+    positional_embedding: Tensor,
+    blocks: Vec<ResidualAttentionBlock>,
+    ln_post: LayerNorm,
+}
+
+impl AudioEncoder {
+    pub fn new(cfg: &ModelConfig, vb: VarBuilder) -> Res<Self> {
+        let d = cfg.d_model;
+        let conv = |stride| Conv1dConfig {
+            padding: 1,
+            stride,
+            ..Conv1dConfig::default()
+        };
+        let conv1 = vb.pp("conv1").conv1d(cfg.num_mel_bins, d, 3, conv(1))?;
+        let conv2 = vb.pp("conv2").conv1d(d, d, 3, conv(2))?;
+        let positional_embedding = vb
+            .pp("embed_positions")
+            .embedding(cfg.max_source_positions, d)?
+            .embeddings()
+            .clone();
+        let blocks = (0..cfg.encoder_layers)
+            .map(|i| {
+                let vb = vb.pp("layers").pp(i);
+                ResidualAttentionBlock::new(
+                    cfg,
+                    cfg.encoder_attention_heads,
+                    cfg.encoder_ffn_dim,
+                    false,
+                    vb,
+                )
+            })
+            .collect::<Res<Vec<_>>>()?;
+        let ln_post = vb.pp("layer_norm").layer_norm(d, LAYER_NORM_EPS)?;
+
+        Ok(Self {
+            conv1,
+            conv2,
+            positional_embedding,
+            blocks,
+            ln_post,
+        })
+    }
+
+    /// Encode log-mel spectrograms (batch, n_mels, 3000) to (batch, 1500, d_model)
+    pub fn forward(&mut self, mel: &Tensor) -> Res<Tensor> {
+        let x = self.conv1.forward(mel)?.gelu_erf()?;
+        let x = self.conv2.forward(&x)?.gelu_erf()?;
+        let x = x.transpose(1, 2)?;
+        let positions = self.positional_embedding.narrow(0, 0, x.dim(1)?)?;
+        let mut x = x.broadcast_add(&positions)?;
+        for block in self.blocks.iter_mut() {
+            x = block.forward(&x, None, None)?;
+        }
+        Ok(self.ln_post.forward(&x)?)
+    }
+}
+
+pub struct TextDecoder {
+    token_embedding: Tensor,
+    positional_embedding: Tensor,
+    blocks: Vec<ResidualAttentionBlock>,
+    ln: LayerNorm,
+}
+
+impl TextDecoder {
+    pub fn new(cfg: &ModelConfig, vb: VarBuilder) -> Res<Self> {
+        let d = cfg.d_model;
+        let token_embedding = vb
+            .pp("embed_tokens")
+            .embedding(cfg.vocab_size, d)?
+            .embeddings()
+            .clone();
+        let positional_embedding = vb
+            .pp("embed_positions")
+            .embedding(cfg.max_target_positions, d)?
+            .embeddings()
+            .clone();
+        let blocks = (0..cfg.decoder_layers)
+            .map(|i| {
+                let vb = vb.pp("layers").pp(i);
+                ResidualAttentionBlock::new(
+                    cfg,
+                    cfg.decoder_attention_heads,
+                    cfg.decoder_ffn_dim,
+                    true,
+                    vb,
+                )
+            })
+            .collect::<Res<Vec<_>>>()?;
+        let ln = vb.pp("layer_norm").layer_norm(d, LAYER_NORM_EPS)?;
+
+        Ok(Self {
+            token_embedding,
+            positional_embedding,
+            blocks,
+            ln,
+        })
+    }
+
+    /// Logits (batch, vocab) of the token after `tokens` (batch, time)
+    ///
+    /// Keys and values of earlier calls are cached, `offset` is the number
+    /// of tokens already fed since the last `reset`.
+    pub fn forward(&mut self, tokens: &Tensor, xa: &Tensor, offset: usize) -> Res<Tensor> {
+        let t = tokens.dim(1)?;
+        let x = self.token_embedding.embedding(&tokens.flatten_all()?)?;
+        let x = x.reshape((tokens.dim(0)?, t, ()))?;
+        let positions = self.positional_embedding.narrow(0, offset, t)?;
+        let mut x = x.broadcast_add(&positions)?;
+
+        let mask = causal_mask(t, offset, x.device())?;
+        for block in self.blocks.iter_mut() {
+            x = block.forward(&x, Some(xa), mask.as_ref())?;
+        }
+
+        let x = self.ln.forward(&x.narrow(1, t - 1, 1)?.squeeze(1)?)?;
+        Ok(x.matmul(&self.token_embedding.t()?)?.to_dtype(DType::F32)?)
+    }
+
+    /// Forget the tokens and encoder output of the previous sequence
+    pub fn reset(&mut self) {
+        self.blocks.iter_mut().for_each(|block| block.reset());
+    }
+}
+
+/// Mask of `t` new tokens after `offset` cached ones, None for a single token
+fn causal_mask(t: usize, offset: usize, device: &Device) -> Res<Option<Tensor>> {
+    if t == 1 {
+        return Ok(None);
+    }
+    let mask = (0..t)
+        .flat_map(|i| {
+            (0..offset + t).map(move |j| {
+                if j > offset + i {
+                    f32::NEG_INFINITY
+                } else {
+                    0.0
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(Some(Tensor::from_vec(mask, (t, offset + t), device)?))
+}
+
+/// Index of the largest value of a single row of logits
+pub fn argmax(x: &Tensor) -> Res<u32> {
+    Ok(x.flatten_all()?.argmax(D::Minus1)?.to_scalar::<u32>()?)
+}
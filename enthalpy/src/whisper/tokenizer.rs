@@ -0,0 +1,153 @@
+use crate::Res;
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Byte level BPE vocabulary of a Hugging Face `tokenizer.json`
+///
+/// Only decoding is needed, the prompt is made of special tokens.
+pub struct Tokenizer {
+    /// Bytes of every token id, special tokens are empty
+    bytes: Vec<Vec<u8>>,
+    /// Special tokens such as `<|en|>` by content
+    special: HashMap<String, u32>,
+    /// `<|endoftext|>`, every id above it is a special token
+    pub eot: u32,
+    pub sot: u32,
+    pub transcribe: u32,
+    pub no_timestamps: u32,
+    /// Language tokens with their code, such as `(50261, "de")`
+    pub languages: Vec<(u32, String)>,
+}
+
+#[derive(Deserialize)]
+struct TokenizerJson {
+    model: BpeModel,
+    added_tokens: Vec<AddedToken>,
+}
+
+#[derive(Deserialize)]
+struct BpeModel {
+    vocab: HashMap<String, u32>,
+}
+
+#[derive(Deserialize)]
+struct AddedToken {
+    id: u32,
+    content: String,
+}
+
+impl Tokenizer {
+    pub fn new(path: &Path) -> Res<Self> {
+        let json: TokenizerJson = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+        let byte_of = byte_decoder();
+        let size = json
+            .model
+            .vocab
+            .values()
+            .chain(json.added_tokens.iter().map(|t| &t.id))
+            .max()
+            .map_or(0, |id| id + 1);
+        let mut bytes = vec![Vec::new(); size as usize];
+        for (token, id) in &json.model.vocab {
+            bytes[*id as usize] = token
+                .chars()
+                .filter_map(|c| byte_of.get(&c).copied())
+                .collect();
+        }
+
+        let special = json
+            .added_tokens
+            .into_iter()
+            .map(|t| (t.content, t.id))
+            .collect::<HashMap<_, _>>();
+        let get = |name: &str| {
+            special
+                .get(name)
+                .copied()
+                .ok_or_else(|| Error::msg(format!("Special token {name} not found")))
+        };
+
+        let eot = get("<|endoftext|>")?;
+        let sot = get("<|startoftranscript|>")?;
+        let translate = get("<|translate|>")?;
+        let transcribe = get("<|transcribe|>")?;
+        let no_timestamps = get("<|notimestamps|>")?;
+
+        // Language tokens sit between the start of transcript and the task tokens
+        let mut languages = special
+            .iter()
+            .filter(|(_, id)| **id > sot && **id < translate)
+            .filter_map(|(content, id)| {
+                let code = content.strip_prefix("<|")?.strip_suffix("|>")?;
+                Some((*id, code.to_string()))
+            })
+            .collect::<Vec<_>>();
+        languages.sort();
+
+        for id in special.values() {
+            bytes[*id as usize].clear();
+        }
+
+        Ok(Self {
+            bytes,
+            special,
+            eot,
+            sot,
+            transcribe,
+            no_timestamps,
+            languages,
+        })
+    }
+
+    /// Token of a language code such as `de`
+    pub fn language(&self, code: &str) -> Option<u32> {
+        self.special.get(&format!("<|{code}|>")).copied()
+    }
+
+    /// UTF-8 bytes of a token, empty for special tokens
+    pub fn bytes(&self, id: u32) -> &[u8] {
+        self.bytes.get(id as usize).map_or(&[], |b| b.as_slice())
+    }
+}
+
+/// Inverse of GPT-2's `bytes_to_unicode`
+///
+/// Printable bytes map to the same code point, the others to 256 and up in
+/// byte order.
+fn byte_decoder() -> HashMap<char, u8> {
+    let printable = |b: u8| (b'!'..=b'~').contains(&b) || (0xA1..=0xAC).contains(&b) || b >= 0xAE;
+    let mut decoder = HashMap::with_capacity(256);
+    let mut shifted = 256;
+    for b in 0..=255u8 {
+        let c = if printable(b) {
+            b as u32
+        } else {
+            shifted += 1;
+            shifted - 1
+        };
+        decoder.insert(char::from_u32(c).unwrap_or_default(), b);
+    }
+    decoder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_decoder_inverts_gpt2_bytes_to_unicode() {
+        let decoder = byte_decoder();
+        assert_eq!(decoder.len(), 256);
+        assert_eq!(decoder[&'a'], b'a');
+        assert_eq!(decoder[&'\u{A1}'], 0xA1);
+        assert_eq!(decoder[&'Ā'], 0x00);
+        assert_eq!(decoder[&'Ċ'], b'\n');
+        assert_eq!(decoder[&'Ġ'], b' ');
+        assert_eq!(decoder[&'Ń'], 0xAD);
+    }
+}
@@ -17,10 +17,10 @@ export async function checkRequiredFiles(modelDir: string): Promise<FileInfo[]>
     return await invoke<FileInfo[]>('get_required_files', { modelDir: modelDir })
 }
 
-export async function downloadRequiredFile(modelDir: string, fileName: string): Promise<void> {
-    return await invoke<void>('download_required_file', { modelDir: modelDir, fileName: fileName })
+export async function downloadRequiredFile(modelDir: string, modelId: string, fileName: string): Promise<void> {
+    return await invoke<void>('download_required_file', { modelDir: modelDir, modelId: modelId, fileName: fileName })
 }
 
-export async function stopDownloadRequiredFile(fileName: string): Promise<void> {
-    return await invoke<void>('stop_download_required_file', { fileName: fileName })
+export async function stopDownloadRequiredFile(modelId: string, fileName: string): Promise<void> {
+    return await invoke<void>('stop_download_required_file', { modelId: modelId, fileName: fileName })
 }
\ No newline at end of file
@@ -30,9 +30,105 @@ export type TransposeConfig = {
     realtime_rate: number;
 
     /**
-     * Model configuration for SenseVoiceSmall
+     * Whether to restore punctuation and caption whole sentences
      */
-    model_config: SenseVoiceSmallConfig;
+    punctuation: boolean;
+
+    /**
+     * Words that have to follow a sentence end before it is captioned
+     */
+    punctuation_lookahead: number;
+
+    /**
+     * Whether to label the captions with the speaker
+     */
+    diarization: boolean;
+
+    /**
+     * Cosine similarity a voice needs to be taken for a known speaker
+     */
+    speaker_threshold: number;
+
+    /**
+     * Speech recognition model
+     */
+    engine: Engine;
+
+    /**
+     * Model configuration of the engine
+     */
+    model_config: SenseVoiceSmallConfig | WhisperConfig | ParaformerConfig;
+};
+
+export type Engine = "sense_voice_small" | "whisper" | "paraformer";
+
+/**
+ * Configuration for Whisper model
+ */
+export type WhisperConfig = {
+
+    /**
+     * Path to model cache dir
+     */
+    model_dir: string;
+
+    /**
+     * Checkpoint size
+     */
+    size: WhisperSize;
+
+    /**
+     * Voice Activity Detection configuration
+     */
+    vad: VadConfig;
+
+    /**
+     * Resample configuration as [from, to] sample rates
+     */
+    resample?: [number, number];
+
+    /**
+     * Whether to use GPU for inference
+     */
+    use_gpu: boolean;
+
+    /**
+     * Language code such as "de", "auto" detects it for every segment
+     */
+    language: string;
+};
+
+export type WhisperSize = "tiny" | "base" | "small";
+
+/**
+ * Configuration for Paraformer-large model
+ */
+export type ParaformerConfig = {
+
+    /**
+     * Path to model cache dir
+     */
+    model_dir: string;
+
+    /**
+     * Voice Activity Detection configuration
+     */
+    vad: VadConfig;
+
+    /**
+     * Resample configuration as [from, to] sample rates
+     */
+    resample?: [number, number];
+
+    /**
+     * Whether to use GPU for inference
+     */
+    use_gpu: boolean;
+
+    /**
+     * Where the FSMN memory blocks run, "cpu" works around GPU issues
+     */
+    fsmn_device: FsmnDevice;
 };
 
 /**
@@ -59,8 +155,176 @@ export type SenseVoiceSmallConfig = {
      * Whether to use GPU for inference
      */
     use_gpu: boolean;
+
+    /**
+     * Recognition language, "auto" lets the model detect it
+     */
+    language: Language;
+
+    /**
+     * Inverse text normalization, "withitn" adds punctuation and digits
+     */
+    text_norm: TextNorm;
+
+    /**
+     * Number of segments encoded together, 0 or 1 disables batching
+     */
+    batch_size: number;
+
+    /**
+     * CTC decoding configuration
+     */
+    decode: DecodeConfig;
+
+    /**
+     * Encoding of long segments in overlapping windows
+     */
+    chunk: ChunkConfig;
+
+    /**
+     * Precision the weights are loaded in, half precision saves memory on GPUs
+     */
+    dtype: ModelDtype;
+
+    /**
+     * Checkpoint loaded instead of the downloaded model.pt (.pt, .safetensors or .gguf)
+     */
+    weight_file?: string | null;
+
+    /**
+     * Where the FSMN blocks of the encoder run, cpu copies activations back and forth
+     */
+    fsmn_device: FsmnDevice;
+};
+
+export type ModelDtype = "f32" | "f16" | "bf16";
+
+export type FsmnDevice = "encoder" | "cpu";
+
+/**
+ * Long segments are encoded in overlapping windows to bound memory and latency
+ */
+export type ChunkConfig = {
+    /**
+     * Longest speech encoded at once in milliseconds, 0 encodes segments whole
+     */
+    chunk_ms: number;
+
+    /**
+     * Audio shared by neighbouring windows in milliseconds
+     */
+    overlap_ms: number;
+};
+
+/**
+ * Configuration for CTC decoding
+ */
+export type DecodeConfig = {
+    /**
+     * Search strategy, greedy is fastest
+     */
+    method: "greedy" | "beam_search" | "keyword_spotting";
+
+    /**
+     * Number of prefixes kept per frame by beam search
+     */
+    beam_size: number;
+
+    /**
+     * Frames whose blank probability exceeds this are skipped by beam search
+     */
+    blank_skip_threshold: number;
+
+    /**
+     * Tokens below this probability are not expanded by beam search
+     */
+    token_threshold: number;
+
+    /**
+     * Phrases boosted by beam search
+     */
+    hotwords: Hotword[];
+
+    /**
+     * Text file with one hotword per line, optionally followed by " :weight"
+     */
+    hotwords_file: string | null;
+
+    /**
+     * Weight of hotwords from the file that do not set their own
+     */
+    hotword_weight: number;
+
+    /**
+     * N-gram language model fused into beam search, .arpa or .bin
+     */
+    lm_file: string | null;
+
+    /**
+     * Units the language model was trained on
+     */
+    lm_unit: LmUnit;
+
+    /**
+     * Scale of the language model log probability
+     */
+    lm_weight: number;
+
+    /**
+     * Bonus per language model unit
+     */
+    word_bonus: number;
+
+    /**
+     * Phrases reported when spoken, with any decoding method
+     */
+    keywords: Keyword[];
+
+    /**
+     * Score a keyword needs to be reported, between 0 and 1
+     */
+    keyword_threshold: number;
+};
+
+/**
+ * A phrase to watch for
+ */
+export type Keyword = {
+    phrase: string;
+
+    /**
+     * Detection threshold, the configured default if null
+     */
+    threshold: number | null;
+};
+
+/**
+ * Tokens of tokens.json or whitespace separated words
+ */
+export type LmUnit = "token" | "word";
+
+/**
+ * A phrase boosted during beam search
+ */
+export type Hotword = {
+    phrase: string;
+
+    /**
+     * Bonus added per matched token
+     */
+    weight: number;
 };
 
+/**
+ * Recognition language of SenseVoiceSmall
+ */
+export type Language = "auto" | "zh" | "en" | "yue" | "ja" | "ko" | "nospeech";
+
+/**
+ * Text normalization mode of SenseVoiceSmall
+ */
+export type TextNorm = "withitn" | "woitn";
+
 /**
  * Configuration parameters for Voice Activity Detection
  */
@@ -98,6 +362,10 @@ export type VadConfig = {
 
 
 export type FileInfo = {
+    /**
+     * Repository the file is downloaded from
+     */
+    model_id: string;
     name: string;
     path: string;
     size: number;
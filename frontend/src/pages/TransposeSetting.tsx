@@ -3,7 +3,18 @@ import { currentMonitor } from "@tauri-apps/api/window";
 import { produce, type WritableDraft } from "immer";
 import { useEffect, useState } from "react";
 import { checkRequiredFiles, getDevices, getTranscribeConfig, updateTranscribeConfig } from "../cmds/index.ts"; // 修改这一行
-import { FileInfo, TransposeConfig } from "../cmds/types.ts";
+import {
+    Engine,
+    FileInfo,
+    FsmnDevice,
+    Language,
+    ModelDtype,
+    ParaformerConfig,
+    SenseVoiceSmallConfig,
+    TransposeConfig,
+    WhisperConfig,
+    WhisperSize,
+} from "../cmds/types.ts";
 import FileStatusItem from "./components/FileStatusItem.tsx";
 import NumberInput from "./components/NumberInput.tsx";
 import SectionCard from "./components/SectionCard.tsx";
@@ -89,8 +100,21 @@ export default function TransposeSetting() {
         await fetchConfig();
     };
 
+    const updateSenseVoice = (fn: (d: WritableDraft<SenseVoiceSmallConfig>) => void) =>
+        updateConfig(draft => fn(draft.model_config as WritableDraft<SenseVoiceSmallConfig>));
+
+    const updateWhisper = (fn: (d: WritableDraft<WhisperConfig>) => void) =>
+        updateConfig(draft => fn(draft.model_config as WritableDraft<WhisperConfig>));
+
+    const updateParaformer = (fn: (d: WritableDraft<ParaformerConfig>) => void) =>
+        updateConfig(draft => fn(draft.model_config as WritableDraft<ParaformerConfig>));
+
     if (!config) return <div>Loading...</div>;
 
+    const senseVoice = config.engine === "sense_voice_small" ? config.model_config as SenseVoiceSmallConfig : null;
+    const whisper = config.engine === "whisper" ? config.model_config as WhisperConfig : null;
+    const paraformer = config.engine === "paraformer" ? config.model_config as ParaformerConfig : null;
+
     return (
         <>
             <div className="space-y-2">
@@ -142,6 +166,50 @@ export default function TransposeSetting() {
                         })}
                     />
 
+                    {senseVoice && (
+                        <>
+                            <SelectInput
+                                label="计算精度"
+                                description="半精度占用更少显存，bf16 仅支持 GPU"
+                                value={senseVoice.dtype}
+                                onChange={(value) => updateSenseVoice(draft => {
+                                    draft.dtype = (value || "f32") as ModelDtype;
+                                })}
+                                options={[
+                                    { value: "f32", label: "f32" },
+                                    { value: "f16", label: "f16" },
+                                    { value: "bf16", label: "bf16" },
+                                ]}
+                            />
+                            <SelectInput
+                                label="FSMN 设备"
+                                description="默认与模型同设备，GPU 结果异常时可退回 CPU"
+                                value={senseVoice.fsmn_device}
+                                onChange={(value) => updateSenseVoice(draft => {
+                                    draft.fsmn_device = (value || "encoder") as FsmnDevice;
+                                })}
+                                options={[
+                                    { value: "encoder", label: "与模型相同" },
+                                    { value: "cpu", label: "CPU" },
+                                ]}
+                            />
+                        </>
+                    )}
+                    {paraformer && (
+                        <SelectInput
+                            label="FSMN 设备"
+                            description="默认与模型同设备，GPU 结果异常时可退回 CPU"
+                            value={paraformer.fsmn_device}
+                            onChange={(value) => updateParaformer(draft => {
+                                draft.fsmn_device = (value || "encoder") as FsmnDevice;
+                            })}
+                            options={[
+                                { value: "encoder", label: "与模型相同" },
+                                { value: "cpu", label: "CPU" },
+                            ]}
+                        />
+                    )}
+
                 </SectionCard>
 
 
@@ -165,8 +233,60 @@ export default function TransposeSetting() {
                     />
                 </SectionCard>
 
+                <SectionCard title="标点">
+                    <SettingItem
+                        label="标点恢复"
+                        description="为无标点的识别结果添加标点，按句显示字幕，首次开启需要下载模型"
+                        checked={config.punctuation}
+                        onChange={() => updateConfig(draft => {
+                            draft.punctuation = !draft.punctuation;
+                        })}
+                    />
+                    <NumberInput
+                        label="断句前瞻"
+                        value={config.punctuation_lookahead}
+                        onChange={(value) => updateConfig(draft => {
+                            draft.punctuation_lookahead = Math.max(0, Math.round(value));
+                        })}
+                        placeholder="词数"
+                    />
+                </SectionCard>
+
+                <SectionCard title="说话人">
+                    <SettingItem
+                        label="说话人区分"
+                        description="为字幕标注说话人，首次开启需要下载模型"
+                        checked={config.diarization}
+                        onChange={() => updateConfig(draft => {
+                            draft.diarization = !draft.diarization;
+                        })}
+                    />
+                    <NumberInput
+                        label="相似度阈值"
+                        value={config.speaker_threshold}
+                        onChange={(value) => updateConfig(draft => {
+                            draft.speaker_threshold = Math.min(1, Math.max(0, value));
+                        })}
+                        placeholder="相似度阈值"
+                        step="0.05"
+                    />
+                </SectionCard>
+
 
                 <SectionCard title="模型">
+                    <SelectInput
+                        label="识别引擎"
+                        description="切换后重新加载模型"
+                        value={config.engine}
+                        onChange={(value) => updateConfig(draft => {
+                            draft.engine = (value || "sense_voice_small") as Engine;
+                        })}
+                        options={[
+                            { value: "sense_voice_small", label: "SenseVoiceSmall" },
+                            { value: "whisper", label: "Whisper" },
+                            { value: "paraformer", label: "Paraformer" },
+                        ]}
+                    />
                     <TextInput
                         label="模型目录"
                         value={config.model_config.model_dir}
@@ -178,6 +298,71 @@ export default function TransposeSetting() {
                         placeholder="模型缓存路径"
                         disable={config.enable}
                     />
+                    {senseVoice && (
+                        <>
+                            <TextInput
+                                label="权重文件"
+                                description="留空使用下载的 model.pt，也可指定 .safetensors 或 .gguf 文件"
+                                value={senseVoice.weight_file || ""}
+                                onChange={(value) => updateSenseVoice(draft => {
+                                    draft.weight_file = value || null;
+                                })}
+                                placeholder="权重文件路径"
+                                disable={config.enable}
+                            />
+                            <SelectInput
+                                label="识别语言"
+                                description="短句容易误判语言时可手动指定"
+                                value={senseVoice.language}
+                                onChange={(value) => updateSenseVoice(draft => {
+                                    draft.language = (value || "auto") as Language;
+                                })}
+                                options={[
+                                    { value: "auto", label: "自动" },
+                                    { value: "zh", label: "中文" },
+                                    { value: "en", label: "英语" },
+                                    { value: "yue", label: "粤语" },
+                                    { value: "ja", label: "日语" },
+                                    { value: "ko", label: "韩语" },
+                                    { value: "nospeech", label: "无语音" },
+                                ]}
+                            />
+                            <SettingItem
+                                label="标点与数字规整"
+                                description="输出带标点和阿拉伯数字的文本，关闭则保留口语原文"
+                                checked={senseVoice.text_norm === "withitn"}
+                                onChange={() => updateSenseVoice(draft => {
+                                    draft.text_norm = draft.text_norm === "withitn" ? "woitn" : "withitn";
+                                })}
+                            />
+                        </>
+                    )}
+                    {whisper && (
+                        <>
+                            <SelectInput
+                                label="模型大小"
+                                description="越大越准确也越慢，切换后需要下载对应模型"
+                                value={whisper.size}
+                                onChange={(value) => updateWhisper(draft => {
+                                    draft.size = (value || "base") as WhisperSize;
+                                })}
+                                options={[
+                                    { value: "tiny", label: "tiny" },
+                                    { value: "base", label: "base" },
+                                    { value: "small", label: "small" },
+                                ]}
+                            />
+                            <TextInput
+                                label="识别语言"
+                                description="语言代码，如 de、fr、es，auto 为自动检测"
+                                value={whisper.language}
+                                onChange={(value) => updateWhisper(draft => {
+                                    draft.language = value || "auto";
+                                })}
+                                placeholder="auto"
+                            />
+                        </>
+                    )}
                     <label className="block text-sm font-medium mb-1">所需文件</label>
                     <div>
                         {requiredFiles.map((file, index) => (
@@ -193,6 +378,103 @@ export default function TransposeSetting() {
                 </SectionCard>
 
 
+                {senseVoice && (
+                    <SectionCard title="解码">
+                        <SelectInput
+                            label="解码方式"
+                            description="束搜索更准确，热词仅在束搜索下生效；关键词检测只报告关键词，不输出字幕"
+                            value={senseVoice.decode.method}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.method =
+                                    value === "beam_search" || value === "keyword_spotting" ? value : "greedy";
+                            })}
+                            options={[
+                                { value: "greedy", label: "贪心" },
+                                { value: "beam_search", label: "束搜索" },
+                                { value: "keyword_spotting", label: "关键词检测" },
+                            ]}
+                        />
+                        <NumberInput
+                            label="束宽"
+                            value={senseVoice.decode.beam_size}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.beam_size = value || 1;
+                            })}
+                            placeholder="束宽"
+                        />
+                        <TextInput
+                            label="热词文件"
+                            description="每行一个热词，可在末尾用 :权重 指定权重"
+                            value={senseVoice.decode.hotwords_file || ""}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.hotwords_file = value || null;
+                            })}
+                            placeholder="热词文件路径"
+                        />
+                        <TextInput
+                            label="关键词"
+                            description="说出这些词时发出提醒，用逗号分隔"
+                            value={senseVoice.decode.keywords.map(k => k.phrase).join(", ")}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.keywords = value
+                                    .split(/[,，]/)
+                                    .map(phrase => phrase.trim())
+                                    .filter(phrase => phrase.length > 0)
+                                    .map(phrase => ({ phrase, threshold: null }));
+                            })}
+                            placeholder="关键词"
+                        />
+                        <NumberInput
+                            label="关键词阈值"
+                            value={senseVoice.decode.keyword_threshold}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.keyword_threshold = value || 0;
+                            })}
+                            placeholder="关键词阈值"
+                            step="0.05"
+                        />
+                        <TextInput
+                            label="语言模型"
+                            description="N-gram 语言模型文件 (.arpa 或 .bin)，仅在束搜索下生效"
+                            value={senseVoice.decode.lm_file || ""}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.lm_file = value || null;
+                            })}
+                            placeholder="语言模型文件路径"
+                        />
+                        <SelectInput
+                            label="语言模型单元"
+                            value={senseVoice.decode.lm_unit}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.lm_unit = value === "word" ? "word" : "token";
+                            })}
+                            options={[
+                                { value: "token", label: "子词" },
+                                { value: "word", label: "词" },
+                            ]}
+                        />
+                        <NumberInput
+                            label="语言模型权重"
+                            value={senseVoice.decode.lm_weight}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.lm_weight = value || 0;
+                            })}
+                            placeholder="语言模型权重"
+                            step="0.1"
+                        />
+                        <NumberInput
+                            label="词插入奖励"
+                            value={senseVoice.decode.word_bonus}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.decode.word_bonus = value || 0;
+                            })}
+                            placeholder="词插入奖励"
+                            step="0.1"
+                        />
+                    </SectionCard>
+                )}
+
+
                 <SectionCard title="VAD">
                     <NumberInput
                         label="采样率 (Hz)"
@@ -262,6 +544,29 @@ export default function TransposeSetting() {
                         step="0.01"
                     />
                 </SectionCard>
+
+
+                {senseVoice && (
+                    <SectionCard title="长语音">
+                        <NumberInput
+                            label="分块时长 (ms，0 为不分块)"
+                            value={senseVoice.chunk.chunk_ms}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.chunk.chunk_ms = value || 0;
+                            })}
+                            placeholder="分块时长"
+                        />
+
+                        <NumberInput
+                            label="分块重叠 (ms)"
+                            value={senseVoice.chunk.overlap_ms}
+                            onChange={(value) => updateSenseVoice(draft => {
+                                draft.chunk.overlap_ms = value || 0;
+                            })}
+                            placeholder="分块重叠"
+                        />
+                    </SectionCard>
+                )}
             </div>
         </>
     );
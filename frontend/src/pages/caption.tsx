@@ -2,14 +2,13 @@ import {listen} from "@tauri-apps/api/event";
 import {useEffect, useState} from "react";
 
 export default function Caption() {
-    const [caption, setCaption] = useState('')
+    const [caption, setCaption] = useState<Caption | null>(null)
 
     useEffect(() => {
         try {
             listen<Caption>('caption', (event) => {
                 try {
-                    const caption = event.payload
-                    setCaption(caption.text)
+                    setCaption(event.payload)
                 } catch (error) {
                     console.log(error)
                 }
@@ -24,15 +23,57 @@ export default function Caption() {
             className="h-full bg-black/50 text-white p-4 rounded-md flex flex-col absolute bottom-0 left-0 right-0 select-none"
             data-tauri-drag-region>
             <h1 className="text-3xl font-medium select-none">
-                {caption}
+                {caption && annotations(caption).map((a, i) => <span key={`a${i}`}>{a} </span>)}
+                {caption?.tokens.map((t, i) => (
+                    <span key={i} className={t.confidence < LOW_CONFIDENCE ? 'text-white/40' : undefined}>
+                        {t.text}
+                    </span>
+                ))}
             </h1>
         </div>
     )
 
 }
 
+/**
+ * Tokens below this confidence are greyed out
+ */
+const LOW_CONFIDENCE = 0.5
+
+function annotations(caption: Caption): string[] {
+    const speaker = caption.speaker === null ? [] : [`[S${caption.speaker + 1}]`]
+    return speaker.concat(caption.events
+        .filter((e) => e in EVENT_LABELS)
+        .map((e) => `[${EVENT_LABELS[e]}]`))
+}
+
+const EVENT_LABELS: Record<string, string> = {
+    bgm: 'Music',
+    applause: 'Applause',
+    laughter: 'Laughter',
+    cry: 'Crying',
+    sneeze: 'Sneeze',
+    breath: 'Breath',
+    cough: 'Cough',
+}
+
 type Caption = {
     text: string
     start: number
     end: number
+    language: string | null
+    emotion: string | null
+    events: string[]
+    confidence: number
+    speaker: number | null
+    tokens: CaptionToken[]
+    words: CaptionToken[]
+}
+
+type CaptionToken = {
+    text: string
+    start: number
+    end: number
+    confidence: number
+    speaker: number | null
 }
\ No newline at end of file
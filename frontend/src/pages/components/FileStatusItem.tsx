@@ -9,6 +9,7 @@ interface FileStatusItemProps {
 }
 
 interface DownloadProgress {
+    model_id: string;
     file_name: string;
     size: number;
     position: number;
@@ -26,7 +27,7 @@ export default function FileStatusItem({ file, modelDir }: FileStatusItemProps)
         const setupListener = async () => {
             unlisten = await listen<DownloadProgress>('download_progress', (event) => {
                 let progress = event.payload
-                if (progress.file_name === file.name) {
+                if (progress.model_id === file.model_id && progress.file_name === file.name) {
                     setDownloadSize(progress.position);
                     if (progress.size > 0) {
                         setIsDownloading(progress.position < progress.size);
@@ -42,7 +43,7 @@ export default function FileStatusItem({ file, modelDir }: FileStatusItemProps)
                 unlisten();
             }
         };
-    }, [file.name]);
+    }, [file.model_id, file.name]);
 
     const formatFileSize = (bytes: number): string => {
         if (bytes === 0) return '0 B';
@@ -59,7 +60,7 @@ export default function FileStatusItem({ file, modelDir }: FileStatusItemProps)
 
         if (isDownloading) {
             try {
-                await stopDownloadRequiredFile(file.name);
+                await stopDownloadRequiredFile(file.model_id, file.name);
                 setIsDownloading(false);
             } catch (error) {
                 console.error("Failed to stop download:", error);
@@ -67,7 +68,7 @@ export default function FileStatusItem({ file, modelDir }: FileStatusItemProps)
         } else {
             try {
                 setIsDownloading(true);
-                await downloadRequiredFile(modelDir, file.name);
+                await downloadRequiredFile(modelDir, file.model_id, file.name);
             } catch (error) {
                 console.error("Failed to start download:", error);
                 setIsDownloading(false);
@@ -8,6 +8,13 @@ interface NotificationMessage {
   content: string;
 }
 
+interface KeywordDetection {
+  keyword: string;
+  start: number;
+  end: number;
+  score: number;
+}
+
 interface NotificationProps {
   message: NotificationMessage;
   onClose: () => void;
@@ -66,8 +73,22 @@ const NotificationContainer: React.FC = () => {
       setNextId(prev => prev + 1);
     });
 
+    const unlistenKeyword = listen<KeywordDetection>('keyword', (event) => {
+      const newNotification = {
+        id: nextId,
+        message: {
+          type: 'warn' as NotificationType,
+          content: `检测到关键词「${event.payload.keyword}」(${event.payload.score.toFixed(2)})`
+        }
+      };
+
+      setNotifications(prev => [...prev, newNotification]);
+      setNextId(prev => prev + 1);
+    });
+
     return () => {
       unlisten.then(unlistenFn => unlistenFn());
+      unlistenKeyword.then(unlistenFn => unlistenFn());
     }
   }, [nextId]);
 